@@ -1,4 +1,4 @@
-use super::{data_requests, datasets, query, threads};
+use super::{data_requests, datasets, places, query, threads, usage};
 use crate::error::Result as AppResult;
 use crate::state::AppState;
 use axum::http::Method;
@@ -12,8 +12,8 @@ async fn root() -> Redirect {
     Redirect::temporary("https://www.bblackhole.com/")
 }
 async fn health(State(state): State<AppState>) -> AppResult<String> {
-    let pg = state.postgres_pool.get().await?;
-    let mut chatter = Chatter::new(pg).await?;
+    let mut chatter =
+        Chatter::new(state.postgres_pool.clone(), state.chatter_config.clone()).await?;
     let rows = chatter
         .execute_raw_query(
             r#"
@@ -38,8 +38,8 @@ pub async fn create_api() -> Router {
 
     let cors = CorsLayer::new()
         .allow_headers([axum::http::header::CONTENT_TYPE])
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
+        // allow `GET`, `POST` and `PATCH` when accessing the resource
+        .allow_methods([Method::GET, Method::POST, Method::PATCH])
         // allow requests from any origin
         .allow_origin(Any);
 
@@ -50,6 +50,8 @@ pub async fn create_api() -> Router {
         .merge(threads::threads_routes())
         .merge(query::query_routes())
         .merge(data_requests::data_requests_routes())
+        .merge(usage::usage_routes())
+        .merge(places::places_routes())
         .layer(cors)
         .with_state(app_state)
 }
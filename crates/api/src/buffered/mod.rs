@@ -1,5 +1,7 @@
 pub mod api;
 pub mod data_requests;
 pub mod datasets;
+pub mod places;
 pub mod query;
 pub mod threads;
+pub mod usage;
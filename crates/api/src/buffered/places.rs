@@ -0,0 +1,61 @@
+use crate::error::{AppError, Result};
+use crate::state::AppState;
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    routing::get,
+};
+use chatter::functions::{AdministrativeArea, find_administrative_area, validate_coordinates};
+use chatter::municipality_codes::{CodeResolution, normalize_code, resolve_code};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct ReverseGeocodeQuery {
+    lon: f64,
+    lat: f64,
+}
+
+#[derive(Serialize)]
+struct ReverseGeocodeResponse {
+    /// `null` if the point isn't in any administrative area
+    area: Option<AdministrativeArea>,
+}
+
+async fn reverse_geocode_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ReverseGeocodeQuery>,
+) -> Result<Json<ReverseGeocodeResponse>> {
+    validate_coordinates(query.lon, query.lat).map_err(AppError::BadRequest)?;
+    let pg = state.postgres_pool.get().await?;
+    let area = find_administrative_area(&pg, query.lon, query.lat).await?;
+    Ok(Json(ReverseGeocodeResponse { area }))
+}
+
+#[derive(Deserialize)]
+struct MunicipalityCodeQuery {
+    /// Follow the changes up to this date (`YYYY-MM-DD`). Defaults to now.
+    as_of: Option<NaiveDate>,
+}
+
+async fn municipality_code_handler(
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Query(query): Query<MunicipalityCodeQuery>,
+) -> Result<Json<CodeResolution>> {
+    let code = normalize_code(&code).map_err(AppError::BadRequest)?;
+    let pg = state.postgres_pool.get().await?;
+    let resolution = resolve_code(&pg, &code, query.as_of)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("`{}` is not a municipality code.", code)))?;
+    Ok(Json(resolution))
+}
+
+pub fn places_routes() -> Router<AppState> {
+    Router::new()
+        .route("/places/reverse_geocode", get(reverse_geocode_handler))
+        .route(
+            "/places/municipality_codes/{code}",
+            get(municipality_code_handler),
+        )
+}
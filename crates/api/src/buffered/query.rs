@@ -22,8 +22,8 @@ async fn get_table_handler(
     Query(query): Query<QueryString>,
     State(state): State<AppState>,
 ) -> Result<Json<serde_json::Value>> {
-    let pg = state.postgres_pool.get().await?;
-    let mut chatter = Chatter::new(pg).await?;
+    let mut chatter =
+        Chatter::new(state.postgres_pool.clone(), state.chatter_config.clone()).await?;
     let rows: Vec<serde_json::Value> = chatter
         .get_query_results(&query.q)
         .await?
@@ -40,8 +40,8 @@ async fn get_tile_metadata_handler(
     Query(query): Query<QueryString>,
     State(state): State<AppState>,
 ) -> Result<Json<serde_json::Value>> {
-    let pg = state.postgres_pool.get().await?;
-    let mut chatter = Chatter::new(pg).await?;
+    let mut chatter =
+        Chatter::new(state.postgres_pool.clone(), state.chatter_config.clone()).await?;
 
     let bbox = chatter
         .get_query_bbox(&query.q)
@@ -69,8 +69,8 @@ async fn get_tile_handler(
     Query(query): Query<QueryString>,
     State(state): State<AppState>,
 ) -> Result<Response> {
-    let pg = state.postgres_pool.get().await?;
-    let mut chatter = Chatter::new(pg).await?;
+    let mut chatter =
+        Chatter::new(state.postgres_pool.clone(), state.chatter_config.clone()).await?;
 
     let tile = chatter
         .get_tile(&query.q, z, x, y)
@@ -2,7 +2,7 @@ use crate::data::threads::ThreadDetailsFull;
 use crate::state::AppState;
 use crate::{
     data::threads::{MessageView, Thread, ThreadDetails, ThreadList},
-    error::Result,
+    error::{AppError, Result},
 };
 use anyhow::Context;
 use axum::{
@@ -12,10 +12,13 @@ use axum::{
     response::{IntoResponse, Response},
     routing::{get, post},
 };
+use chatter::data::error::DataError;
 use chatter::data::types::chat_message::ChatMessage;
 use chatter::data::types::chat_thread::{ChatThread, ChatThreadBuilder};
+use chatter::functions::ToolProfile;
+use chatter::llm::GenerationSettings;
 use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use ulid::Ulid;
 
 async fn get_threads_handler(State(state): State<AppState>) -> Result<Json<ThreadList>> {
@@ -36,6 +39,9 @@ async fn get_thread_handler(
         id: thread.id().to_string(),
         title: thread.title,
         archived: thread.archived,
+        model: thread.model,
+        generation: thread.generation,
+        tool_profile: thread.tool_profile,
         messages: messages
             .into_iter()
             .map(Into::into)
@@ -65,19 +71,56 @@ async fn get_thread_full_handler(
     .into())
 }
 
+#[derive(Deserialize, Default)]
+struct CreateNewThreadRequest {
+    /// The model to use in this thread. Defaults to the server's default model.
+    model: Option<String>,
+    #[serde(default)]
+    generation: GenerationSettings,
+    /// The tools available in this thread. Defaults to `analysis`, or the most permissive
+    /// profile this deployment allows if that is more restrictive.
+    tool_profile: Option<ToolProfile>,
+}
+
 #[derive(Serialize)]
 struct CreateNewThreadResponse {
     thread_id: String,
 }
 
-async fn create_new_thread_handler(State(state): State<AppState>) -> Result<Response> {
+async fn create_new_thread_handler(
+    State(state): State<AppState>,
+    payload: Option<Json<CreateNewThreadRequest>>,
+) -> Result<Response> {
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+    payload
+        .generation
+        .validate()
+        .map_err(AppError::BadRequest)?;
+    let max_profile = ToolProfile::max_from_env();
+    let tool_profile = match payload.tool_profile {
+        Some(profile) if profile > max_profile => {
+            return Err(AppError::BadRequest(format!(
+                "The tool profile {:?} is not available",
+                profile
+            )));
+        }
+        Some(profile) => profile,
+        None => ToolProfile::default().min(max_profile),
+    };
+
     let thread_id = Ulid::new();
-    let thread = ChatThreadBuilder::default()
+    let mut builder = ChatThreadBuilder::default();
+    builder
         .id(thread_id.to_string())
         .user_id("demo_user".to_string())
         .title(thread_id.to_string())
         .modified_ts(Utc::now())
-        .build()?;
+        .generation(payload.generation)
+        .tool_profile(tool_profile);
+    if let Some(model) = payload.model.filter(|m| !m.is_empty()) {
+        builder.model(model);
+    }
+    let thread = builder.build()?;
     state.ddb.put_item_excl(&thread).await?;
 
     Ok((
@@ -105,11 +148,90 @@ async fn archive_thread_handler(
     Ok(StatusCode::NO_CONTENT.into_response())
 }
 
+/// The maximum length of a thread title, in characters.
+const MAX_TITLE_CHARS: usize = 100;
+
+#[derive(Deserialize)]
+struct UpdateThreadRequest {
+    title: String,
+}
+
+async fn update_thread_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateThreadRequest>,
+) -> Result<Json<Thread>> {
+    let title = payload.title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_CHARS {
+        return Err(AppError::BadRequest(format!(
+            "title must be between 1 and {} characters",
+            MAX_TITLE_CHARS
+        )));
+    }
+
+    let thread_id = Ulid::from_string(&id).context("Invalid thread ID")?;
+    let mut thread =
+        ChatThread::get_thread(&state.ddb, "demo_user", &thread_id.to_string()).await?;
+    let ts = thread.modified_ts;
+    thread.title = title.to_string();
+    thread.modified_ts = Utc::now();
+
+    match state.ddb.put_item_lock(&thread, "modified_ts", &ts).await {
+        Err(DataError::OptimisticLockFailed) => {
+            return Err(AppError::Conflict("thread_modified".to_string()));
+        }
+        result => result?,
+    }
+
+    Ok(Json(thread.into()))
+}
+
+#[derive(Deserialize)]
+struct ForkThreadRequest {
+    /// The last message to copy to the new thread.
+    message_id: u32,
+}
+
+async fn fork_thread_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(payload): Json<ForkThreadRequest>,
+) -> Result<Response> {
+    let thread_id = Ulid::from_string(&id).context("Invalid thread ID")?;
+    let thread = match ChatThread::fork(
+        &state.ddb,
+        "demo_user",
+        &thread_id.to_string(),
+        payload.message_id,
+    )
+    .await
+    {
+        Err(DataError::DocumentNotFound) => {
+            return Err(AppError::BadRequest(
+                "message_id must be a message in this thread".to_string(),
+            ));
+        }
+        result => result?,
+    };
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateNewThreadResponse {
+            thread_id: thread.id().to_string(),
+        }),
+    )
+        .into_response())
+}
+
 pub fn threads_routes() -> Router<AppState> {
     Router::new()
         .route("/threads", get(get_threads_handler))
         .route("/threads", post(create_new_thread_handler))
-        .route("/threads/{id}", get(get_thread_handler))
+        .route(
+            "/threads/{id}",
+            get(get_thread_handler).patch(update_thread_handler),
+        )
         .route("/threads/{id}/_full", get(get_thread_full_handler))
         .route("/threads/{id}/archive", post(archive_thread_handler))
+        .route("/threads/{id}/fork", post(fork_thread_handler))
 }
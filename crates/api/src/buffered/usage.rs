@@ -0,0 +1,132 @@
+use crate::error::{AppError, Result};
+use crate::state::AppState;
+use anyhow::Context;
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    routing::get,
+};
+use chatter::data::types::usage::UsageCounter;
+use chrono::{Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// The longest range that can be requested at once, in days.
+const MAX_RANGE_DAYS: u64 = 366;
+/// The range returned when `from` isn't given.
+const DEFAULT_RANGE_DAYS: u64 = 30;
+
+#[derive(Serialize, Default)]
+pub struct UsageView {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cached_prompt_tokens: u64,
+    pub cache_write_prompt_tokens: u64,
+    /// Requests to models without a known price are not included.
+    pub cost_usd: f64,
+}
+
+impl UsageView {
+    fn add(&mut self, counter: &UsageCounter) {
+        self.requests += counter.requests;
+        self.prompt_tokens += counter.prompt_tokens;
+        self.completion_tokens += counter.completion_tokens;
+        self.cached_prompt_tokens += counter.cached_prompt_tokens;
+        self.cache_write_prompt_tokens += counter.cache_write_prompt_tokens;
+        self.cost_usd += counter.cost_micros as f64 / 1_000_000.0;
+    }
+}
+
+impl From<&UsageCounter> for UsageView {
+    fn from(counter: &UsageCounter) -> Self {
+        let mut view = Self::default();
+        view.add(counter);
+        view
+    }
+}
+
+#[derive(Serialize)]
+pub struct DailyUsageView {
+    pub date: NaiveDate,
+    pub model: String,
+    #[serde(flatten)]
+    pub usage: UsageView,
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub total: UsageView,
+    pub days: Vec<DailyUsageView>,
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    /// The first day to include (`YYYY-MM-DD`, UTC). Defaults to 30 days before `to`.
+    from: Option<NaiveDate>,
+    /// The last day to include (`YYYY-MM-DD`, UTC). Defaults to today.
+    to: Option<NaiveDate>,
+}
+
+async fn get_usage_handler(
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageReport>> {
+    let to = query.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = query
+        .from
+        .or_else(|| to.checked_sub_days(Days::new(DEFAULT_RANGE_DAYS)))
+        .unwrap_or(to);
+    if from > to {
+        return Err(AppError::BadRequest(
+            "from must not be after to".to_string(),
+        ));
+    }
+    if (to - from).num_days() as u64 > MAX_RANGE_DAYS {
+        return Err(AppError::BadRequest(format!(
+            "the range must not be longer than {} days",
+            MAX_RANGE_DAYS
+        )));
+    }
+
+    let counters = UsageCounter::get_daily_usage(&state.ddb, "demo_user", from, to).await?;
+
+    let mut total = UsageView::default();
+    let mut days = Vec::with_capacity(counters.len());
+    for counter in &counters {
+        total.add(counter);
+        let (Some(date), Some(model)) = (counter.date(), counter.model()) else {
+            continue;
+        };
+        days.push(DailyUsageView {
+            date,
+            model: model.to_string(),
+            usage: counter.into(),
+        });
+    }
+
+    Ok(Json(UsageReport {
+        from,
+        to,
+        total,
+        days,
+    }))
+}
+
+async fn get_thread_usage_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<UsageView>> {
+    let thread_id = Ulid::from_string(&id).context("Invalid thread ID")?;
+    let counter =
+        UsageCounter::get_thread_usage(&state.ddb, "demo_user", &thread_id.to_string()).await?;
+    Ok(Json(counter.as_ref().map(Into::into).unwrap_or_default()))
+}
+
+pub fn usage_routes() -> Router<AppState> {
+    Router::new()
+        .route("/usage", get(get_usage_handler))
+        .route("/threads/{id}/usage", get(get_thread_usage_handler))
+}
@@ -1,6 +1,8 @@
 use chatter::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
 use chatter::data::types::chat_message::ChatMessage;
 use chatter::data::types::chat_thread::ChatThread;
+use chatter::functions::ToolProfile;
+use chatter::llm::GenerationSettings;
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -70,6 +72,9 @@ pub struct ThreadDetails {
     pub id: String,
     pub title: String,
     pub archived: Option<bool>,
+    pub model: String,
+    pub generation: GenerationSettings,
+    pub tool_profile: ToolProfile,
     pub messages: Vec<MessageView>,
 }
 
@@ -1,5 +1,6 @@
+#[cfg(feature = "streaming")]
+use axum::body::Body;
 use axum::{
-    body::Body,
     http::StatusCode,
     response::{IntoResponse, Response},
 };
@@ -8,7 +9,9 @@ use futures::stream;
 use lambda_http::tracing;
 use sentry::integrations::anyhow::capture_anyhow;
 use serde_json::json;
-use std::{convert::Infallible, fmt};
+#[cfg(feature = "streaming")]
+use std::convert::Infallible;
+use std::fmt;
 
 pub type Result<T> = std::result::Result<T, AppError>;
 
@@ -16,6 +19,7 @@ pub type Result<T> = std::result::Result<T, AppError>;
 pub enum AppError {
     InternalServerError(anyhow::Error),
     Conflict(String),
+    BadRequest(String),
 }
 
 impl IntoResponse for AppError {
@@ -39,6 +43,12 @@ impl IntoResponse for AppError {
                     serde_json::to_string(&json_body).unwrap_or_else(|_| "Conflict".into());
                 (StatusCode::CONFLICT, message)
             }
+            AppError::BadRequest(error) => {
+                let json_body = json!({ "error": error });
+                let message =
+                    serde_json::to_string(&json_body).unwrap_or_else(|_| "Bad Request".into());
+                (StatusCode::BAD_REQUEST, message)
+            }
         };
 
         // If streaming is enabled, wrap the string in a stream body;
@@ -75,6 +85,7 @@ impl fmt::Display for AppError {
         match self {
             AppError::InternalServerError(error) => write!(f, "Internal server error: {}", error),
             AppError::Conflict(code) => write!(f, "Conflict: {}", code),
+            AppError::BadRequest(error) => write!(f, "Bad request: {}", error),
         }
     }
 }
@@ -1,5 +1,7 @@
 use lambda_http::tracing;
 
+// Shared with the buffered API, which uses more of the views.
+#[allow(dead_code)]
 mod data;
 mod error;
 mod sentry;
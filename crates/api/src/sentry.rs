@@ -1,9 +1,7 @@
 // use tracing_subscriber::prelude::*;
 
 pub fn init_sentry_guard() -> Option<sentry::ClientInitGuard> {
-    let Some(dsn) = std::env::var("SENTRY_DSN").ok() else {
-        return None;
-    };
+    let dsn = std::env::var("SENTRY_DSN").ok()?;
 
     let guard = sentry::init((
         dsn,
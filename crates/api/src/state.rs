@@ -1,4 +1,6 @@
 use crate::error::Result;
+use anyhow::Context;
+use chatter::config::ChatterConfig;
 use chatter::data::dynamodb::Db;
 use deadpool_postgres::{Config, ManagerConfig, Pool, PoolConfig, RecyclingMethod, Runtime};
 use std::{env, sync::Arc};
@@ -12,6 +14,7 @@ use tokio_postgres::NoTls;
 pub struct AppState {
     pub ddb: Arc<Db>,
     pub postgres_pool: Pool,
+    pub chatter_config: Arc<ChatterConfig>,
 }
 
 impl AppState {
@@ -20,6 +23,9 @@ impl AppState {
         Self {
             ddb: Arc::new(db),
             postgres_pool: Self::get_postgres_pool().unwrap(),
+            chatter_config: Arc::new(
+                ChatterConfig::from_env().expect("Invalid chatter configuration"),
+            ),
         }
     }
 
@@ -27,8 +33,13 @@ impl AppState {
         let mut cfg = Config::new();
         let config = env::var("POSTGRES_CONN_STR")?;
         cfg.url = Some(config);
+        // Tool calls that run concurrently each use a connection
+        let max_size = match env::var("POSTGRES_POOL_SIZE") {
+            Ok(size) => size.parse().context("Invalid POSTGRES_POOL_SIZE")?,
+            Err(_) => 4,
+        };
         cfg.pool = Some(PoolConfig {
-            max_size: 1,
+            max_size,
             ..Default::default()
         });
         cfg.manager = Some(ManagerConfig {
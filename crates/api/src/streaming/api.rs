@@ -12,8 +12,8 @@ use std::convert::Infallible;
 use tower_http::cors::{Any, CorsLayer};
 
 async fn health(State(state): State<AppState>) -> AppResult<impl IntoResponse> {
-    let pg = state.postgres_pool.get().await?;
-    let mut chatter = Chatter::new(pg).await?;
+    let mut chatter =
+        Chatter::new(state.postgres_pool.clone(), state.chatter_config.clone()).await?;
     let rows = chatter
         .execute_raw_query(
             r#"
@@ -48,8 +48,8 @@ pub async fn create_api() -> Router {
 
     let cors = CorsLayer::new()
         .allow_headers([axum::http::header::CONTENT_TYPE])
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
+        // allow `GET`, `POST` and `PATCH` when accessing the resource
+        .allow_methods([Method::GET, Method::POST, Method::PATCH])
         // allow requests from any origin
         .allow_origin(Any);
 
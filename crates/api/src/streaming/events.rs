@@ -0,0 +1,34 @@
+use crate::data::threads::MessageView;
+use chatter::chatter_message::{ChatterEvent, ChatterMessageSidecar};
+use serde::Serialize;
+
+/// A line in the NDJSON stream returned when a message is sent to a thread.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEventView {
+    /// A complete, persisted message.
+    Message(MessageView),
+    /// A fragment of the assistant's reply. Will be followed by a `message` with the full text.
+    TextDelta { delta: String },
+    /// The assistant started to call a tool.
+    ToolCallStarted { tool_call_id: String, name: String },
+    /// A title was generated for the thread. Sent after the first answer.
+    ThreadTitle { title: String },
+    /// A status update that isn't stored in the thread, such as a retried LLM request.
+    Sidecar { sidecar: ChatterMessageSidecar },
+}
+
+impl StreamEventView {
+    /// Convert an incremental chatter event. Complete messages have to be persisted
+    /// first, so they are converted from `ChatMessage` instead.
+    pub fn from_event(event: ChatterEvent) -> Option<Self> {
+        match event {
+            ChatterEvent::Message(_) | ChatterEvent::Usage(_) | ChatterEvent::Summary(_) => None,
+            ChatterEvent::TextDelta(delta) => Some(Self::TextDelta { delta }),
+            ChatterEvent::ToolCallStarted { tool_call_id, name } => {
+                Some(Self::ToolCallStarted { tool_call_id, name })
+            }
+            ChatterEvent::Sidecar(sidecar) => Some(Self::Sidecar { sidecar }),
+        }
+    }
+}
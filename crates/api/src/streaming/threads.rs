@@ -1,6 +1,6 @@
-use crate::data::threads::MessageView;
-use crate::error::AppError;
+use crate::error::{AppError, Result};
 use crate::state::AppState;
+use crate::streaming::events::StreamEventView;
 use anyhow::Context;
 use axum::body::{Body, Bytes};
 use axum::http::header;
@@ -8,20 +8,57 @@ use axum::{
     Json, Router,
     extract::{Path, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::post,
 };
 use chatter::chatter::Chatter;
 use chatter::chatter_context::ChatterContext;
-use chatter::chatter_message::Role;
+use chatter::chatter_message::{ChatterEvent, Role};
+use chatter::data::dynamodb::Db;
 use chatter::data::types::chat_message::{ChatMessage, ChatMessageBuilder};
 use chatter::data::types::chat_thread::ChatThread;
+use chatter::data::types::usage::UsageCounter;
+use chrono::Utc;
 use futures::{StreamExt, future};
+use lambda_http::tracing;
 use serde::Deserialize;
 use serde_json::json;
 use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use ulid::Ulid;
 
+/// An item in the turn stream, after complete messages have been assigned IDs.
+enum TurnItem {
+    Message(ChatMessage),
+    Event(ChatterEvent),
+}
+
+/// Generate a title for a thread that still has the default title, and save it.
+/// Returns `None` if no title was generated, or if the thread was modified in the meantime.
+async fn generate_thread_title(
+    db: &Db,
+    chatter: &Chatter,
+    mut thread: ChatThread,
+) -> anyhow::Result<Option<String>> {
+    let (title, usage) = chatter.generate_title().await?;
+    if let Some(usage) = usage {
+        UsageCounter::record(db, "demo_user", thread.id(), &usage, Utc::now()).await?;
+    }
+    let Some(title) = title else {
+        return Ok(None);
+    };
+    let ts = thread.modified_ts;
+    thread.title = title.clone();
+    thread.modified_ts = Utc::now();
+    match db.put_item_lock(&thread, "modified_ts", &ts).await {
+        Ok(()) => Ok(Some(title)),
+        // The user renamed (or otherwise changed) the thread while we were generating
+        Err(chatter::data::error::DataError::OptimisticLockFailed) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
 #[derive(Deserialize)]
 struct CreateThreadMessageRequest {
     content: String,
@@ -31,33 +68,109 @@ async fn create_thread_message_handler(
     State(state): State<AppState>,
     Path(id): Path<String>,
     Json(payload): Json<CreateThreadMessageRequest>,
-) -> impl IntoResponse {
-    let thread_id = Ulid::from_string(&id).context("Invalid thread ID")?;
+) -> Result<Response> {
+    let thread = get_open_thread(&state, &id).await?;
+    // Get the messages for the thread so we can re-instantiate the context
+    let messages =
+        ChatMessage::get_all_thread_messages(&state.ddb, "demo_user", thread.id()).await?;
+    run_turn(state, thread, messages, payload.content).await
+}
 
+/// Replace a past user message and everything after it, and answer the new message.
+async fn edit_thread_message_handler(
+    State(state): State<AppState>,
+    Path((id, message_id)): Path<(String, u32)>,
+    Json(payload): Json<CreateThreadMessageRequest>,
+) -> Result<Response> {
+    let thread = get_open_thread(&state, &id).await?;
+    let mut messages =
+        ChatMessage::get_all_thread_messages(&state.ddb, "demo_user", thread.id()).await?;
+    let is_user_message = messages
+        .iter()
+        .any(|m| m.id() == message_id && m.msg.role == Role::User);
+    if !is_user_message {
+        return Err(AppError::BadRequest(
+            "message_id must be a user message in this thread".to_string(),
+        ));
+    }
+
+    ChatMessage::truncate_thread(&state.ddb, "demo_user", thread.id(), message_id).await?;
+    messages.retain(|m| m.id() < message_id);
+    run_turn(state, thread, messages, payload.content).await
+}
+
+/// Discard the last answer and answer the last user message again.
+async fn regenerate_thread_message_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response> {
+    let thread = get_open_thread(&state, &id).await?;
+    let mut messages =
+        ChatMessage::get_all_thread_messages(&state.ddb, "demo_user", thread.id()).await?;
+    let Some(last_user_message) = messages.iter().rposition(|m| m.msg.role == Role::User) else {
+        return Err(AppError::BadRequest(
+            "the thread has no message to regenerate".to_string(),
+        ));
+    };
+
+    // The user message is written again by the turn, so it is removed too
+    let message = messages.split_off(last_user_message).remove(0);
+    ChatMessage::truncate_thread(&state.ddb, "demo_user", thread.id(), message.id()).await?;
+    run_turn(
+        state,
+        thread,
+        messages,
+        message.msg.message.unwrap_or_default(),
+    )
+    .await
+}
+
+/// Get a thread that new messages can be sent to.
+async fn get_open_thread(state: &AppState, id: &str) -> Result<ChatThread> {
+    let thread_id = Ulid::from_string(id).context("Invalid thread ID")?;
     let thread = ChatThread::get_thread(&state.ddb, "demo_user", &thread_id.to_string()).await?;
-    if thread.archived.unwrap_or(false) == true {
+    if thread.archived.unwrap_or(false) {
         return Err(AppError::Conflict("thread_archived".to_string()));
     }
+    Ok(thread)
+}
 
-    // Get the messages for the thread so we can re-instantiate the context
-    let messages =
-        ChatMessage::get_all_thread_messages(&state.ddb, "demo_user", &thread_id.to_string())
-            .await?;
+/// Add `content` as a user message after `messages`, and stream the answer as NDJSON.
+/// Every message of the turn is persisted, starting with the user message.
+async fn run_turn(
+    state: AppState,
+    mut thread: ChatThread,
+    messages: Vec<ChatMessage>,
+    content: String,
+) -> Result<Response> {
+    let thread_id = Ulid::from_string(thread.id()).context("Invalid thread ID")?;
+    // A turn that was interrupted can leave tool calls without responses
+    let messages = ChatMessage::repair_thread(&state.ddb, &mut thread, messages).await?;
     let thread_message_count = messages.len() as u32;
+    // Threads are created with their ID as the title. Replace it after the first answer.
+    let needs_title = messages.is_empty() && thread.title == thread.id();
 
-    let stream = {
-        let pg = state.postgres_pool.get().await?;
-        let mut chatter = Chatter::new(pg).await?;
-        if !messages.is_empty() {
-            let ctx = ChatterContext::new_with_stored(
-                thread_id.to_string(),
-                messages.into_iter().map(|m| m.msg).collect(),
-            );
-            chatter.switch_context(ctx).await?;
-        } else {
-            chatter.new_context().await?;
-        }
-        chatter.add_user_message(&payload.content)?;
+    let (stream, title_chatter, cancel) = {
+        let mut chatter =
+            Chatter::new(state.postgres_pool.clone(), state.chatter_config.clone()).await?;
+        let mut ctx = ChatterContext::new_with_stored(
+            thread_id.to_string(),
+            messages.into_iter().map(|m| m.msg).collect(),
+        );
+        ctx.model = thread.model.clone();
+        ctx.generation = thread.generation.clone();
+        // The deployment may have been restricted since the thread was created
+        ctx.tool_profile = thread.tool_profile.restrict_to_env();
+        // A summary that covers messages that have been replaced can't be used
+        ctx.summary = thread
+            .context_summary
+            .clone()
+            .filter(|summary| summary.covered <= thread_message_count as usize);
+        chatter.switch_context(ctx).await?;
+        chatter.add_user_message(&content)?;
+        // Shares the context with the chatter that runs the turn, so it sees the answer
+        let title_chatter = chatter.clone();
+        let cancel = chatter.cancel_token();
         // let messages = &chatter.context.messages;
         // let msg = messages.last().unwrap();
         // let mut binding = ChatMessageBuilder::default();
@@ -68,47 +181,111 @@ async fn create_thread_message_handler(
         // let message = binding.build()?;
         // state.ddb.put_item_excl(&message).await?;
 
-        chatter.execute_stream()
+        (chatter.execute_stream(), title_chatter, cancel)
     };
 
+    let title_db = state.ddb.clone();
+    let title_cancel = cancel.clone();
+    let title = futures::stream::once(async move {
+        if !needs_title || title_cancel.is_cancelled() {
+            return None;
+        }
+        match generate_thread_title(&title_db, &title_chatter, thread).await {
+            Ok(title) => {
+                title.map(|title| Ok::<_, AppError>(StreamEventView::ThreadTitle { title }))
+            }
+            Err(err) => {
+                // Not being able to generate a title shouldn't fail the turn
+                tracing::error!("failed to generate thread title: {:?}", err);
+                None
+            }
+        }
+    })
+    .filter_map(future::ready);
+
     let db = state.ddb.clone();
+    let mut next_message_id = thread_message_count;
+    // Usage is sent right before the assistant message it belongs to
+    let mut pending_usage = None;
     let stream = stream
-        .enumerate()
-        .map(move |(i, m)| {
-            let message = m?;
-            let mut binding = ChatMessageBuilder::default();
-            let builder = binding
-                .thread_message_ids(thread_id.to_string(), thread_message_count + i as u32)
-                .user_id("demo_user".to_string())
-                .msg(message.clone());
-            let db_message = builder.build()?;
-            Ok::<_, AppError>(db_message)
+        .map(move |event| {
+            let item = match event? {
+                ChatterEvent::Message(message) => {
+                    let mut binding = ChatMessageBuilder::default();
+                    let builder = binding
+                        .thread_message_ids(thread_id.to_string(), next_message_id)
+                        .user_id("demo_user".to_string())
+                        .msg(message)
+                        .usage(pending_usage.take());
+                    next_message_id += 1;
+                    TurnItem::Message(builder.build()?)
+                }
+                ChatterEvent::Usage(usage) => {
+                    pending_usage = Some(usage.clone());
+                    TurnItem::Event(ChatterEvent::Usage(usage))
+                }
+                event => TurnItem::Event(event),
+            };
+            Ok::<_, AppError>(item)
         })
-        .then(move |m| {
+        .then(move |item| {
             let value = db.clone();
             async move {
-                let db_message = m?;
-                value.put_item_excl(&db_message).await?;
-                Ok::<_, AppError>(db_message)
+                let item = item?;
+                match &item {
+                    TurnItem::Message(db_message) => {
+                        value.put_item_excl(db_message).await?;
+                    }
+                    TurnItem::Event(ChatterEvent::Usage(usage)) => {
+                        UsageCounter::record(
+                            &value,
+                            "demo_user",
+                            &thread_id.to_string(),
+                            usage,
+                            Utc::now(),
+                        )
+                        .await?;
+                    }
+                    TurnItem::Event(ChatterEvent::Summary(summary)) => {
+                        ChatThread::set_context_summary(
+                            &value,
+                            "demo_user",
+                            &thread_id.to_string(),
+                            summary,
+                        )
+                        .await?;
+                    }
+                    TurnItem::Event(_) => {}
+                }
+                Ok::<_, AppError>(item)
             }
         })
-        .filter(|m| {
+        .filter(|item| {
             // Filter out system messages.
-            let resp = if let Ok(db_message) = m {
+            let resp = if let Ok(TurnItem::Message(db_message)) = item {
                 db_message.msg.role != Role::System
             } else {
-                // Keep errors in the stream.
+                // Keep events and errors in the stream.
                 true
             };
             future::ready(resp)
         })
-        .map(|m| {
-            let message = m?;
-
-            let message_view: MessageView = message.into();
-            let message_json = serde_json::to_string(&message_view)?;
-            println!("sending message to user: {}", &message_json);
-            Ok::<_, AppError>(Bytes::from(message_json + "\n"))
+        .filter_map(|item| {
+            let view = match item {
+                Ok(TurnItem::Message(message)) => Some(StreamEventView::Message(message.into())),
+                Ok(TurnItem::Event(event)) => StreamEventView::from_event(event),
+                Err(err) => return future::ready(Some(Err(err))),
+            };
+            future::ready(view.map(Ok))
+        })
+        .chain(title)
+        .map(|view| {
+            let view = view?;
+            let view_json = serde_json::to_string(&view)?;
+            if let StreamEventView::Message(_) = view {
+                println!("sending message to user: {}", &view_json);
+            }
+            Ok::<_, AppError>(Bytes::from(view_json + "\n"))
         })
         .map(|res| {
             Ok::<_, Infallible>(res.unwrap_or_else(|err| {
@@ -120,7 +297,34 @@ async fn create_thread_message_handler(
             }))
         });
 
-    let body = Body::from_stream(stream);
+    // The turn runs in its own task, so it is finished (and persisted) even if the client
+    // goes away. When it does, the turn is cancelled, and the task keeps consuming the
+    // stream until the cancellation has been written to the thread.
+    let (tx, rx) = mpsc::channel::<std::result::Result<Bytes, Infallible>>(32);
+    tokio::spawn(async move {
+        let mut stream = std::pin::pin!(stream);
+        let mut connected = true;
+        loop {
+            tokio::select! {
+                chunk = stream.next() => {
+                    let Some(chunk) = chunk else {
+                        break;
+                    };
+                    if connected && tx.send(chunk).await.is_err() {
+                        connected = false;
+                        cancel.cancel();
+                    }
+                }
+                _ = tx.closed(), if connected => {
+                    tracing::info!("client disconnected, cancelling turn in thread {}", thread_id);
+                    connected = false;
+                    cancel.cancel();
+                }
+            }
+        }
+    });
+
+    let body = Body::from_stream(ReceiverStream::new(rx));
 
     Ok((
         StatusCode::OK,
@@ -134,4 +338,12 @@ pub fn threads_routes() -> Router<AppState> {
     Router::new()
         // this route is is the streaming API
         .route("/threads/{id}/message", post(create_thread_message_handler))
+        .route(
+            "/threads/{id}/messages/{message_id}/edit",
+            post(edit_thread_message_handler),
+        )
+        .route(
+            "/threads/{id}/regenerate",
+            post(regenerate_thread_message_handler),
+        )
 }
@@ -0,0 +1,309 @@
+//! Procedural macros for `chatter`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::meta::ParseNestedMeta;
+use syn::{
+    Attribute, Error, Expr, FnArg, Ident, ItemFn, Lit, LitInt, LitStr, Pat, Type, parse_macro_input,
+};
+
+/// Turns an `async fn` into an LLM function (a tool the model can call).
+///
+/// ```ignore
+/// /// Get detailed information about the requested tables.
+/// #[llm_function(concurrency_safe)]
+/// async fn describe_tables(
+///     resources: &SharedResources,
+///     /// The tables to describe.
+///     table_names: Vec<String>,
+/// ) -> Result<ToolOutput> {
+///     ...
+/// }
+/// ```
+///
+/// generates `DescribeTablesParams` from the arguments, and `DescribeTablesFunction`, which
+/// implements `LlmFunction` and `LlmFunctionExecutor`.
+///
+/// - The doc comment of the function is the tool description. Doc comments on the arguments
+///   become the descriptions in the parameters schema.
+/// - An argument with a reference type receives the `SharedResources`. Every other argument
+///   is a parameter.
+/// - The function returns `Result<T>`, where `ToolOutput: From<T>` (`ToolOutput` or `String`).
+///
+/// Options: `name = "..."` (defaults to the function name), `concurrency_safe`,
+/// `timeout_secs = N` and `access = read | write | admin` (defaults to `write`).
+///
+/// The generated code refers to `crate::functions`, so it can only be used inside `chatter`.
+#[proc_macro_attribute]
+pub fn llm_function(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut options = Options::default();
+    let parser = syn::meta::parser(|meta| options.parse(meta));
+    parse_macro_input!(attr with parser);
+    let function = parse_macro_input!(item as ItemFn);
+    expand(options, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct Options {
+    name: Option<LitStr>,
+    concurrency_safe: bool,
+    timeout_secs: Option<LitInt>,
+    access: Option<Ident>,
+}
+
+impl Options {
+    fn parse(&mut self, meta: ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("concurrency_safe") {
+            self.concurrency_safe = true;
+        } else if meta.path.is_ident("timeout_secs") {
+            self.timeout_secs = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("access") {
+            let access: Ident = meta.value()?.parse()?;
+            if !["read", "write", "admin"].contains(&access.to_string().as_str()) {
+                return Err(Error::new_spanned(
+                    access,
+                    "access must be `read`, `write` or `admin`",
+                ));
+            }
+            self.access = Some(access);
+        } else {
+            return Err(meta.error("unsupported llm_function option"));
+        }
+        Ok(())
+    }
+}
+
+/// `describe_tables` -> `DescribeTables`
+fn to_camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// The text of the doc comments, one line per `///` line.
+fn doc_string(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta.require_name_value().ok()?.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(s) => Some(s.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|line| {
+            line.strip_prefix(' ')
+                .unwrap_or(&line)
+                .trim_end()
+                .to_string()
+        })
+        .collect();
+    let doc = lines.join("\n").trim().to_string();
+    (!doc.is_empty()).then_some(doc)
+}
+
+fn expand(options: Options, mut function: ItemFn) -> syn::Result<TokenStream2> {
+    if function.sig.asyncness.is_none() {
+        return Err(Error::new_spanned(
+            function.sig.fn_token,
+            "llm_function must be an async fn",
+        ));
+    }
+    let description = doc_string(&function.attrs).ok_or_else(|| {
+        Error::new_spanned(
+            &function.sig.ident,
+            "llm_function needs a doc comment, which is used as the tool description",
+        )
+    })?;
+
+    let fn_ident = function.sig.ident.clone();
+    let name = options
+        .name
+        .map(|name| name.value())
+        .unwrap_or_else(|| fn_ident.to_string());
+    let camel_case = to_camel_case(&fn_ident.to_string());
+    let params_ident = format_ident!("{}Params", camel_case);
+    let function_ident = format_ident!("{}Function", camel_case);
+
+    let mut fields = vec![];
+    let mut call_args = vec![];
+    for input in function.sig.inputs.iter_mut() {
+        let FnArg::Typed(arg) = input else {
+            return Err(Error::new_spanned(input, "llm_function can't take `self`"));
+        };
+        if let Type::Reference(_) = *arg.ty {
+            call_args.push(quote!(resources));
+            continue;
+        }
+        let Pat::Ident(pat) = &*arg.pat else {
+            return Err(Error::new_spanned(
+                &arg.pat,
+                "llm_function arguments must be plain identifiers",
+            ));
+        };
+        let ident = &pat.ident;
+        // Doc comments aren't allowed on function arguments, so they are moved to the field
+        let (docs, attrs): (Vec<_>, Vec<_>) = arg
+            .attrs
+            .drain(..)
+            .partition(|attr| attr.path().is_ident("doc"));
+        arg.attrs = attrs;
+        let ty = &arg.ty;
+        fields.push(quote! { #(#docs)* #ident: #ty });
+        call_args.push(quote!(params.#ident));
+    }
+
+    let params_binding = if fields.is_empty() {
+        quote!(_)
+    } else {
+        quote!(params)
+    };
+    let concurrency_safe = options.concurrency_safe.then(|| {
+        quote! {
+            fn concurrency_safe(&self) -> bool {
+                true
+            }
+        }
+    });
+    let timeout = options.timeout_secs.map(|secs| {
+        quote! {
+            fn timeout(&self) -> ::std::time::Duration {
+                ::std::time::Duration::from_secs(#secs)
+            }
+        }
+    });
+    let access = options.access.map(|access| {
+        let variant = format_ident!("{}", to_camel_case(&access.to_string()));
+        quote! {
+            fn access(&self) -> crate::functions::ToolAccess {
+                crate::functions::ToolAccess::#variant
+            }
+        }
+    });
+    let function_doc = format!("The `{}` tool.", name);
+
+    Ok(quote! {
+        #function
+
+        #[derive(::serde::Serialize, ::serde::Deserialize, ::schemars::JsonSchema)]
+        #[schemars(deny_unknown_fields)]
+        pub struct #params_ident {
+            #(#fields,)*
+        }
+
+        #[doc = #function_doc]
+        pub struct #function_ident;
+
+        impl crate::functions::LlmFunction for #function_ident {
+            fn name(&self) -> &str {
+                #name
+            }
+
+            fn description(&self) -> &str {
+                #description
+            }
+
+            fn parameters_schema(&self) -> ::serde_json::Value {
+                ::serde_json::json!(::schemars::schema_for!(#params_ident))
+            }
+
+            #concurrency_safe
+
+            #timeout
+
+            #access
+        }
+
+        #[::async_trait::async_trait]
+        impl crate::functions::LlmFunctionExecutor for #function_ident {
+            #[allow(unused_variables)]
+            async fn execute(
+                &self,
+                resources: &crate::functions::SharedResources,
+                tool_call_id: ::std::string::String,
+                params: ::serde_json::Value,
+            ) -> crate::error::Result<crate::chatter_message::ChatterMessage> {
+                let #params_binding: #params_ident = ::serde_json::from_value(params)?;
+                let output = #fn_ident(#(#call_args),*).await?;
+                Ok(crate::functions::ToolOutput::from(output).into_message(tool_call_id))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn test_to_camel_case() {
+        assert_eq!(to_camel_case("describe_tables"), "DescribeTables");
+        assert_eq!(to_camel_case("query_database"), "QueryDatabase");
+        assert_eq!(to_camel_case("geocode"), "Geocode");
+    }
+
+    #[test]
+    fn test_doc_string() {
+        let function: ItemFn = parse_quote! {
+            /// First line.
+            ///
+            /// Second line with `code`.
+            async fn f() {}
+        };
+        assert_eq!(
+            doc_string(&function.attrs).as_deref(),
+            Some("First line.\n\nSecond line with `code`.")
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let function: ItemFn = parse_quote! {
+            /// Get detailed information about the requested tables.
+            async fn describe_tables(
+                resources: &SharedResources,
+                /// The tables to describe.
+                table_names: Vec<String>,
+            ) -> Result<ToolOutput> {
+                todo!()
+            }
+        };
+        let options = Options {
+            concurrency_safe: true,
+            access: Some(format_ident!("read")),
+            ..Default::default()
+        };
+        let expanded = expand(options, function).unwrap().to_string();
+        assert!(expanded.contains("pub struct DescribeTablesParams"));
+        assert!(expanded.contains("pub struct DescribeTablesFunction"));
+        assert!(expanded.contains("\"describe_tables\""));
+        assert!(expanded.contains("fn concurrency_safe"));
+        assert!(!expanded.contains("fn timeout"));
+        assert!(expanded.contains("ToolAccess :: Read"));
+        assert!(expanded.contains("params . table_names"));
+
+        let function: ItemFn = parse_quote! {
+            /// Not async.
+            fn describe_tables() {}
+        };
+        assert!(expand(Options::default(), function).is_err());
+
+        let function: ItemFn = parse_quote! {
+            async fn undocumented() {}
+        };
+        assert!(expand(Options::default(), function).is_err());
+    }
+}
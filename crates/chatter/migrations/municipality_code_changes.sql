@@ -0,0 +1,44 @@
+-- The changes of 全国地方公共団体コード (廃置分合 and renames), for `resolve_municipality_code`.
+-- See DB_SETUP.md for loading them.
+
+CREATE TABLE IF NOT EXISTS municipality_code_changes (
+    old_code char(5) NOT NULL,
+    old_name text,
+    new_code char(5) NOT NULL,
+    new_name text,
+    effective_date date NOT NULL,
+    reason text,
+    PRIMARY KEY (old_code, new_code, effective_date)
+);
+CREATE INDEX IF NOT EXISTS municipality_code_changes_new_code_idx
+    ON municipality_code_changes (new_code);
+
+-- The current code of every code that has changed, for joining datasets of different years.
+-- A code that was split (分割) has a row for each code it became, with `split` set.
+CREATE MATERIALIZED VIEW IF NOT EXISTS municipality_code_current AS (
+    WITH RECURSIVE chain AS (
+        SELECT old_code AS code, new_code, effective_date
+            FROM municipality_code_changes
+            WHERE new_code <> old_code
+        UNION
+        SELECT chain.code, c.new_code, c.effective_date
+            FROM municipality_code_changes AS c
+            JOIN chain ON c.old_code = chain.new_code
+                AND c.effective_date >= chain.effective_date
+                AND c.new_code <> c.old_code
+    ),
+    current AS (
+        SELECT DISTINCT code::text AS code, new_code::text AS current_code
+            FROM chain
+            WHERE NOT EXISTS (
+                SELECT 1 FROM municipality_code_changes AS c
+                    WHERE c.old_code = chain.new_code
+                        AND c.new_code <> c.old_code
+                        AND c.effective_date >= chain.effective_date
+            )
+    )
+    SELECT code, current_code, COUNT(*) OVER (PARTITION BY code) > 1 AS split
+        FROM current
+);
+CREATE INDEX IF NOT EXISTS municipality_code_current_code_idx
+    ON municipality_code_current (code);
@@ -1,20 +1,39 @@
 use crate::{
     chatter_context::ChatterContext,
-    chatter_message::ChatterMessage,
+    chatter_message::{
+        ChatterEvent, ChatterMessage, ChatterMessageSidecar, LlmFallbackDetails, LlmRetryDetails,
+        MessageUsage,
+    },
+    config::ChatterConfig,
+    context_window::{self, ContextSummary, FittedContext},
     data::types::sql_query::SqlQuery,
     error::{ChatterError, Result},
-    functions::{FunctionRegistry, SharedResources},
+    functions::{SharedResources, ToolProfile},
     geom::GeometryWrapper,
+    llm::{self, LlmProvider, LlmRequest, LlmStreamEvent, ModelCapabilities, RetryClass},
     pg_helpers::convert_column_value,
+    turn_limits::{TurnBudget, TurnLimit},
 };
-use async_openai::types::{
-    ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionResponseMessage,
-    CreateChatCompletionRequestArgs,
-};
+use async_openai::types::{ChatCompletionMessageToolCall, Role};
 use async_stream::try_stream;
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use geo_types::Geometry;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_postgres::NoTls;
+use tokio_util::sync::CancellationToken;
+
+/// Instructions used when the earliest turns of a conversation have to be summarized.
+const SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and a GIS assistant. \
+Keep the user's goals, the tables and columns that were used, any query IDs, and conclusions that were reached. \
+Be concise.";
+
+/// Instructions used to generate a thread title.
+const TITLE_PROMPT: &str = "Write a short title for the following conversation, in the same language as the user's message. \
+Reply with only the title, without quotes or punctuation at the end.";
+
+/// Generated titles are cut off at this many characters.
+const MAX_TITLE_CHARS: usize = 40;
 
 pub struct QueryResultRow {
     pub geom: Geometry,
@@ -24,38 +43,39 @@ pub struct QueryResultRow {
 #[derive(Clone)]
 pub struct Chatter {
     pub context: Arc<Mutex<ChatterContext>>,
-    pub client: async_openai::Client<async_openai::config::OpenAIConfig>,
+    pub provider: Arc<dyn LlmProvider>,
     pub ddb_client: Arc<crate::data::dynamodb::Db>,
-    pub pg_client: Arc<deadpool_postgres::Client>,
+    /// Each tool call checks out its own connection, so concurrent calls run in parallel.
+    pub pg_pool: deadpool_postgres::Pool,
 
-    resources: SharedResources,
-    function_registry: Arc<FunctionRegistry>,
+    config: Arc<ChatterConfig>,
+    /// Cancels the running turn. Shared by clones of this Chatter.
+    cancel: CancellationToken,
 }
 
 impl Chatter {
-    pub async fn new(pg_client: deadpool_postgres::Client) -> Result<Self> {
-        let pg_client = Arc::new(pg_client);
+    /// Create a new Chatter using the LLM provider configured in the environment.
+    pub async fn new(pg_pool: deadpool_postgres::Pool, config: Arc<ChatterConfig>) -> Result<Self> {
+        let provider = crate::llm::provider_from_env()?;
+        Self::new_with_provider(pg_pool, config, provider).await
+    }
+
+    /// Create a new Chatter backed by the given LLM provider.
+    pub async fn new_with_provider(
+        pg_pool: deadpool_postgres::Pool,
+        config: Arc<ChatterConfig>,
+        provider: Arc<dyn LlmProvider>,
+    ) -> Result<Self> {
         let ddb_client = Arc::new(crate::data::dynamodb::Db::new().await);
         let context = Arc::new(Mutex::new(ChatterContext::new()));
 
-        let resources = SharedResources {
-            chatter_context: context.clone(),
-            pg: pg_client.clone(),
-            ddb: ddb_client.clone(),
-        };
-
-        let mut function_registry = FunctionRegistry::new();
-        function_registry.register(crate::functions::DescribeTablesFunction);
-        function_registry.register(crate::functions::QueryDatabaseFunction);
-        function_registry.register(crate::functions::RequestUnavailableDataFunction);
-
         Ok(Self {
             context,
-            client: async_openai::Client::new(),
-            pg_client,
+            provider,
+            pg_pool,
             ddb_client,
-            resources,
-            function_registry: Arc::new(function_registry),
+            config,
+            cancel: CancellationToken::new(),
         })
     }
 
@@ -63,7 +83,7 @@ impl Chatter {
     /// will be replaced with the new context.
     pub async fn new_context(&mut self) -> Result<()> {
         let mut ctx = ChatterContext::new();
-        ctx.tools = self.function_registry.get_tools();
+        ctx.tools = self.config.function_registry.get_tools(ctx.tool_profile);
         self.switch_context(ctx).await
     }
 
@@ -72,16 +92,32 @@ impl Chatter {
     /// Note that these messages should not include the system message -- it will be added in this function.
     pub async fn switch_context(&mut self, mut context: ChatterContext) -> Result<()> {
         // because the context doesn't have the system message, we will add it here.
-        let system_message = ChatterMessage::create_system_message(&self.pg_client).await?;
+        let pg_client = self.pg_pool.get().await?;
+        let system_message = ChatterMessage::create_system_message(&pg_client).await?;
         context.messages.insert(0, system_message);
 
-        // Set the tools from the function registry
-        context.tools = self.function_registry.get_tools();
+        // Set the tools of the thread's profile from the function registry
+        context.tools = self
+            .config
+            .function_registry
+            .get_tools(context.tool_profile);
+        context.context_policy = self.config.context_policy.clone();
+        context.turn_limits = self.config.turn_limits.clone();
+        context.retry_policy = self.config.retry_policy.clone();
+
+        // A turn that was interrupted can leave tool calls without responses. Stored threads
+        // are repaired with `ChatMessage::repair_thread` first, so this is only a safeguard.
+        let repaired = context.repair_tool_calls();
+        if repaired > 0 {
+            tracing::warn!(
+                "repaired {} messages with unanswered tool calls in context {}",
+                repaired,
+                context.id
+            );
+        }
 
         // Set the new context
         self.context = Arc::new(Mutex::new(context));
-        // Update the resources with the new context
-        self.resources.chatter_context = self.context.clone();
 
         Ok(())
     }
@@ -92,36 +128,300 @@ impl Chatter {
         Ok(())
     }
 
-    pub fn execute_stream(mut self) -> impl Stream<Item = Result<ChatterMessage>> {
+    /// A token that cancels the turn run by `execute_stream`. The running LLM request and
+    /// database queries are stopped, and the turn ends with a "cancelled" message.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Run the conversation until the assistant has finished responding. Complete messages
+    /// are emitted as `ChatterEvent::Message`, interleaved with incremental events.
+    pub fn execute_stream(self) -> impl Stream<Item = Result<ChatterEvent>> {
         let stream = try_stream! {
             let last_message = {
                 let context = self.context.lock().unwrap();
                 context.messages.last().cloned()
             };
             if let Some(last_message) = last_message {
-                yield last_message;
+                yield ChatterEvent::Message(last_message);
             }
 
+            let (mut budget, retry_policy, tool_profile) = {
+                let context = self.context.lock().unwrap();
+                (
+                    TurnBudget::new(context.turn_limits.clone()),
+                    context.retry_policy.clone(),
+                    context.tool_profile,
+                )
+            };
+            // Set when the model failed and the fallback model took over
+            let mut model_override: Option<String> = None;
+
             loop {
-                let message = self.create_and_send_request().await?;
+                if let Some(limit) = budget.check_round_trip() {
+                    let message = budget.limit_message(limit);
+                    self.context.lock().unwrap().add_message(message.clone());
+                    yield ChatterEvent::Message(message);
+                    break;
+                }
+
+                // Send the request, retrying transient failures. Once the retries run out,
+                // the rest of the turn uses the fallback model, if there is one.
+                let mut retries = 0;
+                let mut summary_usage: Option<MessageUsage> = None;
+                // Requests that are still running at the deadline are abandoned
+                let deadline = tokio::time::Instant::now() + budget.remaining();
+                let outcome = loop {
+                    let model = model_override.clone().unwrap_or_else(|| {
+                        self.context.lock().unwrap().model.clone()
+                    });
+                    let mut prompt_tokens: usize = 0;
+                    let result = match self.within_turn(deadline, self.prepare_request(&model)).await {
+                        Err(error) => Err(error),
+                        Ok((request, summary)) => {
+                            if let Some((summary, usage)) = summary {
+                                yield ChatterEvent::Summary(summary);
+                                if let Some(usage) = usage {
+                                    match summary_usage.as_mut() {
+                                        Some(summary_usage) => summary_usage.add(&usage),
+                                        None => summary_usage = Some(usage),
+                                    }
+                                }
+                            }
+                            prompt_tokens = request
+                                .messages
+                                .iter()
+                                .map(context_window::count_message_tokens)
+                                .sum();
+                            match self.within_turn(deadline, self.provider.stream(request)).await {
+                                Err(error) => Err(error),
+                                Ok(mut events) => {
+                                    let mut result = Err(ChatterError::LlmStreamIncomplete);
+                                    loop {
+                                        let event = tokio::select! {
+                                            biased;
+                                            _ = self.cancel.cancelled() => Some(Err(ChatterError::Cancelled)),
+                                            _ = tokio::time::sleep_until(deadline) => Some(Err(ChatterError::TurnDeadline)),
+                                            event = events.next() => event,
+                                        };
+                                        let Some(event) = event else {
+                                            break;
+                                        };
+                                        match event {
+                                            Ok(LlmStreamEvent::TextDelta(delta)) => {
+                                                yield ChatterEvent::TextDelta(delta);
+                                            }
+                                            Ok(LlmStreamEvent::ToolCallStarted { id, name }) => {
+                                                yield ChatterEvent::ToolCallStarted { tool_call_id: id, name };
+                                            }
+                                            Ok(LlmStreamEvent::Done(done)) => {
+                                                result = Ok(done);
+                                            }
+                                            Err(error) => {
+                                                result = Err(error);
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    result
+                                }
+                            }
+                        }
+                    };
+                    let error = match result {
+                        Ok(response) => break Ok((response, model, prompt_tokens)),
+                        Err(error) => error,
+                    };
+
+                    let retry_after = match llm::classify(&error) {
+                        RetryClass::Retryable { retry_after } => retry_after,
+                        RetryClass::Fatal => break Err(error),
+                    };
+                    if retries < retry_policy.max_retries {
+                        retries += 1;
+                        let delay = retry_policy.backoff(retries, retry_after, rand::random());
+                        yield ChatterEvent::Sidecar(ChatterMessageSidecar::LlmRetry(LlmRetryDetails {
+                            model,
+                            attempt: retries,
+                            max_retries: retry_policy.max_retries,
+                            delay_ms: delay.as_millis() as u64,
+                            error: error.to_string(),
+                        }));
+                        let sleep = async {
+                            tokio::time::sleep(delay).await;
+                            Ok(())
+                        };
+                        if let Err(error) = self.within_turn(deadline, sleep).await {
+                            break Err(error);
+                        }
+                        continue;
+                    }
+                    match retry_policy.fallback_model.clone() {
+                        Some(fallback) if model_override.is_none() && fallback != model => {
+                            yield ChatterEvent::Sidecar(ChatterMessageSidecar::LlmFallback(LlmFallbackDetails {
+                                from_model: model,
+                                to_model: fallback.clone(),
+                                error: error.to_string(),
+                            }));
+                            model_override = Some(fallback);
+                            retries = 0;
+                        }
+                        _ => break Err(error),
+                    }
+                };
+                // The assistant message with the tool calls has already been persisted, so the
+                // turn has to end with a message rather than an error. Otherwise the thread
+                // can't be sent to the model again.
+                let (response, model, prompt_tokens) = match outcome {
+                    Ok(outcome) => outcome,
+                    Err(error) => {
+                        let message = match error {
+                            ChatterError::Cancelled => ChatterMessage::turn_cancelled(),
+                            ChatterError::TurnDeadline => budget.limit_message(TurnLimit::Deadline),
+                            error => ChatterMessage::turn_failed(&error.to_string()),
+                        };
+                        self.context.lock().unwrap().add_message(message.clone());
+                        yield ChatterEvent::Message(message);
+                        break;
+                    }
+                };
+                // Estimate the usage if the provider didn't report it
+                let tokens = match response.usage {
+                    Some(usage) => usage.total_tokens() as usize,
+                    None => prompt_tokens + context_window::count_message_tokens(&response.message),
+                };
+                budget.record_round_trip(tokens as u64);
+                let message = response.message;
+
+                // Summarizing the context was part of generating this message, so it counts towards its usage
+                let usage = match (response.usage, summary_usage) {
+                    (Some(usage), summary) => {
+                        let mut usage = MessageUsage::new(&model, &usage);
+                        if let Some(summary) = summary {
+                            usage.add(&summary);
+                        }
+                        Some(usage)
+                    }
+                    (None, summary) => summary,
+                };
+                if let Some(usage) = usage {
+                    yield ChatterEvent::Usage(usage);
+                }
 
                 // Add the AI response to the context
-                let cmessage: ChatterMessage = message.clone().try_into()?;
                 {
                     let mut context = self.context.lock().unwrap();
-                    context.add_message(cmessage.clone());
+                    context.add_message(message.clone());
                 };
-                yield cmessage;
+                yield ChatterEvent::Message(message.clone());
 
                 if let Some(tool_calls) = message.tool_calls {
-                    // Iterate over all tool calls and process each one
-                    for tool_call in tool_calls {
-                        let tool_response = self.execute_tool_call(tool_call).await?;
-                        {
-                            let mut context = self.context.lock().unwrap();
-                            context.add_message(tool_response.clone());
-                        };
-                        yield tool_response;
+                    // Tool calls are run in batches: a run of consecutive calls that are safe to
+                    // run concurrently, or a single call that isn't. Responses are added in the
+                    // order of the calls. Once a limit is reached, the remaining calls are
+                    // answered without being executed.
+                    let mut reached = None;
+                    // The calls that haven't been answered when the turn is cancelled
+                    let mut cancelled: Option<Vec<String>> = None;
+                    let mut tool_calls = tool_calls.into_iter().peekable();
+                    while let Some(first) = tool_calls.next() {
+                        let concurrent = self.config.function_registry.is_concurrency_safe(&first.function.name);
+                        let mut batch = vec![first];
+                        while let Some(tool_call) = tool_calls.next_if(|call| {
+                            concurrent && self.config.function_registry.is_concurrency_safe(&call.function.name)
+                        }) {
+                            batch.push(tool_call);
+                        }
+
+                        let mut run = vec![];
+                        let mut skipped = vec![];
+                        for tool_call in batch {
+                            if reached.is_none() {
+                                reached = budget.check_tool_call();
+                            }
+                            if reached.is_none() {
+                                budget.record_tool_call();
+                                run.push(tool_call);
+                            } else {
+                                skipped.push(tool_call.id);
+                            }
+                        }
+
+                        let run_ids: Vec<String> = run.iter().map(|call| call.id.clone()).collect();
+                        let run_names: Vec<String> =
+                            run.iter().map(|call| call.function.name.clone()).collect();
+                        let run: Vec<_> = run
+                            .into_iter()
+                            .map(|call| {
+                                let timeout = budget.tool_timeout(&call.function.name);
+                                (call, timeout)
+                            })
+                            .collect();
+                        let mut answered = 0;
+                        let mut responses = futures::stream::iter(run)
+                            .map(|(tool_call, timeout)| {
+                                self.execute_tool_call(tool_call, tool_profile, timeout)
+                            })
+                            .buffered(budget.max_concurrent_tool_calls());
+                        while let Some(tool_response) = responses.next().await {
+                            if self.cancel.is_cancelled() {
+                                // Each call cancels its own queries when the turn is cancelled,
+                                // so the rest of the batch is waited for rather than dropped.
+                                while responses.next().await.is_some() {}
+                                let mut unanswered = run_ids[answered..].to_vec();
+                                unanswered.append(&mut skipped);
+                                unanswered.extend(tool_calls.by_ref().map(|call| call.id));
+                                cancelled = Some(unanswered);
+                                break;
+                            }
+                            // Errors are sent back to the model, so it can correct the call
+                            let tool_response = match tool_response {
+                                Ok(tool_response) => tool_response,
+                                Err(error) => {
+                                    if let ChatterError::InvalidToolCall(_) = error {
+                                        budget.record_invalid_tool_call();
+                                    }
+                                    ChatterMessage::tool_failed(
+                                        &run_ids[answered],
+                                        &run_names[answered],
+                                        &error,
+                                    )
+                                }
+                            };
+                            answered += 1;
+                            self.context.lock().unwrap().add_message(tool_response.clone());
+                            yield ChatterEvent::Message(tool_response);
+                        }
+                        if cancelled.is_some() {
+                            break;
+                        }
+                        if let Some(limit) = reached {
+                            for id in skipped {
+                                let tool_response = budget.skipped_tool_response(&id, limit);
+                                self.context.lock().unwrap().add_message(tool_response.clone());
+                                yield ChatterEvent::Message(tool_response);
+                            }
+                        }
+                    }
+                    if let Some(unanswered) = cancelled {
+                        for id in unanswered {
+                            let tool_response = ChatterMessage::tool_error(
+                                &id,
+                                "The turn was cancelled before this tool call finished.",
+                            );
+                            self.context.lock().unwrap().add_message(tool_response.clone());
+                            yield ChatterEvent::Message(tool_response);
+                        }
+                        let message = ChatterMessage::turn_cancelled();
+                        self.context.lock().unwrap().add_message(message.clone());
+                        yield ChatterEvent::Message(message);
+                        break;
+                    }
+                    if let Some(limit) = reached {
+                        let message = budget.limit_message(limit);
+                        self.context.lock().unwrap().add_message(message.clone());
+                        yield ChatterEvent::Message(message);
+                        break;
                     }
                     // Continue the loop to process the next message
                 } else {
@@ -133,48 +433,209 @@ impl Chatter {
         stream
     }
 
-    /// Creates and sends a chat completion request, then returns the message from the response.
-    async fn create_and_send_request(&mut self) -> Result<ChatCompletionResponseMessage> {
-        let request = {
+    /// Run `future`, unless the turn is cancelled first.
+    async fn unless_cancelled<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(ChatterError::Cancelled),
+            result = future => result,
+        }
+    }
+
+    /// Run `future`, unless the turn is cancelled or reaches `deadline` first.
+    async fn within_turn<T>(
+        &self,
+        deadline: tokio::time::Instant,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        self.unless_cancelled(async {
+            tokio::time::timeout_at(deadline, future)
+                .await
+                .map_err(|_| ChatterError::TurnDeadline)?
+        })
+        .await
+    }
+
+    /// Cancel the queries that are running on a Postgres connection. Dropping a query's
+    /// future doesn't stop it on the server.
+    async fn cancel_queries(pg_client: &deadpool_postgres::Client) {
+        if let Err(error) = pg_client.cancel_token().cancel_query(NoTls).await {
+            tracing::error!("failed to cancel running queries: {}", error);
+        }
+    }
+
+    /// Creates a chat completion request from the current context. If the conversation
+    /// doesn't fit in the context window, the earliest turns are summarized first, and
+    /// the new summary and the tokens used for it are returned with the request.
+    async fn prepare_request(
+        &self,
+        model: &str,
+    ) -> Result<(LlmRequest, Option<(ContextSummary, Option<MessageUsage>)>)> {
+        let (messages, mut policy, summary, capabilities, settings) = {
             let context = self.context.lock().unwrap();
-            // Create the chat completion request
-            let mut builder = CreateChatCompletionRequestArgs::default();
-            builder
-                .max_completion_tokens(2048u32)
-                .model(&context.model)
-                .messages(
-                    context
-                        .messages
-                        .iter()
-                        .map(|m| m.clone().try_into())
-                        .collect::<Result<Vec<ChatCompletionRequestMessage>>>()?,
-                )
-                .tools(context.tools.clone());
-            // The following two options are supported by gpt-4o, but not o3-mini
-            // builder.temperature(0.2);
-            // builder.parallel_tool_calls(false); // We only want to run one tool at a time
-            builder
-                .build()
-                .map_err(|e| ChatterError::OpenAIError(e.into()))?
+            let capabilities = ModelCapabilities::for_model(model);
+            (
+                context.messages.clone(),
+                context.context_policy.clone(),
+                context.summary.clone(),
+                capabilities,
+                capabilities.apply(&context.generation),
+            )
         };
-        // Send the request and get the response
-        let response = self.client.chat().create(request).await?;
-        let choice = response.choices[0].clone();
+        // Leave room in the model's context window for the completion
+        let available = capabilities
+            .context_window
+            .saturating_sub(settings.max_completion_tokens as usize);
+        policy.max_prompt_tokens = policy.max_prompt_tokens.min(available);
 
-        Ok(choice.message)
+        let mut new_summary = None;
+        let messages =
+            match context_window::fit_messages(&messages, &policy, summary.as_ref(), true) {
+                FittedContext::Ready(messages) => messages,
+                FittedContext::NeedsSummary { covered } => {
+                    let (summary, usage) = self
+                        .summarize_messages(model, &messages, summary.as_ref(), covered)
+                        .await?;
+                    new_summary = Some((summary.clone(), usage));
+                    self.context.lock().unwrap().summary = Some(summary.clone());
+                    // If the summary still isn't enough, the earliest turns are dropped.
+                    match context_window::fit_messages(&messages, &policy, Some(&summary), false) {
+                        FittedContext::Ready(messages) => messages,
+                        FittedContext::NeedsSummary { .. } => {
+                            unreachable!("fit_messages doesn't summarize when not allowed")
+                        }
+                    }
+                }
+            };
+
+        let context = self.context.lock().unwrap();
+        let request = LlmRequest {
+            model: model.to_string(),
+            messages,
+            tools: context.tools.clone(),
+            max_completion_tokens: settings.max_completion_tokens,
+            temperature: settings.temperature,
+            parallel_tool_calls: settings.parallel_tool_calls,
+        };
+        Ok((request, new_summary))
+    }
+
+    /// Summarize the first `covered` messages following the system message, together
+    /// with the previous summary.
+    async fn summarize_messages(
+        &self,
+        model: &str,
+        messages: &[ChatterMessage],
+        previous: Option<&ContextSummary>,
+        covered: usize,
+    ) -> Result<(ContextSummary, Option<MessageUsage>)> {
+        let start = context_window::system_prefix_len(messages);
+        let previous = previous.filter(|p| p.covered <= covered);
+        let mut transcript = String::new();
+        if let Some(previous) = previous {
+            transcript.push_str(&format!("Summary so far:\n{}\n\n", previous.text));
+        }
+        let from = start + previous.map(|p| p.covered).unwrap_or(0);
+        transcript.push_str(&context_window::render_transcript(
+            &messages[from..start + covered],
+        ));
+
+        let request = LlmRequest {
+            model: model.to_string(),
+            messages: vec![
+                text_message(Role::System, SUMMARY_PROMPT.to_string()),
+                text_message(Role::User, transcript),
+            ],
+            tools: vec![],
+            max_completion_tokens: 1024,
+            temperature: None,
+            parallel_tool_calls: None,
+        };
+        let response = self.provider.complete(request).await?;
+        let usage = response.usage.map(|usage| MessageUsage::new(model, &usage));
+        let summary = ContextSummary {
+            covered,
+            text: response.message.message.unwrap_or_default(),
+        };
+        Ok((summary, usage))
     }
 
-    /// Executes a tool call and returns the response message
+    /// Generate a short title for the conversation, from the first question and answer.
+    /// The title is `None` if the assistant hasn't answered yet, or the model didn't come up
+    /// with a usable one. The tokens used for the request are returned either way.
+    pub async fn generate_title(&self) -> Result<(Option<String>, Option<MessageUsage>)> {
+        let (model, transcript) = {
+            let context = self.context.lock().unwrap();
+            let question = context.messages.iter().find(|m| m.role == Role::User);
+            let answer = context.messages.iter().find(|m| {
+                m.role == Role::Assistant
+                    && m.tool_calls.is_none()
+                    && m.message.as_deref().is_some_and(|t| !t.is_empty())
+            });
+            let (Some(question), Some(answer)) = (question, answer) else {
+                return Ok((None, None));
+            };
+            (
+                context.model.clone(),
+                context_window::render_transcript(&[question.clone(), answer.clone()]),
+            )
+        };
+
+        let request = LlmRequest {
+            model: model.clone(),
+            messages: vec![
+                text_message(Role::System, TITLE_PROMPT.to_string()),
+                text_message(Role::User, transcript),
+            ],
+            tools: vec![],
+            max_completion_tokens: 512,
+            temperature: None,
+            parallel_tool_calls: None,
+        };
+        let response = self.provider.complete(request).await?;
+        let usage = response
+            .usage
+            .map(|usage| MessageUsage::new(&model, &usage));
+        let title = response.message.message.as_deref().and_then(clean_title);
+        Ok((title, usage))
+    }
+
+    /// Executes a tool call and returns the response message. The caller turns errors into a
+    /// tool response, so the model can see what went wrong and every tool call still gets one.
+    /// The call runs on its own connection, which is cancelled if the call times out or the
+    /// turn is cancelled, without affecting the other calls in the batch.
     async fn execute_tool_call(
-        &mut self,
+        &self,
         tool_call: ChatCompletionMessageToolCall,
+        tool_profile: ToolProfile,
+        timeout: Option<Duration>,
     ) -> Result<ChatterMessage> {
+        let pg_client = self
+            .unless_cancelled(async { Ok(self.pg_pool.get().await?) })
+            .await?;
+        let pg_client = Arc::new(pg_client);
+        let resources = SharedResources {
+            chatter_context: self.context.clone(),
+            pg: pg_client.clone(),
+            pg_pool: self.pg_pool.clone(),
+            ddb: self.ddb_client.clone(),
+        };
         let call = tool_call.function;
-        let id = tool_call.id;
-        let args = serde_json::from_str(&call.arguments)?;
-        self.function_registry
-            .execute(&self.resources, &call.name, id, args)
-            .await
+        let result = self
+            .unless_cancelled(self.config.function_registry.execute(
+                &resources,
+                tool_profile,
+                &call.name,
+                tool_call.id,
+                &call.arguments,
+                timeout,
+            ))
+            .await;
+        if let Err(ChatterError::ToolTimeout { .. } | ChatterError::Cancelled) = result {
+            // Dropping the future doesn't stop the query on the server
+            Self::cancel_queries(&pg_client).await;
+        }
+        result
     }
 
     /// Execute a SQL query and return the result. Used by the API to execute queries.
@@ -183,7 +644,8 @@ impl Chatter {
     /// the execution, rendering this function obsolete. This is used in the meantime.
     pub async fn execute_raw_query(&mut self, query: &str) -> Result<Vec<QueryResultRow>> {
         // Execute the provided query directly.
-        let rows = self.pg_client.query(query, &[]).await?;
+        let pg_client = self.pg_pool.get().await?;
+        let rows = pg_client.query(query, &[]).await?;
         let mut results = Vec::with_capacity(rows.len());
 
         for row in rows {
@@ -229,7 +691,8 @@ impl Chatter {
             .await
             .map_err(|e| ChatterError::QueryError(e.to_string()))?;
         let query_str = query_obj.query_content;
-        let stmt = self.pg_client.prepare(&query_str).await?;
+        let pg_client = self.pg_pool.get().await?;
+        let stmt = pg_client.prepare(&query_str).await?;
         let columns = stmt.columns();
         // get the first column from the query -- that will be our ID column
         let id_column = columns
@@ -317,7 +780,7 @@ impl Chatter {
             "#,
         );
 
-        let result = self.pg_client.query_one(&query, &[&z, &x, &y]).await?;
+        let result = pg_client.query_one(&query, &[&z, &x, &y]).await?;
         let mvt_tile: Option<Vec<u8>> = result.get(0);
 
         mvt_tile.ok_or_else(|| {
@@ -346,7 +809,8 @@ impl Chatter {
                 ) AS agg;
             "#,
         );
-        let result = self.pg_client.query_one(&extent_query, &[]).await?;
+        let pg_client = self.pg_pool.get().await?;
+        let result = pg_client.query_one(&extent_query, &[]).await?;
         let minx: f64 = result.get(0);
         let miny: f64 = result.get(1);
         let maxx: f64 = result.get(2);
@@ -356,29 +820,189 @@ impl Chatter {
     }
 }
 
+fn text_message(role: Role, text: String) -> ChatterMessage {
+    ChatterMessage {
+        message: Some(text),
+        role,
+        tool_calls: None,
+        tool_call_id: None,
+        sidecar: ChatterMessageSidecar::None,
+    }
+}
+
+/// Clean up a title generated by the LLM: use the first line, strip quotes and markdown,
+/// and cut it off at `MAX_TITLE_CHARS`.
+fn clean_title(raw: &str) -> Option<String> {
+    let line = raw.lines().map(str::trim).find(|l| !l.is_empty())?;
+    let line = line
+        .trim_start_matches(['#', '*'])
+        .trim_end_matches('*')
+        .trim()
+        .trim_matches(['"', '\'', '「', '」', '『', '』'])
+        .trim();
+    if line.is_empty() {
+        return None;
+    }
+    let mut title: String = line.chars().take(MAX_TITLE_CHARS).collect();
+    if title.len() < line.len() {
+        title.push('…');
+    }
+    Some(title)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chatter_message::{ChatterMessageSidecar, Role};
+    use crate::data::types::data_request::DataRequest;
+    use crate::functions::{
+        FunctionRegistry, LlmFunction, LlmFunctionExecutor, ToolAccess, ToolOutput,
+    };
+    use crate::llm::ReplayProvider;
     use deadpool_postgres::{Config, ManagerConfig, PoolConfig, RecyclingMethod, Runtime};
+    use futures::TryStreamExt;
     use geo_types::Point;
     use serde_json::Value;
     use std::env;
     use tokio_postgres::NoTls;
 
-    async fn setup() -> Result<Chatter> {
+    async fn pg_pool() -> Result<deadpool_postgres::Pool> {
         let mut cfg = Config::new();
         let config = env::var("POSTGRES_CONN_STR")?;
         cfg.url = Some(config);
         cfg.pool = Some(PoolConfig {
-            max_size: 1,
+            max_size: 4,
             ..Default::default()
         });
         cfg.manager = Some(ManagerConfig {
             recycling_method: RecyclingMethod::Fast,
         });
-        let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls)?;
-        let pg_client = pool.get().await?;
-        Chatter::new(pg_client).await
+        Ok(cfg.create_pool(Some(Runtime::Tokio1), NoTls)?)
+    }
+
+    fn config() -> Arc<ChatterConfig> {
+        Arc::new(ChatterConfig::from_env().expect("Invalid chatter configuration"))
+    }
+
+    async fn setup() -> Result<Chatter> {
+        Chatter::new(pg_pool().await?, config()).await
+    }
+
+    /// Run the chatter and collect the complete messages it emits.
+    async fn collect_messages(chatter: Chatter) -> Result<Vec<ChatterMessage>> {
+        chatter
+            .execute_stream()
+            .try_filter_map(|event| async move { Ok::<_, ChatterError>(event.into_message()) })
+            .try_collect()
+            .await
+    }
+
+    /// Set up a Chatter that plays back the given fixture from `fixtures/` instead of calling an LLM.
+    async fn setup_replay(fixture: &str) -> Result<(Chatter, Arc<ReplayProvider>)> {
+        setup_replay_with_config(fixture, config()).await
+    }
+
+    async fn setup_replay_with_config(
+        fixture: &str,
+        config: Arc<ChatterConfig>,
+    ) -> Result<(Chatter, Arc<ReplayProvider>)> {
+        let path = format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), fixture);
+        let provider = Arc::new(ReplayProvider::from_file(path)?);
+        let mut chatter =
+            Chatter::new_with_provider(pg_pool().await?, config, provider.clone()).await?;
+        chatter.new_context().await?;
+        Ok((chatter, provider))
+    }
+
+    /// Sleeps on the call's connection, for tests of concurrent and timed out calls.
+    struct PgSleepFunction {
+        name: &'static str,
+        timeout: Duration,
+    }
+
+    impl LlmFunction for PgSleepFunction {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn description(&self) -> &str {
+            "Sleep on the database server."
+        }
+
+        fn parameters_schema(&self) -> Value {
+            serde_json::json!({
+                "type": "object",
+                "properties": { "seconds": { "type": "number" } },
+                "required": ["seconds"],
+                "additionalProperties": false
+            })
+        }
+
+        fn concurrency_safe(&self) -> bool {
+            true
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn access(&self) -> ToolAccess {
+            ToolAccess::Read
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl LlmFunctionExecutor for PgSleepFunction {
+        async fn execute(
+            &self,
+            resources: &SharedResources,
+            tool_call_id: String,
+            params: Value,
+        ) -> Result<ChatterMessage> {
+            let seconds = params["seconds"].as_f64().unwrap_or_default();
+            resources
+                .pg
+                .execute("SELECT pg_sleep($1)", &[&seconds])
+                .await?;
+            Ok(ToolOutput::new("done").into_message(tool_call_id))
+        }
+    }
+
+    /// A configuration with only `pg_sleep`, and `pg_sleep_stalled`, which times out after a second.
+    fn pg_sleep_config() -> Arc<ChatterConfig> {
+        let mut registry = FunctionRegistry::new();
+        registry.register(PgSleepFunction {
+            name: "pg_sleep",
+            timeout: Duration::from_secs(30),
+        });
+        registry.register(PgSleepFunction {
+            name: "pg_sleep_stalled",
+            timeout: Duration::from_secs(1),
+        });
+        Arc::new(ChatterConfig {
+            function_registry: Arc::new(registry),
+            context_policy: Default::default(),
+            turn_limits: Default::default(),
+            retry_policy: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_clean_title() {
+        assert_eq!(
+            clean_title("「東京都の人口分布」\n").as_deref(),
+            Some("東京都の人口分布")
+        );
+        assert_eq!(
+            clean_title("\n## \"Bus stops in Osaka\"").as_deref(),
+            Some("Bus stops in Osaka")
+        );
+        assert_eq!(clean_title("  \n \"\""), None);
+        let long = "あ".repeat(100);
+        assert_eq!(
+            clean_title(&long).unwrap().chars().count(),
+            MAX_TITLE_CHARS + 1
+        );
     }
 
     #[tokio::test]
@@ -409,4 +1033,272 @@ mod tests {
         assert_eq!(row.geom, Point::new(35.0, 135.0).into());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_execute_stream_request_unavailable_data() -> Result<()> {
+        let (mut chatter, provider) = setup_replay("request_unavailable_data.json").await?;
+        chatter.add_user_message("How many people use each bus stop?")?;
+        let thread_id = chatter.context.lock().unwrap().id.clone();
+        let ddb = chatter.ddb_client.clone();
+
+        let messages = collect_messages(chatter).await?;
+        let roles: Vec<Role> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(
+            roles,
+            vec![Role::User, Role::Assistant, Role::Tool, Role::Assistant]
+        );
+        assert_eq!(
+            messages[2].tool_call_id.as_deref(),
+            Some("call_request_data")
+        );
+        assert!(messages[2].sidecar.is_none());
+        assert_eq!(
+            messages[3].message.as_deref(),
+            Some("I've requested the bus stop ridership data. It isn't available yet.")
+        );
+
+        // The second request must end with the tool response.
+        assert_eq!(provider.remaining(), 0);
+        let requests = provider.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].messages.last().unwrap().role, Role::Tool);
+
+        let data_requests: Vec<DataRequest> = DataRequest::get_all_requests(&ddb)
+            .await
+            .expect("Failed to get data requests")
+            .into_iter()
+            .filter(|r| r.thread_id() == thread_id)
+            .collect();
+        assert_eq!(data_requests.len(), 1);
+        assert_eq!(data_requests[0].name, "Bus stop ridership");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_tool_call_limit() -> Result<()> {
+        let (mut chatter, provider) = setup_replay("request_unavailable_data.json").await?;
+        chatter.context.lock().unwrap().turn_limits.max_tool_calls = 0;
+        chatter.add_user_message("How many people use each bus stop?")?;
+
+        let messages = collect_messages(chatter).await?;
+        let roles: Vec<Role> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(
+            roles,
+            vec![Role::User, Role::Assistant, Role::Tool, Role::Assistant]
+        );
+        // The tool call is answered, but not executed.
+        assert_eq!(
+            messages[2].tool_call_id.as_deref(),
+            Some("call_request_data")
+        );
+        assert!(
+            messages[2]
+                .message
+                .as_deref()
+                .unwrap()
+                .contains("not executed")
+        );
+        let ChatterMessageSidecar::TurnLimitReached(details) = &messages[3].sidecar else {
+            panic!(
+                "Expected a TurnLimitReached sidecar, got {:?}",
+                messages[3].sidecar
+            );
+        };
+        assert_eq!(details.limit, crate::turn_limits::TurnLimit::ToolCalls);
+        assert_eq!(details.round_trips, 1);
+
+        // Only one request was sent to the model.
+        assert_eq!(provider.requests().len(), 1);
+        Ok(())
+    }
+
+    /// A failing tool call is answered with an error, and a failing LLM request ends the
+    /// turn with an error message, so the thread can still be continued.
+    #[tokio::test]
+    async fn test_execute_stream_failure() -> Result<()> {
+        let (mut chatter, provider) = setup_replay("tool_call_error.json").await?;
+        chatter.add_user_message("Delete everything")?;
+
+        let messages = collect_messages(chatter).await?;
+        let roles: Vec<Role> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(
+            roles,
+            vec![Role::User, Role::Assistant, Role::Tool, Role::Assistant]
+        );
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("call_unknown"));
+        assert!(
+            messages[2]
+                .message
+                .as_deref()
+                .unwrap()
+                .contains("drop_all_tables")
+        );
+        assert!(matches!(
+            &messages[2].sidecar,
+            ChatterMessageSidecar::ToolError {
+                kind: crate::chatter_message::ToolErrorKind::InvalidCall,
+                ..
+            }
+        ));
+        // The second request fails because the transcript has no more responses
+        let ChatterMessageSidecar::TurnFailed(details) = &messages[3].sidecar else {
+            panic!(
+                "Expected a TurnFailed sidecar, got {:?}",
+                messages[3].sidecar
+            );
+        };
+        assert_eq!(details.error, ChatterError::ReplayExhausted.to_string());
+        assert_eq!(provider.requests().len(), 2);
+        Ok(())
+    }
+
+    /// A provider that never answers.
+    struct StalledProvider;
+
+    #[async_trait::async_trait]
+    impl LlmProvider for StalledProvider {
+        async fn complete(&self, _request: LlmRequest) -> Result<llm::LlmResponse> {
+            futures::future::pending().await
+        }
+    }
+
+    /// A request that is still running at the deadline ends the turn.
+    #[tokio::test]
+    async fn test_execute_stream_deadline() -> Result<()> {
+        let mut chatter =
+            Chatter::new_with_provider(pg_pool().await?, config(), Arc::new(StalledProvider))
+                .await?;
+        chatter.new_context().await?;
+        chatter
+            .context
+            .lock()
+            .unwrap()
+            .turn_limits
+            .max_duration_secs = 1;
+        chatter.add_user_message("How many people use each bus stop?")?;
+
+        let started = std::time::Instant::now();
+        let messages = collect_messages(chatter).await?;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let ChatterMessageSidecar::TurnLimitReached(details) = &messages[1].sidecar else {
+            panic!(
+                "Expected a TurnLimitReached sidecar, got {:?}",
+                messages[1].sidecar
+            );
+        };
+        assert_eq!(details.limit, TurnLimit::Deadline);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_cancelled() -> Result<()> {
+        let (mut chatter, provider) = setup_replay("request_unavailable_data.json").await?;
+        chatter.add_user_message("How many people use each bus stop?")?;
+        chatter.cancel_token().cancel();
+
+        let messages = collect_messages(chatter).await?;
+        let roles: Vec<Role> = messages.iter().map(|m| m.role).collect();
+        assert_eq!(roles, vec![Role::User, Role::Assistant]);
+        assert!(matches!(
+            messages[1].sidecar,
+            ChatterMessageSidecar::TurnCancelled
+        ));
+        assert!(provider.requests().is_empty());
+        Ok(())
+    }
+
+    /// Concurrent calls run on their own connections, so their queries overlap.
+    #[tokio::test]
+    async fn test_execute_stream_concurrent_queries_overlap() -> Result<()> {
+        let (mut chatter, provider) =
+            setup_replay_with_config("pg_sleep_overlap.json", pg_sleep_config()).await?;
+        chatter.add_user_message("Run two slow queries")?;
+
+        let started = std::time::Instant::now();
+        let messages = collect_messages(chatter).await?;
+        // Each call sleeps for 1.5 seconds
+        assert!(started.elapsed() < Duration::from_secs(3));
+        let responses: Vec<Option<&str>> = messages
+            .iter()
+            .filter(|m| m.role == Role::Tool)
+            .map(|m| m.message.as_deref())
+            .collect();
+        assert_eq!(responses, vec![Some("done"), Some("done")]);
+        assert_eq!(provider.remaining(), 0);
+        Ok(())
+    }
+
+    /// A call that times out only cancels its own query, not the others in the batch.
+    #[tokio::test]
+    async fn test_execute_stream_timeout_keeps_siblings() -> Result<()> {
+        let (mut chatter, provider) =
+            setup_replay_with_config("pg_sleep_timeout.json", pg_sleep_config()).await?;
+        chatter.add_user_message("Run a query that stalls, and a slow one")?;
+
+        let messages = collect_messages(chatter).await?;
+        assert!(matches!(
+            messages[2].sidecar,
+            ChatterMessageSidecar::ToolError {
+                kind: crate::chatter_message::ToolErrorKind::Timeout,
+                ..
+            }
+        ));
+        // Still running when the first call timed out
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("call_slow"));
+        assert_eq!(messages[3].message.as_deref(), Some("done"));
+        assert_eq!(provider.remaining(), 0);
+        Ok(())
+    }
+
+    /// Tool calls that run concurrently are still answered in the order they were made.
+    #[tokio::test]
+    async fn test_execute_stream_parallel_tool_calls() -> Result<()> {
+        let (mut chatter, provider) = setup_replay("parallel_tool_calls.json").await?;
+        chatter.add_user_message("Show me Tokyo and Osaka, and bus stop ridership")?;
+
+        let messages = collect_messages(chatter).await?;
+        let tool_call_ids: Vec<&str> = messages
+            .iter()
+            .filter_map(|m| m.tool_call_id.as_deref())
+            .collect();
+        assert_eq!(
+            tool_call_ids,
+            vec!["call_slow", "call_fast", "call_request_data"]
+        );
+        assert!(matches!(
+            messages[2].sidecar,
+            ChatterMessageSidecar::SQLExecution(_)
+        ));
+        assert_eq!(messages.last().unwrap().role, Role::Assistant);
+        assert_eq!(provider.remaining(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_query_database() -> Result<()> {
+        let (mut chatter, provider) = setup_replay("query_database.json").await?;
+        chatter.add_user_message("Show me a point in Tokyo")?;
+        let thread_id = chatter.context.lock().unwrap().id.clone();
+        let ddb = chatter.ddb_client.clone();
+
+        let messages = collect_messages(chatter).await?;
+        assert_eq!(messages.len(), 4);
+        assert_eq!(provider.remaining(), 0);
+
+        let ChatterMessageSidecar::SQLExecution(details) = &messages[2].sidecar else {
+            panic!(
+                "Expected a SQLExecution sidecar, got {:?}",
+                messages[2].sidecar
+            );
+        };
+        assert_eq!(details.name, "Test point");
+
+        let queries = SqlQuery::get_thread_queries(&ddb, &thread_id)
+            .await
+            .expect("Failed to get thread queries");
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].id(), details.id);
+        assert_eq!(queries[0].query_name, "Test point");
+        Ok(())
+    }
 }
@@ -1,6 +1,11 @@
 use crate::chatter_message::ChatterMessageSidecar;
+use crate::context_window::{ContextPolicy, ContextSummary};
+use crate::functions::ToolProfile;
+use crate::llm::{GenerationSettings, RetryPolicy};
+use crate::turn_limits::TurnLimits;
 use async_openai::types::{ChatCompletionTool, Role};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use ulid::Ulid;
 
 use crate::chatter_message::ChatterMessage;
@@ -16,7 +21,30 @@ pub struct ChatterContext {
     pub id: String,
     pub messages: Vec<ChatterMessage>,
     pub model: String,
+    #[serde(default)]
+    pub generation: GenerationSettings,
     pub tools: Vec<ChatCompletionTool>,
+    /// Controls how the conversation is pruned before it is sent to the LLM.
+    #[serde(default)]
+    pub context_policy: ContextPolicy,
+    /// A summary of the earliest messages, created when the conversation got too long.
+    #[serde(default)]
+    pub summary: Option<ContextSummary>,
+    /// Limits on the work done for each user message.
+    #[serde(default)]
+    pub turn_limits: TurnLimits,
+    /// How failed LLM requests are retried.
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// The tools this conversation may use. Set from the thread.
+    #[serde(default)]
+    pub tool_profile: ToolProfile,
+}
+
+impl Default for ChatterContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChatterContext {
@@ -33,9 +61,14 @@ impl ChatterContext {
         Self {
             id,
             messages,
-            // model: "gpt-4o".to_string(),
-            model: "gpt-4.1".to_string(),
+            model: crate::llm::default_model(),
+            generation: GenerationSettings::default(),
             tools: vec![], // Tools will be set by the Chatter
+            context_policy: ContextPolicy::default(),
+            summary: None,
+            turn_limits: TurnLimits::default(),
+            retry_policy: RetryPolicy::default(),
+            tool_profile: ToolProfile::default(),
         }
     }
 
@@ -52,4 +85,147 @@ impl ChatterContext {
             sidecar: ChatterMessageSidecar::None,
         });
     }
+
+    /// Make sure every tool call is followed by exactly one response, so the conversation
+    /// is accepted by the model. A turn that was interrupted (for example, the server was
+    /// restarted while a tool was running) leaves tool calls without responses, which would
+    /// make every following request to the thread fail.
+    ///
+    /// Missing responses are added as tool errors, and responses that don't belong to the
+    /// tool call before them are dropped. Only the context is changed; use
+    /// `ChatMessage::repair_thread` to store the repair. Returns the number of messages that were added or dropped.
+    pub fn repair_tool_calls(&mut self) -> usize {
+        let mut changed = 0;
+        let mut repaired = Vec::with_capacity(self.messages.len());
+        let mut messages = std::mem::take(&mut self.messages).into_iter().peekable();
+        while let Some(message) = messages.next() {
+            if message.role == Role::Tool {
+                // Not preceded by a tool call
+                changed += 1;
+                continue;
+            }
+            let calls: Vec<String> = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| call.id.clone())
+                .collect();
+            repaired.push(message);
+
+            let mut answered = HashSet::new();
+            while let Some(response) = messages.next_if(|m| m.role == Role::Tool) {
+                let belongs = response
+                    .tool_call_id
+                    .as_ref()
+                    .is_some_and(|id| calls.contains(id) && answered.insert(id.clone()));
+                if belongs {
+                    repaired.push(response);
+                } else {
+                    changed += 1;
+                }
+            }
+            for id in calls.iter().filter(|id| !answered.contains(*id)) {
+                repaired.push(ChatterMessage::tool_error(
+                    id,
+                    "The turn ended before this tool call was answered.",
+                ));
+                changed += 1;
+            }
+        }
+        self.messages = repaired;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionMessageToolCall, ChatCompletionToolType, FunctionCall,
+    };
+
+    fn message(role: Role, text: &str) -> ChatterMessage {
+        ChatterMessage {
+            message: Some(text.to_string()),
+            role,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    fn tool_calls(ids: &[&str]) -> ChatterMessage {
+        ChatterMessage {
+            message: None,
+            role: Role::Assistant,
+            tool_calls: Some(
+                ids.iter()
+                    .map(|id| ChatCompletionMessageToolCall {
+                        id: id.to_string(),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: "query_database".to_string(),
+                            arguments: "{}".to_string(),
+                        },
+                    })
+                    .collect(),
+            ),
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    fn tool_response(id: &str) -> ChatterMessage {
+        ChatterMessage {
+            tool_call_id: Some(id.to_string()),
+            ..message(Role::Tool, "{}")
+        }
+    }
+
+    #[test]
+    fn test_repair_tool_calls() {
+        let mut context = ChatterContext::new_with_stored(
+            "thread".to_string(),
+            vec![
+                message(Role::User, "question"),
+                // The second call was never answered
+                tool_calls(&["call_1", "call_2"]),
+                tool_response("call_1"),
+                message(Role::User, "another question"),
+                // A response without a tool call
+                tool_response("call_3"),
+                tool_calls(&["call_4"]),
+            ],
+        );
+        assert_eq!(context.repair_tool_calls(), 3);
+
+        let summary: Vec<(Role, Option<&str>)> = context
+            .messages
+            .iter()
+            .map(|m| (m.role, m.tool_call_id.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Role::User, None),
+                (Role::Assistant, None),
+                (Role::Tool, Some("call_1")),
+                (Role::Tool, Some("call_2")),
+                (Role::User, None),
+                (Role::Assistant, None),
+                (Role::Tool, Some("call_4")),
+            ]
+        );
+        assert!(
+            context.messages[3]
+                .message
+                .as_deref()
+                .unwrap()
+                .contains("error")
+        );
+
+        // A valid conversation is left as it is
+        assert_eq!(context.repair_tool_calls(), 0);
+        assert_eq!(context.messages.len(), 7);
+    }
 }
@@ -1,10 +1,8 @@
+use crate::context_window::ContextSummary;
 use crate::error::{ChatterError, Result};
-use async_openai::types::{
-    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
-    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
-    ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
-    ChatCompletionResponseMessage, Role as OpenAIRole,
-};
+use crate::llm::LlmUsage;
+use crate::turn_limits::TurnLimitDetails;
+use async_openai::types::{ChatCompletionMessageToolCall, Role as OpenAIRole};
 use serde::{Deserialize, Serialize};
 
 pub type Role = OpenAIRole;
@@ -16,6 +14,53 @@ pub struct SQLExecutionDetails {
     pub sql: String,
 }
 
+/// The turn ended early because of an error.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TurnFailureDetails {
+    pub error: String,
+}
+
+/// A failed LLM request that is being retried.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LlmRetryDetails {
+    pub model: String,
+    /// The retry number, starting at 1.
+    pub attempt: u32,
+    pub max_retries: u32,
+    pub delay_ms: u64,
+    pub error: String,
+}
+
+/// The retries ran out, and the request is being sent to the fallback model instead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LlmFallbackDetails {
+    pub from_model: String,
+    pub to_model: String,
+    pub error: String,
+}
+
+/// Why a tool call failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolErrorKind {
+    /// The call didn't finish within the tool's timeout.
+    Timeout,
+    /// An unknown function, or arguments that don't match its schema.
+    InvalidCall,
+    /// The tool returned an error.
+    Failed,
+}
+
+impl From<&ChatterError> for ToolErrorKind {
+    fn from(error: &ChatterError) -> Self {
+        match error {
+            ChatterError::ToolTimeout { .. } => Self::Timeout,
+            ChatterError::InvalidToolCall(_) => Self::InvalidCall,
+            _ => Self::Failed,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub enum ChatterMessageSidecar {
     #[default]
@@ -28,6 +73,26 @@ pub enum ChatterMessageSidecar {
 
     /// A database lookup.
     DatabaseLookup,
+
+    /// A tool call that failed. The error was sent to the model.
+    ToolError {
+        tool: String,
+        kind: ToolErrorKind,
+        detail: String,
+    },
+
+    /// The turn was stopped because it reached one of its limits.
+    TurnLimitReached(TurnLimitDetails),
+
+    /// The turn was stopped because of an error.
+    TurnFailed(TurnFailureDetails),
+    /// The turn was cancelled, usually because the user left.
+    TurnCancelled,
+
+    /// The LLM request failed and will be retried.
+    LlmRetry(LlmRetryDetails),
+    /// The LLM request is being retried with the fallback model.
+    LlmFallback(LlmFallbackDetails),
 }
 
 impl ChatterMessageSidecar {
@@ -51,9 +116,131 @@ pub struct ChatterMessage {
     pub sidecar: ChatterMessageSidecar,
 }
 
+/// The tokens used to generate an assistant message, and what they cost.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageUsage {
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub cached_prompt_tokens: u32,
+    /// Input written to the prompt cache, which some providers charge extra for.
+    #[serde(default)]
+    pub cache_write_prompt_tokens: u32,
+    /// The cost in millionths of a US dollar. `None` if the model's price is unknown.
+    pub cost_micros: Option<u64>,
+}
+
+impl MessageUsage {
+    pub fn new(model: &str, usage: &LlmUsage) -> Self {
+        Self {
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cached_prompt_tokens: usage.cached_prompt_tokens,
+            cache_write_prompt_tokens: usage.cache_write_prompt_tokens,
+            cost_micros: crate::llm::cost_micros(model, usage),
+        }
+    }
+
+    /// Add the usage of another request made for the same message (for example, a summary).
+    pub fn add(&mut self, other: &MessageUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cached_prompt_tokens += other.cached_prompt_tokens;
+        self.cache_write_prompt_tokens += other.cache_write_prompt_tokens;
+        self.cost_micros = match (self.cost_micros, other.cost_micros) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+/// An event emitted by `Chatter::execute_stream`.
+#[derive(Clone, Debug)]
+pub enum ChatterEvent {
+    /// A complete message. It has been added to the context and should be persisted.
+    Message(ChatterMessage),
+    /// A fragment of the assistant's reply, as it is being generated.
+    /// The assembled text will follow in a `Message`.
+    TextDelta(String),
+    /// The assistant started to call a tool.
+    ToolCallStarted { tool_call_id: String, name: String },
+    /// The tokens used for the assistant message that follows. Only sent if the provider
+    /// reported usage.
+    Usage(MessageUsage),
+    /// A status update that isn't part of the conversation, such as a retry.
+    /// It is not added to the context and shouldn't be persisted.
+    Sidecar(ChatterMessageSidecar),
+    /// The earliest messages were summarized to fit the context window. The summary should
+    /// be saved with the thread, so they don't have to be summarized again on every turn.
+    Summary(ContextSummary),
+}
+
+impl ChatterEvent {
+    /// Returns the message, if this event is a complete message.
+    pub fn into_message(self) -> Option<ChatterMessage> {
+        match self {
+            Self::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
 impl ChatterMessage {
+    /// The response to a tool call that failed or could not be executed. The error is
+    /// sent to the model so it can try something else.
+    pub fn tool_error(tool_call_id: &str, error: &str) -> Self {
+        Self {
+            message: Some(serde_json::json!({ "error": error }).to_string()),
+            role: Role::Tool,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id.to_string()),
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    /// The response to a tool call that returned an error, with a sidecar so the user can
+    /// see which tool failed and why.
+    pub fn tool_failed(tool_call_id: &str, tool: &str, error: &ChatterError) -> Self {
+        let detail = error.to_string();
+        Self {
+            sidecar: ChatterMessageSidecar::ToolError {
+                tool: tool.to_string(),
+                kind: error.into(),
+                detail: detail.clone(),
+            },
+            ..Self::tool_error(tool_call_id, &detail)
+        }
+    }
+
+    /// The assistant message that ends a turn that was cancelled.
+    pub fn turn_cancelled() -> Self {
+        Self {
+            message: Some("回答を中断しました。".to_string()),
+            role: Role::Assistant,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::TurnCancelled,
+        }
+    }
+
+    /// The assistant message that ends a turn that failed.
+    pub fn turn_failed(error: &str) -> Self {
+        Self {
+            message: Some(
+                "エラーが発生したため、回答を完了できませんでした。もう一度お試しください。"
+                    .to_string(),
+            ),
+            role: Role::Assistant,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::TurnFailed(TurnFailureDetails {
+                error: error.to_string(),
+            }),
+        }
+    }
+
     pub async fn create_system_message(client: &tokio_postgres::Client) -> Result<ChatterMessage> {
-        let mut tables: String = String::new();
         let rows = client
             .query(
                 r#"
@@ -65,11 +252,15 @@ impl ChatterMessage {
                 &[],
             )
             .await?;
-        for row in rows {
-            let table_name: String = row.get(0);
-            let name: String = row.get(1);
-            tables.push_str(&format!("- `{}`: {}\n", table_name, name));
-        }
+        let tables: Vec<(String, String)> =
+            rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        let tables = match SystemPromptTables::from_env() {
+            SystemPromptTables::All => tables
+                .iter()
+                .map(|(table_name, name)| format!("- `{}`: {}\n", table_name, name))
+                .collect(),
+            SystemPromptTables::Categories => format_table_categories(&tables),
+        };
 
         Ok(ChatterMessage {
             message: Some(
@@ -87,73 +278,108 @@ impl ChatterMessage {
     }
 }
 
-impl TryFrom<ChatCompletionResponseMessage> for ChatterMessage {
-    type Error = ChatterError;
+/// How the tables are listed in the system prompt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemPromptTables {
+    /// Every table in `datasets`.
+    All,
+    /// Only the largest categories, with a few tables each. The model finds the rest with
+    /// `search_datasets`.
+    Categories,
+}
 
-    fn try_from(message: ChatCompletionResponseMessage) -> Result<Self> {
-        Ok(Self {
-            message: message.content,
-            role: message.role,
-            tool_calls: message.tool_calls,
-            tool_call_id: None,
-            sidecar: ChatterMessageSidecar::None,
-        })
+impl SystemPromptTables {
+    /// From `SYSTEM_PROMPT_TABLES` (`all` or `categories`). Defaults to `all`.
+    pub fn from_env() -> Self {
+        match std::env::var("SYSTEM_PROMPT_TABLES").as_deref() {
+            Ok("categories") => Self::Categories,
+            Ok("all") | Err(_) => Self::All,
+            Ok(other) => {
+                tracing::warn!(
+                    "Unknown SYSTEM_PROMPT_TABLES: {}, listing all tables",
+                    other
+                );
+                Self::All
+            }
+        }
     }
 }
 
-impl TryFrom<ChatterMessage> for ChatCompletionRequestMessage {
-    type Error = ChatterError;
+const MAX_PROMPT_CATEGORIES: usize = 10;
+const EXAMPLES_PER_CATEGORY: usize = 5;
 
-    fn try_from(message: ChatterMessage) -> Result<Self> {
-        let out: ChatCompletionRequestMessage = match message.role {
-            Role::User => {
-                let mut msg = ChatCompletionRequestUserMessageArgs::default();
-                let msg = if let Some(message) = message.message {
-                    msg.content(message)
-                } else {
-                    &mut msg
-                };
-                msg.build()?.into()
-            }
-            Role::System => {
-                let mut msg = ChatCompletionRequestSystemMessageArgs::default();
-                let msg = if let Some(message) = message.message {
-                    msg.content(message)
-                } else {
-                    &mut msg
-                };
-                msg.build()?.into()
-            }
-            Role::Assistant => {
-                let mut msg = ChatCompletionRequestAssistantMessageArgs::default();
-                let mut msg = if let Some(message) = message.message {
-                    msg.content(message)
-                } else {
-                    &mut msg
-                };
-                let msg = if let Some(tool_calls) = message.tool_calls {
-                    msg.tool_calls(tool_calls)
-                } else {
-                    &mut msg
-                };
-                msg.build()?.into()
+/// Group tables by the letter of their KSJ code (`p29` is in `P`), and list the largest
+/// groups. Tables that aren't named by a KSJ code are listed on their own, because they are
+/// used everywhere (`admini_boundary_cd`).
+fn format_table_categories(tables: &[(String, String)]) -> String {
+    let mut categories: Vec<(char, Vec<&(String, String)>)> = vec![];
+    let mut other = vec![];
+    for table in tables {
+        let mut chars = table.0.chars();
+        match (chars.next(), chars.next()) {
+            (Some(letter), Some(digit))
+                if letter.is_ascii_alphabetic() && digit.is_ascii_digit() =>
+            {
+                let letter = letter.to_ascii_uppercase();
+                match categories.iter_mut().find(|(l, _)| *l == letter) {
+                    Some((_, tables)) => tables.push(table),
+                    None => categories.push((letter, vec![table])),
+                }
             }
-            Role::Tool => {
-                let mut msg = ChatCompletionRequestToolMessageArgs::default();
-                let mut msg = if let Some(message) = message.message {
-                    msg.content(message)
-                } else {
-                    &mut msg
-                };
-                let msg = if let Some(tool_call_id) = message.tool_call_id {
-                    msg.tool_call_id(tool_call_id)
-                } else {
-                    &mut msg
-                };
-                msg.build()?.into()
-            }
-            role => Err(ChatterError::UnknownRole(role.to_string()))?,
-        };
-        Ok(out)
+            _ => other.push(table),
+        }
+    }
+    categories.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+
+    let mut out = "Only the main categories of tables are listed here. Use `search_datasets` to find the tables you need.\n\n".to_string();
+    for (table_name, name) in other {
+        out.push_str(&format!("- `{}`: {}\n", table_name, name));
+    }
+    for (letter, tables) in categories.iter().take(MAX_PROMPT_CATEGORIES) {
+        let mut examples: Vec<&str> = tables
+            .iter()
+            .take(EXAMPLES_PER_CATEGORY)
+            .map(|(_, name)| name.as_str())
+            .collect();
+        if tables.len() > EXAMPLES_PER_CATEGORY {
+            examples.push("...");
+        }
+        out.push_str(&format!(
+            "- KSJ `{}` ({} tables): {}\n",
+            letter,
+            tables.len(),
+            examples.join(", ")
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_table_categories() {
+        let tables: Vec<(String, String)> = [
+            ("admini_boundary_cd", "行政区域コード"),
+            ("n02_station", "鉄道"),
+            ("p29", "学校"),
+            ("p04", "医療機関"),
+            ("n03_union", "行政区域"),
+            ("p05", "市町村役場等及び公的集会施設"),
+        ]
+        .iter()
+        .map(|(t, n)| (t.to_string(), n.to_string()))
+        .collect();
+        let out = format_table_categories(&tables);
+        let lines: Vec<&str> = out.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "- `admini_boundary_cd`: 行政区域コード",
+                "- KSJ `P` (3 tables): 学校, 医療機関, 市町村役場等及び公的集会施設",
+                "- KSJ `N` (2 tables): 鉄道, 行政区域",
+            ]
+        );
     }
 }
@@ -0,0 +1,46 @@
+//! Configuration that is read once, when the server starts, and shared by every `Chatter`.
+//! Invalid configuration is reported at startup rather than on the first request.
+
+use crate::context_window::ContextPolicy;
+use crate::error::{ChatterError, Result};
+use crate::functions::FunctionRegistry;
+use crate::llm::RetryPolicy;
+use crate::turn_limits::TurnLimits;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::sync::Arc;
+
+pub struct ChatterConfig {
+    /// The functions the model can call. Loading the HTTP tools reads a file and builds an
+    /// HTTP client for each, so the registry is only built once.
+    pub function_registry: Arc<FunctionRegistry>,
+    /// How the conversation is pruned when it gets too long. From `CHATTER_CONTEXT_POLICY`.
+    pub context_policy: ContextPolicy,
+    /// How much work a turn may do. From `CHATTER_TURN_LIMITS`.
+    pub turn_limits: TurnLimits,
+    /// How failed LLM requests are retried. From `CHATTER_RETRY_POLICY`.
+    pub retry_policy: RetryPolicy,
+}
+
+impl ChatterConfig {
+    /// Read the configuration from the environment. See `FunctionRegistry::from_env` for
+    /// the tools. The policies are JSON objects, and fields that are left out keep their
+    /// defaults, e.g. `CHATTER_CONTEXT_POLICY='{"max_prompt_tokens":50000}'`.
+    pub fn from_env() -> Result<Self> {
+        Ok(Self {
+            function_registry: Arc::new(FunctionRegistry::from_env()?),
+            context_policy: json_from_env("CHATTER_CONTEXT_POLICY")?,
+            turn_limits: json_from_env("CHATTER_TURN_LIMITS")?,
+            retry_policy: json_from_env("CHATTER_RETRY_POLICY")?,
+        })
+    }
+}
+
+/// Parse the JSON in the environment variable `name`, or use the default if it isn't set.
+fn json_from_env<T: DeserializeOwned + Default>(name: &str) -> Result<T> {
+    match env::var(name) {
+        Ok(json) => serde_json::from_str(&json)
+            .map_err(|e| ChatterError::ConfigError(format!("Invalid {}: {}", name, e))),
+        Err(_) => Ok(T::default()),
+    }
+}
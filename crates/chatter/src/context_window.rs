@@ -0,0 +1,441 @@
+//! Keeps the conversation sent to the LLM within the model's context window.
+//!
+//! Messages are handled in groups: an assistant message with tool calls is always kept
+//! together with its tool responses, so pruning never leaves a tool call unanswered.
+
+use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::ops::Range;
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+/// Tokens added to every message by the chat format.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Tool outputs are truncated to this many characters when rendering a transcript for summarization.
+const TRANSCRIPT_TOOL_OUTPUT_CHARS: usize = 500;
+
+fn tokenizer() -> &'static CoreBPE {
+    static TOKENIZER: OnceLock<CoreBPE> = OnceLock::new();
+    TOKENIZER.get_or_init(|| tiktoken_rs::o200k_base().expect("Failed to load tokenizer"))
+}
+
+/// Count the tokens in a piece of text. This uses the GPT-4o tokenizer, so counts for
+/// other models are an approximation.
+pub fn count_tokens(text: &str) -> usize {
+    tokenizer().encode_ordinary(text).len()
+}
+
+/// Count the tokens a message takes up in the prompt.
+pub fn count_message_tokens(message: &ChatterMessage) -> usize {
+    let mut tokens = MESSAGE_OVERHEAD_TOKENS;
+    if let Some(text) = &message.message {
+        tokens += count_tokens(text);
+    }
+    for call in message.tool_calls.iter().flatten() {
+        tokens += count_tokens(&call.function.name) + count_tokens(&call.function.arguments);
+    }
+    tokens
+}
+
+fn count_all_tokens(messages: &[ChatterMessage]) -> usize {
+    messages.iter().map(count_message_tokens).sum()
+}
+
+/// Settings that control how the conversation is pruned when it gets too long.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextPolicy {
+    /// The maximum number of tokens to send in the prompt.
+    pub max_prompt_tokens: usize,
+    /// Old tool outputs longer than this many tokens are elided first.
+    pub elide_tool_outputs_over: usize,
+    /// The number of most recent tool-calling assistant messages that are always kept
+    /// intact, along with their tool responses.
+    pub keep_recent_tool_calls: usize,
+    /// When eliding tool outputs isn't enough, summarize the earliest turns. If this is
+    /// `false`, the earliest turns are dropped instead.
+    pub summarize: bool,
+}
+
+impl Default for ContextPolicy {
+    fn default() -> Self {
+        Self {
+            max_prompt_tokens: 100_000,
+            elide_tool_outputs_over: 500,
+            keep_recent_tool_calls: 2,
+            summarize: true,
+        }
+    }
+}
+
+/// A summary of the earliest messages of the conversation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContextSummary {
+    /// The number of messages, following the system messages, that this summary replaces.
+    pub covered: usize,
+    pub text: String,
+}
+
+impl ContextSummary {
+    fn to_message(&self) -> ChatterMessage {
+        ChatterMessage {
+            message: Some(format!(
+                "Summary of the earlier part of this conversation:\n\n{}",
+                self.text
+            )),
+            role: Role::System,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+}
+
+/// The result of fitting the conversation into the policy's budget.
+#[derive(Debug)]
+pub enum FittedContext {
+    /// The messages to send to the LLM.
+    Ready(Vec<ChatterMessage>),
+    /// The first `covered` messages after the system messages have to be summarized
+    /// (including the previous summary, if any) before the conversation fits.
+    NeedsSummary { covered: usize },
+}
+
+/// The number of system messages at the start of the conversation.
+pub fn system_prefix_len(messages: &[ChatterMessage]) -> usize {
+    messages
+        .iter()
+        .take_while(|m| m.role == Role::System)
+        .count()
+}
+
+/// Split the messages into groups that must be kept or removed together.
+fn groups(messages: &[ChatterMessage]) -> Vec<Range<usize>> {
+    let mut groups: Vec<Range<usize>> = vec![];
+    for (i, message) in messages.iter().enumerate() {
+        match groups.last_mut() {
+            Some(last)
+                if message.role == Role::Tool
+                    && messages[last.start].tool_calls.is_some()
+                    && last.end == i =>
+            {
+                last.end = i + 1;
+            }
+            _ => groups.push(i..i + 1),
+        }
+    }
+    groups
+}
+
+/// Replace a tool output with a short note. The query ID is kept so the model can still
+/// refer to the layer.
+fn elide(message: &ChatterMessage) -> ChatterMessage {
+    let query_id = message
+        .message
+        .as_deref()
+        .and_then(|m| serde_json::from_str::<serde_json::Value>(m).ok())
+        .and_then(|v| v.get("query_id").cloned());
+    let mut note = json!({
+        "elided": true,
+        "note": "This tool output was removed to save space. Call the tool again if you need it.",
+    });
+    if let Some(query_id) = query_id {
+        note["query_id"] = query_id;
+    }
+    ChatterMessage {
+        message: Some(note.to_string()),
+        ..message.clone()
+    }
+}
+
+/// Fit the conversation into the budget set by `policy`.
+///
+/// The system messages, the latest user message and the most recent tool calls are always
+/// kept. Older tool outputs are elided first; if that isn't enough, the earliest groups are
+/// either summarized (`FittedContext::NeedsSummary`, only when `allow_summary` is set) or dropped.
+pub fn fit_messages(
+    messages: &[ChatterMessage],
+    policy: &ContextPolicy,
+    summary: Option<&ContextSummary>,
+    allow_summary: bool,
+) -> FittedContext {
+    let system_len = system_prefix_len(messages);
+    let (system, rest) = messages.split_at(system_len);
+    let (summary, rest) = match summary {
+        Some(summary) if summary.covered <= rest.len() => (Some(summary), &rest[summary.covered..]),
+        _ => (None, rest),
+    };
+    let covered = summary.map(|s| s.covered).unwrap_or(0);
+
+    let mut head: Vec<ChatterMessage> = system.to_vec();
+    if let Some(summary) = summary {
+        head.push(summary.to_message());
+    }
+    let mut rest: Vec<ChatterMessage> = rest.to_vec();
+
+    if count_all_tokens(&head) + count_all_tokens(&rest) <= policy.max_prompt_tokens {
+        return FittedContext::Ready([head, rest].concat());
+    }
+
+    // Everything from `protected` onwards is kept as-is: the latest user message, and the
+    // most recent tool calls.
+    let groups = groups(&rest);
+    let last_user = groups
+        .iter()
+        .rposition(|g| rest[g.start].role == Role::User)
+        .unwrap_or(groups.len().saturating_sub(1));
+    let recent_tool_calls = groups
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, g)| rest[g.start].tool_calls.is_some())
+        .take(policy.keep_recent_tool_calls)
+        .map(|(i, _)| i)
+        .last()
+        .unwrap_or(groups.len());
+    let protected_group = last_user.min(recent_tool_calls);
+    let protected = groups
+        .get(protected_group)
+        .map(|g| g.start)
+        .unwrap_or(rest.len());
+
+    // 1. Elide long tool outputs that aren't protected.
+    for message in rest[..protected].iter_mut() {
+        if message.role == Role::Tool
+            && count_message_tokens(message) > policy.elide_tool_outputs_over
+        {
+            *message = elide(message);
+        }
+    }
+    let head_tokens = count_all_tokens(&head);
+    let mut total = head_tokens + count_all_tokens(&rest);
+    if total <= policy.max_prompt_tokens {
+        return FittedContext::Ready([head, rest].concat());
+    }
+
+    // 2. Remove the earliest groups until the rest fits.
+    let mut remove_until = 0;
+    for group in &groups[..protected_group] {
+        if total <= policy.max_prompt_tokens {
+            break;
+        }
+        total -= count_all_tokens(&rest[group.clone()]);
+        remove_until = group.end;
+    }
+    if remove_until == 0 {
+        // Nothing can be removed without touching the protected messages.
+        return FittedContext::Ready([head, rest].concat());
+    }
+
+    if allow_summary && policy.summarize {
+        return FittedContext::NeedsSummary {
+            covered: covered + remove_until,
+        };
+    }
+    FittedContext::Ready([head, rest.split_off(remove_until)].concat())
+}
+
+/// Render messages as plain text, so they can be summarized.
+pub fn render_transcript(messages: &[ChatterMessage]) -> String {
+    let mut out = String::new();
+    for message in messages {
+        let role = match message.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+            Role::Tool => "Tool",
+            _ => "System",
+        };
+        if let Some(text) = message.message.as_deref().filter(|t| !t.is_empty()) {
+            let text = if message.role == Role::Tool {
+                let truncated: String = text.chars().take(TRANSCRIPT_TOOL_OUTPUT_CHARS).collect();
+                if truncated.len() < text.len() {
+                    format!("{}...", truncated)
+                } else {
+                    truncated
+                }
+            } else {
+                text.to_string()
+            };
+            out.push_str(&format!("{}: {}\n", role, text));
+        }
+        for call in message.tool_calls.iter().flatten() {
+            out.push_str(&format!(
+                "{} called `{}` with {}\n",
+                role, call.function.name, call.function.arguments
+            ));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionMessageToolCall, ChatCompletionToolType, FunctionCall,
+    };
+
+    fn message(role: Role, text: &str) -> ChatterMessage {
+        ChatterMessage {
+            message: Some(text.to_string()),
+            role,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    /// An assistant tool call followed by its (long) tool response.
+    fn tool_round(id: &str, output_words: usize) -> Vec<ChatterMessage> {
+        let mut call = message(Role::Assistant, "");
+        call.tool_calls = Some(vec![ChatCompletionMessageToolCall {
+            id: id.to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: "query_database".to_string(),
+                arguments: "{}".to_string(),
+            },
+        }]);
+        let mut response = message(
+            Role::Tool,
+            &json!({ "query_id": id, "tsv": "word ".repeat(output_words) }).to_string(),
+        );
+        response.tool_call_id = Some(id.to_string());
+        vec![call, response]
+    }
+
+    fn conversation() -> Vec<ChatterMessage> {
+        [
+            vec![message(Role::System, "You are a GIS assistant.")],
+            vec![message(Role::User, "First question")],
+            tool_round("call_1", 1000),
+            vec![message(Role::Assistant, "First answer")],
+            vec![message(Role::User, "Second question")],
+            tool_round("call_2", 1000),
+            tool_round("call_3", 1000),
+            vec![message(Role::Assistant, "Second answer")],
+            vec![message(Role::User, "Third question")],
+        ]
+        .concat()
+    }
+
+    /// A budget that barely fits the system message and the messages from the last tool
+    /// call onwards, so older turns have to go even after eliding.
+    fn protected_budget(messages: &[ChatterMessage]) -> usize {
+        count_all_tokens(&messages[..1]) + count_all_tokens(&messages[8..]) + 50
+    }
+
+    /// Every tool call must be immediately followed by its responses.
+    fn assert_tool_calls_answered(messages: &[ChatterMessage]) {
+        for (i, m) in messages.iter().enumerate() {
+            for (j, call) in m.tool_calls.iter().flatten().enumerate() {
+                let response = &messages[i + 1 + j];
+                assert_eq!(response.role, Role::Tool);
+                assert_eq!(response.tool_call_id.as_deref(), Some(call.id.as_str()));
+            }
+            if m.role == Role::Tool {
+                assert!(i > 0 && messages[..i].iter().any(|p| p.tool_calls.is_some()));
+            }
+        }
+    }
+
+    fn ready(fitted: FittedContext) -> Vec<ChatterMessage> {
+        match fitted {
+            FittedContext::Ready(messages) => messages,
+            other => panic!("Expected Ready, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fits_unchanged() {
+        let messages = conversation();
+        let fitted = ready(fit_messages(
+            &messages,
+            &ContextPolicy::default(),
+            None,
+            true,
+        ));
+        assert_eq!(fitted.len(), messages.len());
+    }
+
+    #[test]
+    fn test_elides_old_tool_outputs() {
+        let messages = conversation();
+        let policy = ContextPolicy {
+            max_prompt_tokens: 2500,
+            keep_recent_tool_calls: 2,
+            ..Default::default()
+        };
+        let fitted = ready(fit_messages(&messages, &policy, None, true));
+        assert_eq!(fitted.len(), messages.len());
+        assert_tool_calls_answered(&fitted);
+
+        // The first tool output is elided, but keeps its query ID.
+        let elided: serde_json::Value =
+            serde_json::from_str(fitted[3].message.as_deref().unwrap()).unwrap();
+        assert_eq!(elided["elided"], true);
+        assert_eq!(elided["query_id"], "call_1");
+        // The two most recent tool outputs are intact.
+        assert_eq!(fitted[7].message, messages[7].message);
+        assert_eq!(fitted[9].message, messages[9].message);
+    }
+
+    #[test]
+    fn test_summarizes_whole_groups() {
+        let messages = conversation();
+        let policy = ContextPolicy {
+            max_prompt_tokens: protected_budget(&messages),
+            keep_recent_tool_calls: 1,
+            ..Default::default()
+        };
+        let FittedContext::NeedsSummary { covered } = fit_messages(&messages, &policy, None, true)
+        else {
+            panic!("Expected NeedsSummary");
+        };
+        // The summarized messages must end on a group boundary.
+        let first_kept = &messages[1 + covered];
+        assert_ne!(first_kept.role, Role::Tool);
+
+        let summary = ContextSummary {
+            covered,
+            text: "The user asked two questions.".to_string(),
+        };
+        let fitted = ready(fit_messages(&messages, &policy, Some(&summary), false));
+        assert_eq!(fitted[0].role, Role::System);
+        assert_eq!(fitted[1].role, Role::System);
+        assert!(
+            fitted[1]
+                .message
+                .as_deref()
+                .unwrap()
+                .contains("two questions")
+        );
+        assert_eq!(
+            fitted.last().unwrap().message.as_deref(),
+            Some("Third question")
+        );
+        assert_tool_calls_answered(&fitted);
+    }
+
+    #[test]
+    fn test_drops_whole_groups_without_summary() {
+        let messages = conversation();
+        let policy = ContextPolicy {
+            max_prompt_tokens: protected_budget(&messages),
+            keep_recent_tool_calls: 1,
+            summarize: false,
+            ..Default::default()
+        };
+        let fitted = ready(fit_messages(&messages, &policy, None, true));
+        assert!(fitted.len() < messages.len());
+        assert_eq!(fitted[0].role, Role::System);
+        assert_ne!(fitted[1].role, Role::Tool);
+        assert_eq!(
+            fitted.last().unwrap().message.as_deref(),
+            Some("Third question")
+        );
+        assert_tool_calls_answered(&fitted);
+    }
+}
@@ -126,6 +126,18 @@ impl Db {
         Ok(())
     }
 
+    /// Delete an item by its key. Deleting an item that doesn't exist is not an error.
+    pub async fn delete_item(&self, pk: &str, sk: &str) -> Result<()> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key("pk", AttributeValue::S(pk.to_string()))
+            .key("sk", AttributeValue::S(sk.to_string()))
+            .send()
+            .await?;
+        Ok(())
+    }
+
     /// Execute a DynamoDB query with pagination, collecting all results.
     /// Takes a pre-built query builder and handles pagination.
     pub async fn query_all(
@@ -81,7 +81,7 @@ pub async fn create_table_if_not_exists(db: &Db) {
         .send()
         .await;
     match table {
-        Ok(_) => println!("Created table: {}", table_name),
-        Err(_) => println!("Table probably exists: {}", table_name),
+        Ok(_) => eprintln!("Created table: {}", table_name),
+        Err(_) => eprintln!("Table probably exists: {}", table_name),
     }
 }
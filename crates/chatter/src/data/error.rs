@@ -7,6 +7,10 @@ pub type Result<T> = std::result::Result<T, DataError>;
 pub enum DataError {
     #[error("serde_dynamo Error: {0}")]
     SerdeDynamoError(#[from] serde_dynamo::Error),
+    #[error("JSON Error: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Failed to build item: {0}")]
+    BuilderError(String),
 
     #[error("DynamoDB PutItem Error: {0}")]
     DynamoPutItemError(#[from] SdkError<operation::put_item::PutItemError>),
@@ -16,6 +20,8 @@ pub enum DataError {
     DynamoQueryError(#[from] SdkError<operation::query::QueryError>),
     #[error("DynamoDB UpdateItem Error: {0}")]
     DynamoUpdateItemError(#[from] SdkError<operation::update_item::UpdateItemError>),
+    #[error("DynamoDB DeleteItem Error: {0}")]
+    DynamoDeleteItemError(#[from] SdkError<operation::delete_item::DeleteItemError>),
 
     #[error("Optimistic lock error")]
     OptimisticLockFailed,
@@ -1,11 +1,16 @@
+use crate::chatter_context::ChatterContext;
+use crate::chatter_message::{ChatterMessageSidecar, Role, SQLExecutionDetails};
 use crate::data::dynamodb::Db;
-use crate::data::error::Result;
+use crate::data::error::{DataError, Result};
 use crate::data::migrations::{Migratable, Migrator};
+use crate::data::types::chat_thread::ChatThread;
+use crate::data::types::sql_query::SqlQuery;
 use async_trait::async_trait;
 use aws_sdk_dynamodb::types::AttributeValue;
 use derive_builder::Builder;
+use futures::future::try_join_all;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Serialize, Deserialize, Builder, Clone, Debug)]
 pub struct ChatMessage {
@@ -17,6 +22,11 @@ pub struct ChatMessage {
     pub sk: String,
 
     pub msg: crate::chatter_message::ChatterMessage,
+
+    /// The tokens used to generate this message. Only set on assistant messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[builder(default = "None")]
+    pub usage: Option<crate::chatter_message::MessageUsage>,
 }
 
 impl ChatMessageBuilder {
@@ -28,8 +38,8 @@ impl ChatMessageBuilder {
 
     /// Custom setter for `thread_id` and `message_id` that sets `sk` automatically.
     pub fn thread_message_ids(&mut self, thread_id: String, message_id: u32) -> &mut Self {
-        // pad the message_id with zeros so DynamoDB sorts it correctly
-        // we shouldn't need more than 3 digits (a 999 message thread is already way too long)
+        // Padded so DynamoDB sorts the first 1000 messages. IDs with more digits sort out of
+        // order, so messages are sorted by `id()` after they are read.
         self.sk = Some(format!("ChatMessage#{}#{:03}", thread_id, message_id));
         self
     }
@@ -48,7 +58,15 @@ impl ChatMessage {
         self.sk.split('#').nth(2).unwrap().parse().unwrap()
     }
 
-    /// Retrieve all messages for a given thread.
+    /// The query this message ran, if it is the response to a successful `query_database` call.
+    pub fn sql_execution(&self) -> Option<&SQLExecutionDetails> {
+        match &self.msg.sidecar {
+            ChatterMessageSidecar::SQLExecution(details) => Some(details),
+            _ => None,
+        }
+    }
+
+    /// Retrieve all messages for a given thread, in order.
     pub async fn get_all_thread_messages(
         db: &Db,
         user_id: &str,
@@ -71,13 +89,122 @@ impl ChatMessage {
         let items = db.query_all(query_builder, None).await?;
 
         // Convert DynamoDB items to ChatMessage structs
-        let messages = items
+        let mut messages = items
             .into_iter()
             .map(serde_dynamo::from_item)
             .collect::<std::result::Result<Vec<Self>, _>>()?;
+        messages.sort_by_key(|m| m.id());
 
         Ok(messages)
     }
+
+    /// Repair the tool calls of a thread (see `ChatterContext::repair_tool_calls`) and store
+    /// the result, so the synthetic responses are kept with the thread and the stored
+    /// messages match the context the model sees. `messages` are the thread's messages, in
+    /// order. The thread's context summary is discarded if it covers a changed message.
+    /// Returns the repaired messages, or `messages` if nothing had to be repaired.
+    pub async fn repair_thread(
+        db: &Db,
+        thread: &mut ChatThread,
+        messages: Vec<Self>,
+    ) -> Result<Vec<Self>> {
+        let user_id = thread.user_id().to_string();
+        let thread_id = thread.id().to_string();
+        let mut context = ChatterContext::new_with_stored(
+            thread_id.clone(),
+            messages.iter().map(|m| m.msg.clone()).collect(),
+        );
+        if context.repair_tool_calls() == 0 {
+            return Ok(messages);
+        }
+
+        // Messages after the first change have moved, so a summary covering them is stale
+        let first_changed = context
+            .messages
+            .iter()
+            .zip(&messages)
+            .position(|(repaired, stored)| {
+                repaired.role != stored.msg.role || repaired.tool_call_id != stored.msg.tool_call_id
+            })
+            .unwrap_or(context.messages.len().min(messages.len()));
+        ChatThread::discard_context_summary_from(db, &user_id, &thread_id, first_changed as u32)
+            .await?;
+        thread.context_summary = thread
+            .context_summary
+            .take()
+            .filter(|summary| summary.covered <= first_changed);
+
+        // Assistant messages are never added or dropped, so their usage stays in order
+        let mut usage = messages
+            .iter()
+            .filter(|m| m.msg.role == Role::Assistant)
+            .map(|m| m.usage.clone());
+        let mut repaired = Vec::with_capacity(context.messages.len());
+        for (id, msg) in context.messages.into_iter().enumerate() {
+            let usage = if msg.role == Role::Assistant {
+                usage.next().flatten()
+            } else {
+                None
+            };
+            let message = ChatMessageBuilder::default()
+                .thread_message_ids(thread_id.clone(), id as u32)
+                .user_id(user_id.clone())
+                .msg(msg)
+                .usage(usage)
+                .build()
+                .map_err(|e| DataError::BuilderError(e.to_string()))?;
+            repaired.push(message);
+        }
+
+        try_join_all(repaired[first_changed..].iter().map(|m| db.put_item(m))).await?;
+        // Messages were dropped, so the last ones are stored under a lower ID now
+        let moved = messages.get(repaired.len()..).unwrap_or_default();
+        try_join_all(moved.iter().map(|m| db.delete_item(&m.pk, &m.sk))).await?;
+        Ok(repaired)
+    }
+
+    /// Delete the messages of a thread, starting with `from_message_id`. The thread's
+    /// context summary is discarded if it covers any of them, and the `SqlQuery` layers
+    /// they ran are deleted, or put back to the version of the last message that is kept.
+    /// Returns the number of messages that were deleted.
+    pub async fn truncate_thread(
+        db: &Db,
+        user_id: &str,
+        thread_id: &str,
+        from_message_id: u32,
+    ) -> Result<usize> {
+        ChatThread::discard_context_summary_from(db, user_id, thread_id, from_message_id).await?;
+        let (kept, deleted): (Vec<Self>, Vec<Self>) =
+            Self::get_all_thread_messages(db, user_id, thread_id)
+                .await?
+                .into_iter()
+                .partition(|m| m.id() < from_message_id);
+
+        // Later versions of a query replace earlier ones
+        let kept_versions: HashMap<&str, &SQLExecutionDetails> = kept
+            .iter()
+            .filter_map(Self::sql_execution)
+            .map(|details| (details.id.as_str(), details))
+            .collect();
+        let deleted_queries: HashSet<&str> = deleted
+            .iter()
+            .filter_map(Self::sql_execution)
+            .map(|details| details.id.as_str())
+            .collect();
+        try_join_all(deleted_queries.into_iter().map(|query_id| {
+            let version = kept_versions.get(query_id).copied();
+            async move {
+                match version {
+                    Some(version) => SqlQuery::restore_version(db, thread_id, version).await,
+                    None => SqlQuery::delete_query(db, thread_id, query_id).await,
+                }
+            }
+        }))
+        .await?;
+
+        try_join_all(deleted.iter().map(|m| db.delete_item(&m.pk, &m.sk))).await?;
+        Ok(deleted.len())
+    }
 }
 
 pub struct ChatMessageMigrator;
@@ -106,3 +233,122 @@ impl Migrator for ChatMessageMigrator {
 impl Migratable for ChatMessage {
     type Migrator = ChatMessageMigrator;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, MessageUsage};
+    use crate::data::types::chat_thread::ChatThreadBuilder;
+    use async_openai::types::{
+        ChatCompletionMessageToolCall, ChatCompletionToolType, FunctionCall,
+    };
+    use chrono::Utc;
+
+    fn message(role: Role, tool_call_id: Option<&str>) -> ChatterMessage {
+        ChatterMessage {
+            message: Some("{}".to_string()),
+            role,
+            tool_calls: None,
+            tool_call_id: tool_call_id.map(str::to_string),
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    fn tool_calls(ids: &[&str]) -> ChatterMessage {
+        ChatterMessage {
+            tool_calls: Some(
+                ids.iter()
+                    .map(|id| ChatCompletionMessageToolCall {
+                        id: id.to_string(),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: "query_database".to_string(),
+                            arguments: "{}".to_string(),
+                        },
+                    })
+                    .collect(),
+            ),
+            ..message(Role::Assistant, None)
+        }
+    }
+
+    /// The synthetic responses are stored, and the messages after them are moved.
+    #[tokio::test]
+    async fn test_repair_thread() {
+        let db = Db::new().await;
+        let thread_id = ulid::Ulid::new().to_string();
+        let mut thread = ChatThreadBuilder::default()
+            .user_id("user123".to_string())
+            .id(thread_id.clone())
+            .title("Interrupted thread".to_string())
+            .modified_ts(Utc::now())
+            .build()
+            .expect("Failed building ChatThread");
+        db.put_item_excl(&thread)
+            .await
+            .expect("Failed to put thread");
+
+        let usage = MessageUsage {
+            model: "gpt-4o".to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 10,
+            cached_prompt_tokens: 0,
+            cache_write_prompt_tokens: 0,
+            cost_micros: None,
+        };
+        let stored = vec![
+            (message(Role::User, None), None),
+            // The second call was never answered
+            (tool_calls(&["call_1", "call_2"]), Some(usage.clone())),
+            (message(Role::Tool, Some("call_1")), None),
+            (message(Role::User, None), None),
+            // Responses without a tool call
+            (message(Role::Tool, Some("call_3")), None),
+            (message(Role::Tool, Some("call_4")), None),
+        ];
+        let mut messages = vec![];
+        for (id, (msg, usage)) in stored.into_iter().enumerate() {
+            let message = ChatMessageBuilder::default()
+                .thread_message_ids(thread_id.clone(), id as u32)
+                .user_id("user123".to_string())
+                .msg(msg)
+                .usage(usage)
+                .build()
+                .expect("Failed building ChatMessage");
+            db.put_item_excl(&message)
+                .await
+                .expect("Failed to put message");
+            messages.push(message);
+        }
+
+        let repaired = ChatMessage::repair_thread(&db, &mut thread, messages)
+            .await
+            .expect("Failed to repair thread");
+        let stored = ChatMessage::get_all_thread_messages(&db, "user123", &thread_id)
+            .await
+            .expect("Failed to get messages");
+        for messages in [&repaired, &stored] {
+            let summary: Vec<(u32, Role, Option<&str>)> = messages
+                .iter()
+                .map(|m| (m.id(), m.msg.role, m.msg.tool_call_id.as_deref()))
+                .collect();
+            assert_eq!(
+                summary,
+                vec![
+                    (0, Role::User, None),
+                    (1, Role::Assistant, None),
+                    (2, Role::Tool, Some("call_1")),
+                    (3, Role::Tool, Some("call_2")),
+                    (4, Role::User, None),
+                ]
+            );
+            assert_eq!(messages[1].usage.as_ref(), Some(&usage));
+        }
+
+        // A repaired thread is left as it is
+        let again = ChatMessage::repair_thread(&db, &mut thread, stored)
+            .await
+            .expect("Failed to repair thread");
+        assert_eq!(again.len(), 5);
+    }
+}
@@ -1,13 +1,25 @@
+use crate::chatter_message::SQLExecutionDetails;
+use crate::context_window::ContextSummary;
 use crate::data::error::Result;
 use crate::data::migrations::{Migratable, Migrator};
+use crate::data::types::chat_message::{ChatMessage, ChatMessageBuilder};
+use crate::data::types::sql_query::{SqlQuery, SqlQueryBuilder};
 use crate::data::{dynamodb::Db, error::DataError};
+use crate::functions::ToolProfile;
+use crate::llm::GenerationSettings;
 use async_trait::async_trait;
 use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
 use chrono::{DateTime, Utc};
 use derive_builder::Builder;
 use futures::future::try_join_all;
 use serde::{Deserialize, Serialize};
+use serde_dynamo::aws_sdk_dynamodb_1::to_attribute_value;
 use std::collections::HashMap;
+use ulid::Ulid;
+
+/// The current schema version. Items without a version, or with an older one, are upgraded
+/// by `ChatThreadMigrator` when they are read.
+pub const CHAT_THREAD_SCHEMA_VERSION: i32 = 3;
 
 #[derive(Serialize, Deserialize, Builder)]
 pub struct ChatThread {
@@ -29,6 +41,30 @@ pub struct ChatThread {
     /// `false` is equivalent to `None`.
     #[builder(default = "None")]
     pub archived: Option<bool>,
+
+    /// The model used to respond in this thread.
+    #[builder(default = "crate::llm::default_model()")]
+    pub model: String,
+
+    /// Generation parameters for this thread.
+    #[serde(default)]
+    #[builder(default)]
+    pub generation: GenerationSettings,
+
+    /// The tools that can be used in this thread. Threads created before profiles existed
+    /// get the default profile.
+    #[serde(default)]
+    #[builder(default)]
+    pub tool_profile: ToolProfile,
+
+    /// A summary of the first `covered` messages, sent instead of them once the thread no
+    /// longer fits in the model's context window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[builder(default)]
+    pub context_summary: Option<ContextSummary>,
+
+    #[builder(default = "CHAT_THREAD_SCHEMA_VERSION")]
+    pub schema_version: i32,
 }
 impl ChatThreadBuilder {
     /// Custom setter for `user_id` that sets `pk` automatically.
@@ -91,6 +127,166 @@ impl ChatThread {
 
         Ok(threads)
     }
+
+    /// Save the summary of the earliest messages. Only the summary is written, so changes
+    /// made to the thread in the meantime (a new title) are kept.
+    pub async fn set_context_summary(
+        db: &Db,
+        user_id: &str,
+        thread_id: &str,
+        summary: &ContextSummary,
+    ) -> Result<()> {
+        db.client
+            .update_item()
+            .table_name(&db.table_name)
+            .key("pk", AttributeValue::S(format!("User#{}", user_id)))
+            .key("sk", AttributeValue::S(format!("ChatThread#{}", thread_id)))
+            .condition_expression("attribute_exists(#sk)")
+            .update_expression("SET #context_summary = :context_summary")
+            .expression_attribute_names("#sk", "sk")
+            .expression_attribute_names("#context_summary", "context_summary")
+            .expression_attribute_values(":context_summary", to_attribute_value(summary)?)
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the summary if it covers message `message_id`, because that message is about
+    /// to be deleted or replaced.
+    pub async fn discard_context_summary_from(
+        db: &Db,
+        user_id: &str,
+        thread_id: &str,
+        message_id: u32,
+    ) -> Result<()> {
+        let result = db
+            .client
+            .update_item()
+            .table_name(&db.table_name)
+            .key("pk", AttributeValue::S(format!("User#{}", user_id)))
+            .key("sk", AttributeValue::S(format!("ChatThread#{}", thread_id)))
+            .condition_expression("#context_summary.#covered > :message_id")
+            .update_expression("REMOVE #context_summary")
+            .expression_attribute_names("#context_summary", "context_summary")
+            .expression_attribute_names("#covered", "covered")
+            .expression_attribute_values(":message_id", AttributeValue::N(message_id.to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            // There is no summary, or it only covers earlier messages
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Copy a thread up to and including message `up_to_message_id` into a new thread.
+    /// The `SqlQuery` layers created in the copied messages are copied too, under new IDs,
+    /// so the two threads can be changed independently.
+    pub async fn fork(
+        db: &Db,
+        user_id: &str,
+        thread_id: &str,
+        up_to_message_id: u32,
+    ) -> Result<Self> {
+        let (thread, messages, queries) = futures::try_join!(
+            Self::get_thread(db, user_id, thread_id),
+            ChatMessage::get_all_thread_messages(db, user_id, thread_id),
+            SqlQuery::get_thread_queries(db, thread_id),
+        )?;
+        let messages: Vec<ChatMessage> = messages
+            .into_iter()
+            .filter(|m| m.id() <= up_to_message_id)
+            .collect();
+        if !messages.iter().any(|m| m.id() == up_to_message_id) {
+            return Err(DataError::DocumentNotFound);
+        }
+
+        // The query may have been changed after the fork point, so the copy is made from
+        // the last version in the copied messages. Later versions replace earlier ones.
+        let versions: HashMap<&str, &SQLExecutionDetails> = messages
+            .iter()
+            .filter_map(ChatMessage::sql_execution)
+            .map(|details| (details.id.as_str(), details))
+            .collect();
+        // Query IDs are global, so the copies get new ones
+        let queries: Vec<&SqlQuery> = queries
+            .iter()
+            .filter(|q| versions.contains_key(q.id()))
+            .collect();
+        let new_ids: HashMap<String, String> = queries
+            .iter()
+            .map(|q| (q.id().to_string(), Ulid::new().to_string()))
+            .collect();
+
+        // The summary can be kept if it only covers copied messages
+        let context_summary = thread
+            .context_summary
+            .filter(|summary| summary.covered <= messages.len())
+            .map(|mut summary| {
+                for (old, new) in &new_ids {
+                    summary.text = summary.text.replace(old.as_str(), new);
+                }
+                summary
+            });
+
+        let new_thread_id = Ulid::new().to_string();
+        let now = Utc::now();
+        let new_thread = ChatThreadBuilder::default()
+            .user_id(user_id.to_string())
+            .id(new_thread_id.clone())
+            .title(thread.title.clone())
+            .modified_ts(now)
+            .model(thread.model.clone())
+            .generation(thread.generation.clone())
+            .tool_profile(thread.tool_profile)
+            .context_summary(context_summary)
+            .build()
+            .map_err(|e| DataError::BuilderError(e.to_string()))?;
+
+        let mut new_messages = Vec::with_capacity(messages.len());
+        for message in &messages {
+            // The query IDs appear in sidecars, tool call arguments and tool responses
+            let mut json = serde_json::to_string(&message.msg)?;
+            for (old, new) in &new_ids {
+                json = json.replace(old.as_str(), new);
+            }
+            let new_message = ChatMessageBuilder::default()
+                .user_id(user_id.to_string())
+                .thread_message_ids(new_thread_id.clone(), message.id())
+                .msg(serde_json::from_str(&json)?)
+                .build()
+                .map_err(|e| DataError::BuilderError(e.to_string()))?;
+            new_messages.push(new_message);
+        }
+        let mut new_queries = Vec::with_capacity(queries.len());
+        for query in queries {
+            let version = versions[query.id()];
+            let new_query = SqlQueryBuilder::default()
+                .thread_id(&new_thread_id)
+                .query_id(&new_ids[query.id()])
+                .query_name(version.name.clone())
+                .query_content(version.sql.clone())
+                .created_ts(now)
+                .modified_ts(now)
+                .accessed_ts(now)
+                .build()
+                .map_err(|e| DataError::BuilderError(e.to_string()))?;
+            new_queries.push(new_query);
+        }
+
+        try_join_all(new_messages.iter().map(|m| db.put_item_excl(m))).await?;
+        try_join_all(new_queries.iter().map(|q| db.put_item_excl(q))).await?;
+        // The thread is written last, so it only shows up once it is complete
+        db.put_item_excl(&new_thread).await?;
+        Ok(new_thread)
+    }
 }
 
 pub struct ChatThreadMigrator;
@@ -107,36 +303,56 @@ impl Migrator for ChatThreadMigrator {
             .and_then(|v| v.parse::<i32>().ok())
             .unwrap_or(1);
 
-        if version == 1 {
-            let pk = item.get("pk").and_then(|v| v.as_s().ok()).unwrap();
-            let sk = item.get("sk").and_then(|v| v.as_s().ok()).unwrap();
-
-            let updated_item = db
-                .client
-                .update_item()
-                .table_name(&db.table_name)
-                .key("pk", AttributeValue::S(pk.to_string()))
-                .key("sk", AttributeValue::S(sk.to_string()))
-                .update_expression(
-                    "SET #modified_ts = :modified_ts, #schema_version = :schema_version",
-                )
-                .expression_attribute_names("#schema_version", "schema_version")
+        if version >= CHAT_THREAD_SCHEMA_VERSION {
+            return Ok(item); // no migration needed
+        }
+
+        let pk = item.get("pk").and_then(|v| v.as_s().ok()).unwrap();
+        let sk = item.get("sk").and_then(|v| v.as_s().ok()).unwrap();
+
+        let mut update = db
+            .client
+            .update_item()
+            .table_name(&db.table_name)
+            .key("pk", AttributeValue::S(pk.to_string()))
+            .key("sk", AttributeValue::S(sk.to_string()))
+            .expression_attribute_names("#schema_version", "schema_version")
+            .expression_attribute_values(
+                ":schema_version",
+                AttributeValue::N(CHAT_THREAD_SCHEMA_VERSION.to_string()),
+            );
+        let mut sets = vec!["#schema_version = :schema_version"];
+
+        if version < 2 {
+            // v2: add modified_ts
+            sets.push("#modified_ts = :modified_ts");
+            update = update
                 .expression_attribute_names("#modified_ts", "modified_ts")
                 .expression_attribute_values(
                     ":modified_ts",
                     AttributeValue::N(Utc::now().timestamp_millis().to_string()),
-                )
-                .expression_attribute_values(":schema_version", AttributeValue::N("2".into()))
-                .return_values(ReturnValue::AllNew)
-                .send()
-                .await?
-                .attributes
-                .unwrap_or(item);
-
-            Ok(updated_item)
-        } else {
-            Ok(item) // no migration needed
+                );
         }
+        if version < 3 {
+            // v3: add the model. Generation settings default to empty.
+            sets.push("#model = if_not_exists(#model, :model)");
+            update = update
+                .expression_attribute_names("#model", "model")
+                .expression_attribute_values(
+                    ":model",
+                    AttributeValue::S(crate::llm::default_model()),
+                );
+        }
+
+        let updated_item = update
+            .update_expression(format!("SET {}", sets.join(", ")))
+            .return_values(ReturnValue::AllNew)
+            .send()
+            .await?
+            .attributes
+            .unwrap_or(item);
+
+        Ok(updated_item)
     }
 }
 
@@ -145,3 +361,274 @@ impl Migrator for ChatThreadMigrator {
 impl Migratable for ChatThread {
     type Migrator = ChatThreadMigrator;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A thread written before `modified_ts` and `model` existed is upgraded when read.
+    #[tokio::test]
+    async fn test_migrate_v1_thread() {
+        let db = Db::new().await;
+        let thread_id = ulid::Ulid::new().to_string();
+        db.client
+            .put_item()
+            .table_name(&db.table_name)
+            .item("pk", AttributeValue::S("User#user123".to_string()))
+            .item("sk", AttributeValue::S(format!("ChatThread#{}", thread_id)))
+            .item("title", AttributeValue::S("Old thread".to_string()))
+            .send()
+            .await
+            .expect("Failed to put v1 thread");
+
+        let thread = ChatThread::get_thread(&db, "user123", &thread_id)
+            .await
+            .expect("Failed to get thread");
+        assert_eq!(thread.title, "Old thread");
+        assert_eq!(thread.model, crate::llm::default_model());
+        assert_eq!(thread.generation, GenerationSettings::default());
+        assert_eq!(thread.schema_version, CHAT_THREAD_SCHEMA_VERSION);
+    }
+
+    /// New threads are written with the current schema version, so the model they were
+    /// created with is never overwritten by the migrator.
+    #[tokio::test]
+    async fn test_new_thread_keeps_model() {
+        let db = Db::new().await;
+        let thread_id = ulid::Ulid::new().to_string();
+        let thread = ChatThreadBuilder::default()
+            .user_id("user123".to_string())
+            .id(thread_id.clone())
+            .title("New thread".to_string())
+            .modified_ts(Utc::now())
+            .model("claude-sonnet-4".to_string())
+            .generation(GenerationSettings {
+                temperature: Some(0.2),
+                ..Default::default()
+            })
+            .build()
+            .expect("Failed building ChatThread");
+        db.put_item_excl(&thread)
+            .await
+            .expect("Failed to put thread");
+
+        let thread = ChatThread::get_thread(&db, "user123", &thread_id)
+            .await
+            .expect("Failed to get thread");
+        assert_eq!(thread.model, "claude-sonnet-4");
+        assert_eq!(thread.generation.temperature, Some(0.2));
+    }
+
+    /// The summary is kept until a message it covers is deleted.
+    #[tokio::test]
+    async fn test_context_summary() {
+        let db = Db::new().await;
+        let thread_id = ulid::Ulid::new().to_string();
+        let thread = ChatThreadBuilder::default()
+            .user_id("user123".to_string())
+            .id(thread_id.clone())
+            .title("Long thread".to_string())
+            .modified_ts(Utc::now())
+            .build()
+            .expect("Failed building ChatThread");
+        db.put_item_excl(&thread)
+            .await
+            .expect("Failed to put thread");
+
+        let summary = ContextSummary {
+            covered: 4,
+            text: "The user asked about schools.".to_string(),
+        };
+        ChatThread::set_context_summary(&db, "user123", &thread_id, &summary)
+            .await
+            .expect("Failed to set summary");
+        ChatThread::discard_context_summary_from(&db, "user123", &thread_id, 4)
+            .await
+            .expect("Failed to discard summary");
+        let thread = ChatThread::get_thread(&db, "user123", &thread_id)
+            .await
+            .expect("Failed to get thread");
+        assert_eq!(thread.context_summary.unwrap().covered, 4);
+
+        ChatThread::discard_context_summary_from(&db, "user123", &thread_id, 3)
+            .await
+            .expect("Failed to discard summary");
+        let thread = ChatThread::get_thread(&db, "user123", &thread_id)
+            .await
+            .expect("Failed to get thread");
+        assert!(thread.context_summary.is_none());
+    }
+
+    /// Forking copies the messages up to the fork point, and the layers created in them.
+    #[tokio::test]
+    async fn test_fork_thread() {
+        use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
+
+        let db = Db::new().await;
+        let thread_id = ulid::Ulid::new().to_string();
+        let query_id = ulid::Ulid::new().to_string();
+        let thread = ChatThreadBuilder::default()
+            .user_id("user123".to_string())
+            .id(thread_id.clone())
+            .title("Original".to_string())
+            .modified_ts(Utc::now())
+            .build()
+            .expect("Failed building ChatThread");
+        db.put_item_excl(&thread)
+            .await
+            .expect("Failed to put thread");
+
+        let messages = [
+            (Role::User, None, ChatterMessageSidecar::None),
+            (
+                Role::Tool,
+                Some(format!("{{\"query_id\":\"{}\"}}", query_id)),
+                ChatterMessageSidecar::SQLExecution(SQLExecutionDetails {
+                    id: query_id.clone(),
+                    name: "Points".to_string(),
+                    sql: "SELECT 1".to_string(),
+                }),
+            ),
+            (Role::Assistant, None, ChatterMessageSidecar::None),
+            // The query is changed after the fork point
+            (
+                Role::Tool,
+                Some(format!("{{\"query_id\":\"{}\"}}", query_id)),
+                ChatterMessageSidecar::SQLExecution(SQLExecutionDetails {
+                    id: query_id.clone(),
+                    name: "Points".to_string(),
+                    sql: "SELECT 2".to_string(),
+                }),
+            ),
+        ];
+        for (i, (role, message, sidecar)) in messages.into_iter().enumerate() {
+            let message = ChatMessageBuilder::default()
+                .user_id("user123".to_string())
+                .thread_message_ids(thread_id.clone(), i as u32)
+                .msg(ChatterMessage {
+                    message,
+                    role,
+                    tool_calls: None,
+                    tool_call_id: None,
+                    sidecar,
+                })
+                .build()
+                .expect("Failed building ChatMessage");
+            db.put_item_excl(&message)
+                .await
+                .expect("Failed to put message");
+        }
+        let now = Utc::now();
+        let query = SqlQueryBuilder::default()
+            .thread_id(&thread_id)
+            .query_id(&query_id)
+            .query_name("Points".to_string())
+            .query_content("SELECT 2".to_string())
+            .created_ts(now)
+            .modified_ts(now)
+            .accessed_ts(now)
+            .build()
+            .expect("Failed building SqlQuery");
+        db.put_item_excl(&query).await.expect("Failed to put query");
+
+        let fork = ChatThread::fork(&db, "user123", &thread_id, 1)
+            .await
+            .expect("Failed to fork thread");
+        assert_ne!(fork.id(), thread_id);
+        assert_eq!(fork.title, "Original");
+
+        let messages = ChatMessage::get_all_thread_messages(&db, "user123", fork.id())
+            .await
+            .expect("Failed to get messages");
+        assert_eq!(messages.len(), 2);
+        let ChatterMessageSidecar::SQLExecution(details) = &messages[1].msg.sidecar else {
+            panic!("Expected a SQLExecution sidecar");
+        };
+        assert_ne!(details.id, query_id);
+        assert!(
+            messages[1]
+                .msg
+                .message
+                .as_deref()
+                .unwrap()
+                .contains(&details.id)
+        );
+
+        let queries = SqlQuery::get_thread_queries(&db, fork.id())
+            .await
+            .expect("Failed to get queries");
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].id(), details.id);
+        assert_eq!(queries[0].query_content, "SELECT 1");
+
+        // The fork point has to be a message in the thread
+        assert!(matches!(
+            ChatThread::fork(&db, "user123", &thread_id, 10).await,
+            Err(DataError::DocumentNotFound)
+        ));
+    }
+
+    /// Truncating a thread undoes the changes its deleted messages made to the queries.
+    #[tokio::test]
+    async fn test_truncate_thread_queries() {
+        use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
+
+        let db = Db::new().await;
+        let thread_id = ulid::Ulid::new().to_string();
+        let query_id = ulid::Ulid::new().to_string();
+        let versions = ["SELECT 1", "SELECT 2"];
+        for (i, sql) in versions.into_iter().enumerate() {
+            let message = ChatMessageBuilder::default()
+                .user_id("user123".to_string())
+                .thread_message_ids(thread_id.clone(), i as u32)
+                .msg(ChatterMessage {
+                    message: None,
+                    role: Role::Tool,
+                    tool_calls: None,
+                    tool_call_id: None,
+                    sidecar: ChatterMessageSidecar::SQLExecution(SQLExecutionDetails {
+                        id: query_id.clone(),
+                        name: format!("Points {}", i),
+                        sql: sql.to_string(),
+                    }),
+                })
+                .build()
+                .expect("Failed building ChatMessage");
+            db.put_item_excl(&message)
+                .await
+                .expect("Failed to put message");
+        }
+        let now = Utc::now();
+        let query = SqlQueryBuilder::default()
+            .thread_id(&thread_id)
+            .query_id(&query_id)
+            .query_name("Points 1".to_string())
+            .query_content("SELECT 2".to_string())
+            .created_ts(now)
+            .modified_ts(now)
+            .accessed_ts(now)
+            .build()
+            .expect("Failed building SqlQuery");
+        db.put_item_excl(&query).await.expect("Failed to put query");
+
+        // The version of the first message is kept
+        let deleted = ChatMessage::truncate_thread(&db, "user123", &thread_id, 1)
+            .await
+            .expect("Failed to truncate thread");
+        assert_eq!(deleted, 1);
+        let query = SqlQuery::get_thread_query(&db, &thread_id, &query_id)
+            .await
+            .expect("Failed to get query");
+        assert_eq!(query.query_name, "Points 0");
+        assert_eq!(query.query_content, "SELECT 1");
+
+        // No message ran the query anymore
+        ChatMessage::truncate_thread(&db, "user123", &thread_id, 0)
+            .await
+            .expect("Failed to truncate thread");
+        assert!(matches!(
+            SqlQuery::get_thread_query(&db, &thread_id, &query_id).await,
+            Err(DataError::DocumentNotFound)
+        ));
+    }
+}
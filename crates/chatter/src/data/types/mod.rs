@@ -2,3 +2,4 @@ pub mod chat_message;
 pub mod chat_thread;
 pub mod data_request;
 pub mod sql_query;
+pub mod usage;
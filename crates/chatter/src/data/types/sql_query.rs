@@ -1,3 +1,4 @@
+use crate::chatter_message::SQLExecutionDetails;
 use crate::data::dynamodb::Db;
 use crate::data::error::{DataError, Result};
 use crate::data::migrations::{Migratable, Migrator};
@@ -96,6 +97,33 @@ impl SqlQuery {
         Ok(query)
     }
 
+    /// Put a query back to the version recorded in a message's sidecar, e.g. when the
+    /// messages that changed it are deleted. Does nothing if the query doesn't exist.
+    pub async fn restore_version(
+        db: &Db,
+        thread_id: &str,
+        version: &SQLExecutionDetails,
+    ) -> Result<()> {
+        let mut query = match Self::get_thread_query(db, thread_id, &version.id).await {
+            Ok(query) => query,
+            Err(DataError::DocumentNotFound) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        query.query_name = version.name.clone();
+        query.query_content = version.sql.clone();
+        query.modified_ts = Utc::now();
+        db.put_item(&query).await
+    }
+
+    /// Delete a query from a thread
+    pub async fn delete_query(db: &Db, thread_id: &str, query_id: &str) -> Result<()> {
+        db.delete_item(
+            &format!("ChatThread#{}", thread_id),
+            &format!("SqlQuery#{}", query_id),
+        )
+        .await
+    }
+
     /// Get a specific SQL query by ID in a thread
     pub async fn get_thread_query(db: &Db, thread_id: &str, query_id: &str) -> Result<Self> {
         let item = db
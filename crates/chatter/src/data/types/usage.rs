@@ -0,0 +1,219 @@
+use crate::chatter_message::MessageUsage;
+use crate::data::dynamodb::Db;
+use crate::data::error::Result;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Aggregated token usage. There are two kinds of counters, both under the user's partition:
+///
+/// - `Usage#Thread#<thread_id>`: all usage in a thread
+/// - `Usage#Day#<YYYY-MM-DD>#<model>`: a user's daily usage of a model
+///
+/// Counters are only ever incremented with `UsageCounter::record`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UsageCounter {
+    /// `User#<user_id>`
+    pub pk: String,
+    pub sk: String,
+
+    #[serde(default)]
+    pub requests: u64,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub cached_prompt_tokens: u64,
+    #[serde(default)]
+    pub cache_write_prompt_tokens: u64,
+    /// The cost in millionths of a US dollar. Requests to models without a known price
+    /// are not included.
+    #[serde(default)]
+    pub cost_micros: u64,
+}
+
+impl UsageCounter {
+    pub fn user_id(&self) -> &str {
+        self.pk.trim_start_matches("User#")
+    }
+
+    /// The date of a daily counter.
+    pub fn date(&self) -> Option<NaiveDate> {
+        let date = self.sk.strip_prefix("Usage#Day#")?.split('#').next()?;
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+    }
+
+    /// The model of a daily counter.
+    pub fn model(&self) -> Option<&str> {
+        let (_date, model) = self.sk.strip_prefix("Usage#Day#")?.split_once('#')?;
+        Some(model)
+    }
+
+    fn thread_sk(thread_id: &str) -> String {
+        format!("Usage#Thread#{}", thread_id)
+    }
+
+    fn day_sk(date: NaiveDate, model: &str) -> String {
+        format!("Usage#Day#{}#{}", date.format("%Y-%m-%d"), model)
+    }
+
+    /// Add the usage of a message to the thread's counter and the user's daily counter.
+    pub async fn record(
+        db: &Db,
+        user_id: &str,
+        thread_id: &str,
+        usage: &MessageUsage,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        let thread = Self::increment(db, user_id, Self::thread_sk(thread_id), usage);
+        let day = Self::increment(
+            db,
+            user_id,
+            Self::day_sk(at.date_naive(), &usage.model),
+            usage,
+        );
+        tokio::try_join!(thread, day)?;
+        Ok(())
+    }
+
+    async fn increment(db: &Db, user_id: &str, sk: String, usage: &MessageUsage) -> Result<()> {
+        let n = |v: u64| AttributeValue::N(v.to_string());
+        db.client
+            .update_item()
+            .table_name(&db.table_name)
+            .key("pk", AttributeValue::S(format!("User#{}", user_id)))
+            .key("sk", AttributeValue::S(sk))
+            .update_expression(
+                "ADD #requests :one, #prompt_tokens :prompt_tokens, #completion_tokens :completion_tokens, #cached_prompt_tokens :cached_prompt_tokens, #cache_write_prompt_tokens :cache_write_prompt_tokens, #cost_micros :cost_micros",
+            )
+            .expression_attribute_names("#requests", "requests")
+            .expression_attribute_names("#prompt_tokens", "prompt_tokens")
+            .expression_attribute_names("#completion_tokens", "completion_tokens")
+            .expression_attribute_names("#cached_prompt_tokens", "cached_prompt_tokens")
+            .expression_attribute_names("#cache_write_prompt_tokens", "cache_write_prompt_tokens")
+            .expression_attribute_names("#cost_micros", "cost_micros")
+            .expression_attribute_values(":one", n(1))
+            .expression_attribute_values(":prompt_tokens", n(usage.prompt_tokens as u64))
+            .expression_attribute_values(":completion_tokens", n(usage.completion_tokens as u64))
+            .expression_attribute_values(
+                ":cached_prompt_tokens",
+                n(usage.cached_prompt_tokens as u64),
+            )
+            .expression_attribute_values(
+                ":cache_write_prompt_tokens",
+                n(usage.cache_write_prompt_tokens as u64),
+            )
+            .expression_attribute_values(":cost_micros", n(usage.cost_micros.unwrap_or(0)))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Get the usage of a thread. Returns `None` if nothing has been recorded yet.
+    pub async fn get_thread_usage(db: &Db, user_id: &str, thread_id: &str) -> Result<Option<Self>> {
+        let item = db
+            .client
+            .get_item()
+            .table_name(&db.table_name)
+            .key("pk", AttributeValue::S(format!("User#{}", user_id)))
+            .key("sk", AttributeValue::S(Self::thread_sk(thread_id)))
+            .send()
+            .await?
+            .item;
+
+        Ok(item.map(serde_dynamo::from_item).transpose()?)
+    }
+
+    /// Get a user's daily counters, for every model, from `from` to `to` (inclusive).
+    pub async fn get_daily_usage(
+        db: &Db,
+        user_id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<Self>> {
+        // `~` sorts after `#` and every character used in model names, so the range
+        // includes all models on the last day.
+        let query_builder = db
+            .client
+            .query()
+            .key_condition_expression("#pk = :pk AND #sk BETWEEN :from AND :to")
+            .expression_attribute_names("#pk", "pk")
+            .expression_attribute_names("#sk", "sk")
+            .expression_attribute_values(":pk", AttributeValue::S(format!("User#{}", user_id)))
+            .expression_attribute_values(
+                ":from",
+                AttributeValue::S(format!("Usage#Day#{}", from.format("%Y-%m-%d"))),
+            )
+            .expression_attribute_values(
+                ":to",
+                AttributeValue::S(format!("Usage#Day#{}#~", to.format("%Y-%m-%d"))),
+            );
+
+        let items = db.query_all(query_builder, None).await?;
+
+        let counters = items
+            .into_iter()
+            .map(serde_dynamo::from_item)
+            .collect::<std::result::Result<Vec<Self>, _>>()?;
+
+        Ok(counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(model: &str) -> MessageUsage {
+        MessageUsage {
+            model: model.to_string(),
+            prompt_tokens: 1000,
+            completion_tokens: 100,
+            cached_prompt_tokens: 200,
+            cache_write_prompt_tokens: 300,
+            cost_micros: Some(2500),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_usage() {
+        let db = Db::new().await;
+        let user_id = format!("usage_test_{}", ulid::Ulid::new());
+        let at = "2025-05-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        UsageCounter::record(&db, &user_id, "thread1", &usage("gpt-4.1"), at)
+            .await
+            .expect("Failed to record usage");
+        UsageCounter::record(&db, &user_id, "thread1", &usage("gpt-4.1"), at)
+            .await
+            .expect("Failed to record usage");
+        UsageCounter::record(&db, &user_id, "thread2", &usage("gpt-4.1-mini"), at)
+            .await
+            .expect("Failed to record usage");
+
+        let thread = UsageCounter::get_thread_usage(&db, &user_id, "thread1")
+            .await
+            .expect("Failed to get thread usage")
+            .expect("Thread usage not found");
+        assert_eq!(thread.requests, 2);
+        assert_eq!(thread.prompt_tokens, 2000);
+        assert_eq!(thread.cache_write_prompt_tokens, 600);
+        assert_eq!(thread.cost_micros, 5000);
+
+        let from = NaiveDate::from_ymd_opt(2025, 5, 1).unwrap();
+        let daily = UsageCounter::get_daily_usage(&db, &user_id, from, from)
+            .await
+            .expect("Failed to get daily usage");
+        assert_eq!(daily.len(), 2);
+        assert!(daily.iter().all(|c| c.date() == Some(from)));
+        let models: Vec<_> = daily.iter().filter_map(|c| c.model()).collect();
+        assert_eq!(models, vec!["gpt-4.1", "gpt-4.1-mini"]);
+
+        let next_day = from.succ_opt().unwrap();
+        let daily = UsageCounter::get_daily_usage(&db, &user_id, next_day, next_day)
+            .await
+            .expect("Failed to get daily usage");
+        assert!(daily.is_empty());
+    }
+}
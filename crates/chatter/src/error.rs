@@ -5,6 +5,29 @@ pub enum ChatterError {
     #[error(transparent)]
     OpenAIError(#[from] async_openai::error::OpenAIError),
     #[error(transparent)]
+    HttpError(#[from] reqwest::Error),
+    #[error("LLM API error ({status}): {message}")]
+    LlmApiError {
+        status: u16,
+        message: String,
+        /// How long the API asked us to wait before retrying (`Retry-After`).
+        retry_after: Option<std::time::Duration>,
+    },
+    #[error("LLM configuration error: {0}")]
+    LlmConfigError(String),
+    #[error("Tool configuration error: {0}")]
+    ToolConfigError(String),
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+    #[error("The replay transcript has no more responses")]
+    ReplayExhausted,
+    #[error("The LLM response stream ended before the response was complete")]
+    LlmStreamIncomplete,
+    #[error("The turn was cancelled")]
+    Cancelled,
+    #[error("The turn ran out of time")]
+    TurnDeadline,
+    #[error(transparent)]
     PostgresError(#[from] tokio_postgres::Error),
     #[error(transparent)]
     EnvError(#[from] std::env::VarError),
@@ -17,6 +40,14 @@ pub enum ChatterError {
 
     #[error("Unknown Tool Call: {0}")]
     UnknownToolCall(String),
+    /// The model called an unknown function, or with arguments that don't match its schema.
+    #[error("Invalid tool call: {0}")]
+    InvalidToolCall(String),
+    #[error("Tool `{tool}` did not finish within {timeout:?}")]
+    ToolTimeout {
+        tool: String,
+        timeout: std::time::Duration,
+    },
     #[error("Unknown Role: {0}")]
     UnknownRole(String),
 
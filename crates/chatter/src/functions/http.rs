@@ -0,0 +1,305 @@
+//! Tools that are served by other services over HTTP, declared in a config file instead of
+//! being compiled in.
+//!
+//! ```json
+//! {
+//!   "tools": [
+//!     {
+//!       "name": "geocode",
+//!       "description": "Look up the coordinates of an address.",
+//!       "parameters": {
+//!         "type": "object",
+//!         "properties": { "address": { "type": "string" } },
+//!         "required": ["address"],
+//!         "additionalProperties": false
+//!       },
+//!       "url": "http://geocoder.internal/geocode",
+//!       "headers": { "Authorization": "Bearer ${GEOCODER_TOKEN}" },
+//!       "response_template": "{{results.0.name}}: {{results.0.lat}}, {{results.0.lng}}",
+//!       "timeout_secs": 10,
+//!       "access": "read"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The arguments from the model are POSTed to `url` as JSON. `${VAR}` in header values is
+//! replaced with the environment variable `VAR`, so secrets don't have to be in the file.
+
+use super::{LlmFunction, LlmFunctionExecutor, SharedResources, ToolAccess, ToolOutput};
+use crate::chatter_message::ChatterMessage;
+use crate::error::{ChatterError, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The config file listing the HTTP tools.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpToolsConfig {
+    pub tools: Vec<HttpToolConfig>,
+}
+
+impl HttpToolsConfig {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            ChatterError::ToolConfigError(format!("Could not read {}: {}", path, e))
+        })?;
+        serde_json::from_str(&contents)
+            .map_err(|e| ChatterError::ToolConfigError(format!("Could not parse {}: {}", path, e)))
+    }
+}
+
+/// A tool that POSTs its arguments to an HTTP endpoint.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpToolConfig {
+    pub name: String,
+    pub description: String,
+    /// The JSON schema of the arguments.
+    pub parameters: Value,
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// How the JSON response is shown to the model. `{{path.to.value}}` is replaced with
+    /// the value at that path (array elements by index). If not set, the response body is
+    /// passed to the model as-is.
+    #[serde(default)]
+    pub response_template: Option<String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Set if the endpoint only reads data.
+    #[serde(default)]
+    pub concurrency_safe: bool,
+    /// `read`, `write` (the default) or `admin`. Decides which tool profiles include it.
+    #[serde(default)]
+    pub access: ToolAccess,
+}
+
+pub struct HttpFunction {
+    config: HttpToolConfig,
+    client: reqwest::Client,
+}
+
+impl HttpFunction {
+    pub fn new(config: HttpToolConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Send the arguments to the endpoint, and return the text for the model.
+    pub async fn call(&self, arguments: Value) -> Result<String> {
+        let mut request = self.client.post(&self.config.url).json(&arguments);
+        for (name, value) in &self.config.headers {
+            request = request.header(name, expand_env(value));
+        }
+        let response = request.send().await?.error_for_status()?;
+        match &self.config.response_template {
+            Some(template) => {
+                let body: Value = response.json().await?;
+                Ok(render_template(template, &body))
+            }
+            None => Ok(response.text().await?),
+        }
+    }
+}
+
+impl LlmFunction for HttpFunction {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn description(&self) -> &str {
+        &self.config.description
+    }
+
+    fn parameters_schema(&self) -> Value {
+        self.config.parameters.clone()
+    }
+
+    fn concurrency_safe(&self) -> bool {
+        self.config.concurrency_safe
+    }
+
+    fn timeout(&self) -> Duration {
+        self.config
+            .timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(super::DEFAULT_TOOL_TIMEOUT)
+    }
+
+    fn access(&self) -> ToolAccess {
+        self.config.access
+    }
+}
+
+#[async_trait]
+impl LlmFunctionExecutor for HttpFunction {
+    async fn execute(
+        &self,
+        _resources: &SharedResources,
+        tool_call_id: String,
+        params: Value,
+    ) -> Result<ChatterMessage> {
+        let output = self.call(params).await?;
+        Ok(ToolOutput::new(output).into_message(tool_call_id))
+    }
+}
+
+/// Replace `${VAR}` with the value of the environment variable `VAR` (empty if unset).
+fn expand_env(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&std::env::var(&rest[start + 2..start + end]).unwrap_or_default());
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Replace `{{path}}` in `template` with the value at `path` in `value`. Strings are
+/// inserted without quotes, other values as JSON. Missing values are left empty.
+fn render_template(template: &str, value: &Value) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let path = rest[start + 2..start + end].trim();
+        let pointer = if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", path.replace('.', "/"))
+        };
+        match value.pointer(&pointer) {
+            Some(Value::String(s)) => out.push_str(s),
+            Some(Value::Null) | None => {}
+            Some(other) => out.push_str(&other.to_string()),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve a single request with `response_body`, and return the body of that request.
+    async fn stub_server(response_body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/tool", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            let body = loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let content_length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if body.len() >= content_length || n == 0 {
+                    break body.to_string();
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response_body.len(),
+                response_body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            body
+        });
+        (url, handle)
+    }
+
+    fn config(url: String, response_template: Option<&str>) -> HttpToolConfig {
+        serde_json::from_value(json!({
+            "name": "geocode",
+            "description": "Look up the coordinates of an address.",
+            "parameters": {
+                "type": "object",
+                "properties": { "address": { "type": "string" } },
+                "required": ["address"],
+                "additionalProperties": false
+            },
+            "url": url,
+            "response_template": response_template,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_call() {
+        let (url, server) =
+            stub_server(r#"{"results":[{"name":"東京駅","lat":35.681,"lng":139.767}]}"#).await;
+        let function = HttpFunction::new(config(
+            url,
+            Some("{{results.0.name}}: {{results.0.lat}}, {{results.0.lng}}"),
+        ));
+        let output = function
+            .call(json!({ "address": "東京都千代田区丸の内1丁目" }))
+            .await
+            .unwrap();
+        assert_eq!(output, "東京駅: 35.681, 139.767");
+
+        let request: Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert_eq!(request["address"], "東京都千代田区丸の内1丁目");
+    }
+
+    #[tokio::test]
+    async fn test_call_without_template() {
+        let (url, _server) = stub_server(r#"{"ok":true}"#).await;
+        let function = HttpFunction::new(config(url, None));
+        let output = function.call(json!({ "address": "x" })).await.unwrap();
+        assert_eq!(output, r#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn test_render_template() {
+        let value = json!({ "a": { "b": [1, "two", null] }, "c": true });
+        assert_eq!(
+            render_template(
+                "{{a.b.0}} {{ a.b.1 }} [{{a.b.2}}] {{c}} {{missing}}",
+                &value
+            ),
+            "1 two [] true "
+        );
+        assert_eq!(
+            render_template("no placeholders", &value),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn test_expand_env() {
+        assert_eq!(
+            expand_env("Bearer ${CHATTER_TEST_UNSET_VARIABLE}"),
+            "Bearer "
+        );
+        assert_eq!(expand_env("plain"), "plain");
+    }
+}
@@ -0,0 +1,66 @@
+use crate::error::{ChatterError, Result};
+use crate::functions::impls::query_database::run_query;
+use crate::functions::{SharedResources, ToolOutput};
+use crate::mesh::{MeshLevel, mesh_code_sql, mesh_polygon_sql, mesh_seconds_sql};
+use crate::pg_helpers::quote_identifier;
+use chatter_macros::llm_function;
+
+/// Aggregate the results of a SQL query onto the JIS regional mesh grid (地域メッシュ), and show the mesh cells as a layer, like `query_database`.
+/// Each cell has its `mesh_code`, the `count` of rows in it and, if `value_column` is given, the `sum` of that column.
+/// Rows are assigned to cells by their `geom` at `level`, or, if `mesh_code_column` is given, by the mesh codes in that column. Use `mesh_code_column` for datasets that are published on mesh codes.
+/// Rows outside the meshes (latitudes 0° to 66°40', longitudes 100° to 200°) are left out.
+/// To find the mesh code of a point, or the polygon of a mesh code, use `convert_mesh_code`.
+// Only writes the query under its own ID, like `query_database`.
+#[llm_function(concurrency_safe, timeout_secs = 60, access = read)]
+async fn aggregate_to_mesh(
+    resources: &SharedResources,
+    /// The ID of the query. When updating or revising a query, provide the ID of the query you want to update. If this is a new query, pass an empty string.
+    query_id: String,
+    /// The name this query will be referred to as. This will be shown to the user. It must be short and descriptive.
+    name: String,
+    /// The SQL query with the rows to aggregate. It must return a `geom` column, unless `mesh_code_column` is given.
+    query: String,
+    /// The mesh level: `1` (about 80 km), `2` (about 10 km), `3` (about 1 km), `half` (about 500 m) or `quarter` (about 250 m). Ignored when `mesh_code_column` is given.
+    level: String,
+    /// A column of the query that already has mesh codes. Pass an empty string to use `geom` instead.
+    mesh_code_column: String,
+    /// A numeric column of the query to sum in each cell. Pass an empty string to only count rows.
+    value_column: String,
+) -> Result<ToolOutput> {
+    let query = query.trim_end_matches(';');
+    let mesh_code = if mesh_code_column.is_empty() {
+        let level: MeshLevel = level.parse().map_err(ChatterError::InvalidToolCall)?;
+        let (lat_s, lon_s) = mesh_seconds_sql(r#"ST_PointOnSurface("t"."geom")"#);
+        mesh_code_sql(&lat_s, &lon_s, level)
+    } else {
+        format!(r#""t".{}::text"#, quote_identifier(&mesh_code_column))
+    };
+    let (value, sum) = if value_column.is_empty() {
+        ("NULL".to_string(), "")
+    } else {
+        (
+            format!(r#""t".{}"#, quote_identifier(&value_column)),
+            r#", SUM("value") AS "sum""#,
+        )
+    };
+
+    let query = format!(
+        r#"
+            WITH "cells" AS (
+                SELECT {mesh_code} AS "mesh_code", {value} AS "value"
+                FROM ({query}) AS "t"
+            )
+            SELECT
+                ROW_NUMBER() OVER (ORDER BY "mesh_code") AS "_id",
+                "mesh_code",
+                COUNT(*) AS "count"{sum},
+                {geom} AS "geom"
+            FROM "cells"
+            -- Rows without a code, or with points outside the meshes
+            WHERE "mesh_code" IS NOT NULL
+            GROUP BY "mesh_code"
+        "#,
+        geom = mesh_polygon_sql(r#""mesh_code""#),
+    );
+    run_query(resources, query_id, name, &query).await
+}
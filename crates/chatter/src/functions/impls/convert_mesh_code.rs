@@ -0,0 +1,65 @@
+use crate::error::{ChatterError, Result};
+use crate::functions::{SharedResources, ToolOutput, validate_coordinates};
+use crate::mesh::{self, MeshLevel, mesh_bounds};
+use chatter_macros::llm_function;
+use serde_json::json;
+
+/// Convert between points and JIS regional mesh codes (地域メッシュ).
+/// Given a `mesh_code`, returns its level, bounds and polygon (WKT, EPSG 4326). Otherwise, returns the mesh codes of the point at `longitude` and `latitude`.
+/// Use this to find the mesh code of a place before querying datasets that are published on mesh codes.
+// Only computes, without the database.
+#[llm_function(concurrency_safe, access = read)]
+async fn convert_mesh_code(
+    _resources: &SharedResources,
+    /// The mesh code to convert to a polygon. Pass an empty string to convert the point instead.
+    mesh_code: String,
+    /// The longitude of the point (WGS84). Ignored when `mesh_code` is given.
+    longitude: f64,
+    /// The latitude of the point (WGS84). Ignored when `mesh_code` is given.
+    latitude: f64,
+    /// The mesh level of the code to return for the point: `1` (about 80 km), `2` (about 10 km), `3` (about 1 km), `half` (about 500 m) or `quarter` (about 250 m). Pass an empty string for the codes at every level.
+    level: String,
+) -> Result<ToolOutput> {
+    let out = if mesh_code.is_empty() {
+        validate_coordinates(longitude, latitude).map_err(ChatterError::InvalidToolCall)?;
+        let levels = if level.is_empty() {
+            MeshLevel::ALL.to_vec()
+        } else {
+            vec![level.parse().map_err(ChatterError::InvalidToolCall)?]
+        };
+        let codes: Option<serde_json::Map<_, _>> = levels
+            .into_iter()
+            .map(|level| {
+                let code = mesh::mesh_code(longitude, latitude, level)?;
+                Some((level.name().to_string(), json!(code)))
+            })
+            .collect();
+        let Some(codes) = codes else {
+            return Err(ChatterError::InvalidToolCall(format!(
+                "({}, {}) has no mesh code. Mesh codes only cover latitudes 0° to 66°40' and longitudes 100° to 200°. Check that the longitude and latitude are not swapped.",
+                longitude, latitude
+            )));
+        };
+        json!({ "longitude": longitude, "latitude": latitude, "mesh_codes": codes })
+    } else {
+        let code = mesh_code.trim();
+        let bounds = mesh_bounds(code).ok_or_else(|| {
+            ChatterError::InvalidToolCall(format!(
+                "`{}` is not a mesh code. Mesh codes have 4, 6, 8, 9 or 10 digits.",
+                code
+            ))
+        })?;
+        let [min_lon, min_lat, max_lon, max_lat] = bounds;
+        let level = MeshLevel::from_code_length(code.len()).unwrap();
+        json!({
+            "mesh_code": code,
+            "level": level.name(),
+            "bounds": bounds,
+            "center": [(min_lon + max_lon) / 2.0, (min_lat + max_lat) / 2.0],
+            "polygon": format!(
+                "POLYGON(({min_lon} {min_lat}, {max_lon} {min_lat}, {max_lon} {max_lat}, {min_lon} {max_lat}, {min_lon} {min_lat}))"
+            ),
+        })
+    };
+    Ok(ToolOutput::new(serde_json::to_string_pretty(&out).unwrap()))
+}
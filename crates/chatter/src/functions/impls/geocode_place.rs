@@ -0,0 +1,301 @@
+use crate::chatter_message::ChatterMessageSidecar;
+use crate::error::{ChatterError, Result};
+use crate::functions::{SharedResources, ToolOutput};
+use crate::pg_helpers::quote_literal;
+use crate::places::parse_place_query;
+use chatter_macros::llm_function;
+use serde::Serialize;
+
+const MAX_CANDIDATES: i64 = 10;
+
+/// Removes the suffix that says what kind of area it is, so `渋谷` matches `渋谷区`.
+const STRIP_SUFFIX: &str = r#"regexp_replace({}, '(都|道|府|県|市|区|町|村)$', '')"#;
+
+/// Find administrative areas (prefectures, municipalities and wards) by name. Returns ranked candidates with their codes, bounding boxes and a SQL query for their boundaries.
+/// Use this instead of writing queries against the administrative boundaries when the user names a place.
+/// Place names can be Japanese (`渋谷区`, `東京都渋谷区`, `横浜市 中区`) or, for prefectures, designated cities and the special wards of Tokyo, romanized (`Shibuya, Tokyo`).
+// Only reads from the database.
+#[llm_function(concurrency_safe, access = read)]
+async fn geocode_place(
+    resources: &SharedResources,
+    /// The name of the place. Put the larger areas it is in first in Japanese, and after a comma when romanized.
+    place: String,
+) -> Result<ToolOutput> {
+    let Some(query) = parse_place_query(&place) else {
+        return Err(ChatterError::InvalidToolCall(format!(
+            "`{}` could not be parsed as a place name. Romanized names are only supported for prefectures, designated cities and the special wards of Tokyo. Try the name in Japanese.",
+            place
+        )));
+    };
+    // Names that start with the query match too, so `渋谷` finds `渋谷区`
+    let prefixes: Vec<String> = if query.japanese {
+        query
+            .names
+            .iter()
+            .map(|name| format!("{}%", name.replace('%', r"\%").replace('_', r"\_")))
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let matches = |column: &str| {
+        format!(
+            "({column} = ANY($1) OR {stripped} = ANY($1) OR {column} LIKE ANY($3))",
+            stripped = STRIP_SUFFIX.replace("{}", column),
+        )
+    };
+    let sql = format!(
+        r#"
+            WITH "areas" AS (
+                SELECT
+                    'prefecture' AS "level",
+                    "都道府県名" AS "name",
+                    "都道府県名" AS "prefecture",
+                    NULL AS "subprefecture",
+                    NULL AS "county",
+                    NULL AS "municipality",
+                    NULL AS "ward",
+                    left(min("全国地方公共団体コード"), 2) || '000' AS "code",
+                    ST_Collect("geom") AS "geom"
+                FROM "n03_union"
+                WHERE {prefecture_matches}
+                GROUP BY "都道府県名"
+                UNION ALL
+                SELECT
+                    'municipality',
+                    "市区町村名",
+                    "都道府県名",
+                    min("北海道の振興局名"),
+                    min("郡名"),
+                    "市区町村名",
+                    NULL,
+                    -- Designated cities are made up of wards, which have their own codes
+                    CASE WHEN count(DISTINCT "全国地方公共団体コード") = 1 THEN min("全国地方公共団体コード") END,
+                    ST_Collect("geom")
+                FROM "n03_union"
+                WHERE {municipality_matches}
+                GROUP BY "都道府県名", "市区町村名"
+                UNION ALL
+                SELECT
+                    'ward',
+                    "政令指定都市の行政区域名",
+                    "都道府県名",
+                    "北海道の振興局名",
+                    "郡名",
+                    "市区町村名",
+                    "政令指定都市の行政区域名",
+                    "全国地方公共団体コード",
+                    "geom"
+                FROM "n03_union"
+                WHERE {ward_matches}
+            )
+            SELECT
+                "level",
+                "name",
+                "prefecture",
+                "subprefecture",
+                "county",
+                "municipality",
+                "ward",
+                "code",
+                CASE
+                    WHEN "name" = ANY($1) THEN 1.0
+                    WHEN {name_stripped} = ANY($1) THEN 0.9
+                    ELSE 0.5
+                END::float8 AS "score",
+                ST_XMin("geom")::float8,
+                ST_YMin("geom")::float8,
+                ST_XMax("geom")::float8,
+                ST_YMax("geom")::float8,
+                ST_X(ST_Centroid("geom"))::float8,
+                ST_Y(ST_Centroid("geom"))::float8
+            FROM "areas"
+            WHERE {within}
+            ORDER BY
+                "score" DESC,
+                array_position(ARRAY['prefecture', 'municipality', 'ward'], "level"),
+                "code"
+            LIMIT $4
+        "#,
+        prefecture_matches = matches(r#""都道府県名""#),
+        municipality_matches = matches(r#""市区町村名""#),
+        ward_matches = matches(r#""政令指定都市の行政区域名""#),
+        name_stripped = STRIP_SUFFIX.replace("{}", r#""name""#),
+        within = within_sql("$2"),
+    );
+    let rows = resources
+        .pg
+        .query(
+            &sql,
+            &[&query.names, &query.within, &prefixes, &MAX_CANDIDATES],
+        )
+        .await?;
+
+    if rows.is_empty() {
+        return Ok(ToolOutput::new(format!(
+            "No administrative areas matched `{}`. Check the name, or try the name without the larger areas it is in.",
+            place
+        ))
+        .with_sidecar(ChatterMessageSidecar::DatabaseLookup));
+    }
+
+    let candidates: Vec<PlaceCandidate> = rows
+        .iter()
+        .map(|row| {
+            let level: String = row.get(0);
+            let prefecture: String = row.get(2);
+            let municipality: Option<String> = row.get(5);
+            let code: Option<String> = row.get(7);
+            let hierarchy = [
+                Some(prefecture.clone()),
+                row.get(3),
+                row.get(4),
+                municipality.clone(),
+                row.get(6),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            let boundary_sql = boundary_sql(
+                &level,
+                &prefecture,
+                municipality.as_deref(),
+                code.as_deref(),
+            );
+            PlaceCandidate {
+                level,
+                name: row.get(1),
+                hierarchy,
+                code,
+                score: row.get(8),
+                bbox: [row.get(9), row.get(10), row.get(11), row.get(12)],
+                centroid: [row.get(13), row.get(14)],
+                boundary_sql,
+            }
+        })
+        .collect();
+
+    Ok(
+        ToolOutput::new(serde_json::to_string_pretty(&candidates).unwrap())
+            .with_sidecar(ChatterMessageSidecar::DatabaseLookup),
+    )
+}
+
+/// A condition that the area is in every one of the larger areas in the SQL parameter
+/// `within`: each of them must be the area's prefecture or municipality.
+fn within_sql(within: &str) -> String {
+    format!(
+        r#"NOT EXISTS (
+            SELECT 1 FROM unnest({within}::text[]) AS "w"("name")
+                WHERE NOT coalesce("w"."name" IN ("prefecture", "municipality", {prefecture}, {municipality}), false)
+        )"#,
+        prefecture = STRIP_SUFFIX.replace("{}", r#""prefecture""#),
+        municipality = STRIP_SUFFIX.replace("{}", r#""municipality""#),
+    )
+}
+
+#[derive(Serialize)]
+struct PlaceCandidate {
+    /// `prefecture`, `municipality` or `ward`
+    level: String,
+    name: String,
+    /// From the prefecture down to the place itself
+    hierarchy: Vec<String>,
+    /// 全国地方公共団体コード. `None` for designated cities, whose wards have the codes.
+    code: Option<String>,
+    score: f64,
+    /// `[min lon, min lat, max lon, max lat]`
+    bbox: [f64; 4],
+    /// `[lon, lat]`
+    centroid: [f64; 2],
+    /// Selects the boundary as a single `geom`
+    boundary_sql: String,
+}
+
+fn boundary_sql(
+    level: &str,
+    prefecture: &str,
+    municipality: Option<&str>,
+    code: Option<&str>,
+) -> String {
+    let condition = match (level, municipality, code) {
+        ("ward", _, Some(code)) => format!(r#""全国地方公共団体コード" = {}"#, quote_literal(code)),
+        ("municipality", Some(municipality), _) => format!(
+            r#""都道府県名" = {} AND "市区町村名" = {}"#,
+            quote_literal(prefecture),
+            quote_literal(municipality)
+        ),
+        _ => format!(r#""都道府県名" = {}"#, quote_literal(prefecture)),
+    };
+    format!(
+        r#"SELECT ST_Multi(ST_Union("geom")) AS "geom" FROM "n03_union" WHERE {}"#,
+        condition
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary_sql() {
+        assert_eq!(
+            boundary_sql("municipality", "東京都", Some("渋谷区"), Some("13113")),
+            r#"SELECT ST_Multi(ST_Union("geom")) AS "geom" FROM "n03_union" WHERE "都道府県名" = '東京都' AND "市区町村名" = '渋谷区'"#
+        );
+        assert_eq!(
+            boundary_sql("ward", "神奈川県", Some("横浜市"), Some("14104")),
+            r#"SELECT ST_Multi(ST_Union("geom")) AS "geom" FROM "n03_union" WHERE "全国地方公共団体コード" = '14104'"#
+        );
+        assert_eq!(
+            boundary_sql("prefecture", "東京都", None, Some("13000")),
+            r#"SELECT ST_Multi(ST_Union("geom")) AS "geom" FROM "n03_union" WHERE "都道府県名" = '東京都'"#
+        );
+    }
+
+    /// A ward in one designated city isn't found in another city of the same prefecture
+    /// that has a ward of the same name. Uses the database at `POSTGRES_CONN_STR_TEST`.
+    #[tokio::test]
+    async fn test_within_sql() {
+        let connect_str = std::env::var("POSTGRES_CONN_STR_TEST").unwrap();
+        let (client, connection) = tokio_postgres::connect(&connect_str, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let sql = format!(
+            r#"
+                SELECT "municipality" FROM (VALUES
+                    ('大阪府', '大阪市'),
+                    ('大阪府', '堺市'),
+                    ('大阪府', NULL)
+                ) AS "areas"("prefecture", "municipality")
+                WHERE {}
+                ORDER BY 1
+            "#,
+            within_sql("$1")
+        );
+        let within = |names: &[&str]| {
+            let sql = &sql;
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            let client = &client;
+            async move {
+                let rows = client.query(sql, &[&names]).await.unwrap();
+                rows.iter()
+                    .map(|row| row.get(0))
+                    .collect::<Vec<Option<String>>>()
+            }
+        };
+
+        let query = crate::places::parse_place_query("大阪府堺市北区").unwrap();
+        let names: Vec<&str> = query.within.iter().map(String::as_str).collect();
+        assert_eq!(within(&names).await, vec![Some("堺市".to_string())]);
+        assert_eq!(
+            within(&["大阪", "堺"]).await,
+            vec![Some("堺市".to_string())]
+        );
+        assert_eq!(within(&["大阪府"]).await.len(), 3);
+        assert_eq!(within(&[]).await.len(), 3);
+        assert!(within(&["京都府"]).await.is_empty());
+    }
+}
@@ -1,3 +1,10 @@
+pub mod aggregate_to_mesh;
+pub mod convert_mesh_code;
 pub mod describe_tables;
+pub mod geocode_place;
 pub mod query_database;
 pub mod request_unavailable_data;
+pub mod resolve_municipality_code;
+pub mod reverse_geocode;
+pub mod sample_column_values;
+pub mod search_datasets;
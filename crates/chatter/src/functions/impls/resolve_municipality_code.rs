@@ -0,0 +1,40 @@
+use crate::chatter_message::ChatterMessageSidecar;
+use crate::error::{ChatterError, Result};
+use crate::functions::{SharedResources, ToolOutput};
+use crate::municipality_codes::{normalize_code, resolve_code};
+use chatter_macros::llm_function;
+use chrono::NaiveDate;
+
+/// Look up a municipality code (全国地方公共団体コード) in the history of municipal mergers. Returns the codes it became, the changes in between with their effective dates, and the codes it replaced.
+/// Codes can have 5 digits, or 6 with the check digit, which is validated.
+/// Use this when datasets from different years use different codes. To join them in SQL, map old codes to current ones with `LEFT JOIN "municipality_code_current" AS "m" ON "m"."code" = <5 digit code>`, and use `COALESCE("m"."current_code", <5 digit code>)`.
+/// A code that was split (分割) became several codes, so the join repeats its rows, one for each, with `"m"."split"` true. Don't add up values over these rows; handle them separately, or leave them out with `WHERE "m"."split" IS NOT TRUE`.
+// Only reads from the database.
+#[llm_function(concurrency_safe, access = read)]
+async fn resolve_municipality_code(
+    resources: &SharedResources,
+    /// The municipality code.
+    code: String,
+    /// Follow the changes up to this date (`YYYY-MM-DD`). Pass an empty string to follow them up to now.
+    as_of: String,
+) -> Result<ToolOutput> {
+    let code = normalize_code(&code).map_err(ChatterError::InvalidToolCall)?;
+    let as_of = if as_of.is_empty() {
+        None
+    } else {
+        Some(NaiveDate::parse_from_str(&as_of, "%Y-%m-%d").map_err(|_| {
+            ChatterError::InvalidToolCall(format!("`{}` is not a date (YYYY-MM-DD).", as_of))
+        })?)
+    };
+
+    let Some(resolution) = resolve_code(&resources.pg, &code, as_of).await? else {
+        return Err(ChatterError::InvalidToolCall(format!(
+            "`{}` is not a municipality code that exists or has existed. Check the code, or find it with `geocode_place`.",
+            code
+        )));
+    };
+    Ok(
+        ToolOutput::new(serde_json::to_string_pretty(&resolution).unwrap())
+            .with_sidecar(ChatterMessageSidecar::DatabaseLookup),
+    )
+}
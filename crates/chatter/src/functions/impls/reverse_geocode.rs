@@ -0,0 +1,107 @@
+use crate::chatter_message::ChatterMessageSidecar;
+use crate::error::{ChatterError, Result};
+use crate::functions::{SharedResources, ToolOutput};
+use chatter_macros::llm_function;
+use serde::Serialize;
+
+/// The administrative area a point is in.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AdministrativeArea {
+    pub prefecture: String,
+    /// 北海道の振興局名, in Hokkaido
+    pub subprefecture: Option<String>,
+    pub county: Option<String>,
+    pub municipality: Option<String>,
+    /// The ward of a designated city
+    pub ward: Option<String>,
+    /// 全国地方公共団体コード
+    pub code: String,
+}
+
+/// Check that a longitude and latitude are valid coordinates.
+pub fn validate_coordinates(longitude: f64, latitude: f64) -> std::result::Result<(), String> {
+    if !(-180.0..=180.0).contains(&longitude) || !(-90.0..=90.0).contains(&latitude) {
+        return Err(format!(
+            "({}, {}) is not a valid longitude and latitude.",
+            longitude, latitude
+        ));
+    }
+    Ok(())
+}
+
+/// The administrative area that contains the point, or `None` if there isn't one (the
+/// point is at sea, or outside of Japan).
+pub async fn find_administrative_area(
+    client: &tokio_postgres::Client,
+    longitude: f64,
+    latitude: f64,
+) -> Result<Option<AdministrativeArea>> {
+    let row = client
+        .query_opt(
+            r#"
+                WITH "point" AS (
+                    -- In the SRID of the boundaries, so the spatial index can be used
+                    SELECT ST_SetSRID(
+                        ST_MakePoint($1, $2),
+                        (SELECT ST_SRID("geom") FROM "n03_union" LIMIT 1)
+                    ) AS "geom"
+                )
+                SELECT
+                    "都道府県名",
+                    "北海道の振興局名",
+                    "郡名",
+                    "市区町村名",
+                    "政令指定都市の行政区域名",
+                    "全国地方公共団体コード"
+                FROM "n03_union", "point"
+                WHERE ST_Intersects("n03_union"."geom", "point"."geom")
+                ORDER BY "全国地方公共団体コード"
+                LIMIT 1
+            "#,
+            &[&longitude, &latitude],
+        )
+        .await?;
+    Ok(row.map(|row| AdministrativeArea {
+        prefecture: row.get(0),
+        subprefecture: row.get(1),
+        county: row.get(2),
+        municipality: row.get(3),
+        ward: row.get(4),
+        code: row.get(5),
+    }))
+}
+
+/// Find the prefecture, municipality and ward that contain a point, with the code of the area.
+/// Use this when the user gives coordinates or points at a location on the map.
+// Only reads from the database.
+#[llm_function(concurrency_safe, access = read)]
+async fn reverse_geocode(
+    resources: &SharedResources,
+    /// The longitude of the point (WGS84).
+    longitude: f64,
+    /// The latitude of the point (WGS84).
+    latitude: f64,
+) -> Result<ToolOutput> {
+    validate_coordinates(longitude, latitude).map_err(ChatterError::InvalidToolCall)?;
+
+    let out = match find_administrative_area(&resources.pg, longitude, latitude).await? {
+        Some(area) => serde_json::to_string_pretty(&area).unwrap(),
+        None => format!(
+            "({}, {}) is not in any administrative area. It may be at sea or outside of Japan. Check that the longitude and latitude are not swapped.",
+            longitude, latitude
+        ),
+    };
+    Ok(ToolOutput::new(out).with_sidecar(ChatterMessageSidecar::DatabaseLookup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_coordinates() {
+        assert!(validate_coordinates(139.7, 35.66).is_ok());
+        assert!(validate_coordinates(35.66, 139.7).is_err());
+        assert!(validate_coordinates(f64::NAN, 35.66).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::chatter_message::ChatterMessageSidecar;
+use crate::error::{ChatterError, Result};
+use crate::functions::{SharedResources, ToolOutput, normalize_text, normalize_translation};
+use crate::pg_helpers::quote_identifier;
+use chatter_macros::llm_function;
+
+/// The most frequent values shown
+const TOP_VALUES: i64 = 20;
+/// The fuzzy matches shown
+const MAX_MATCHES: i64 = 10;
+/// `pg_trgm` similarity below which a value isn't a match, unless it contains the search text
+const MIN_SIMILARITY: f64 = 0.2;
+/// Longer values are cut short, so a column of long text doesn't fill the context
+const MAX_VALUE_CHARS: i32 = 100;
+/// Columns whose values can't be read as text
+const UNSAMPLED_TYPES: &[&str] = &["geometry", "geography", "raster", "bytea"];
+
+/// Show the distinct values of a column, with how often they appear, to find out how the data is written.
+/// Use `search` to find values similar to a name (for example, to find how a place name is written before using it in a WHERE clause).
+/// Values are in Japanese, so translate names to Japanese before searching (for example, "渋谷" for "Shibuya").
+// Only reads from the database. Counting the values of a large table can take a while.
+#[llm_function(concurrency_safe, timeout_secs = 60, access = read)]
+async fn sample_column_values(
+    resources: &SharedResources,
+    /// The table, as listed by `search_datasets` or in the system prompt.
+    table_name: String,
+    /// The column to sample.
+    column_name: String,
+    /// Text to find similar values to. Hiragana and katakana, and full-width and half-width letters, digits and katakana, are treated as the same. Pass an empty string to only get the most frequent values.
+    search: String,
+) -> Result<ToolOutput> {
+    let pg = &resources.pg;
+    // Only the datasets we offer, not system tables or other tables in the database
+    let listed = pg
+        .query_opt(
+            r#"SELECT 1 FROM "datasets" WHERE "table_name" = $1"#,
+            &[&table_name],
+        )
+        .await?;
+    if listed.is_none() {
+        return Err(ChatterError::InvalidToolCall(format!(
+            "The table `{}` does not exist. Use `search_datasets` to find tables.",
+            table_name
+        )));
+    }
+    // `information_schema.columns` doesn't include materialized views, such as `n03_union`
+    let column = pg
+        .query_opt(
+            r#"
+                SELECT format_type(atttypid, NULL) FROM pg_attribute
+                    WHERE attrelid = to_regclass(quote_ident($1))
+                        AND attname = $2
+                        AND attnum > 0
+                        AND NOT attisdropped
+            "#,
+            &[&table_name, &column_name],
+        )
+        .await?;
+    let Some(column) = column else {
+        return Err(ChatterError::InvalidToolCall(format!(
+            "The column `{}` does not exist in `{}`. Use `describe_tables` to see its columns.",
+            column_name, table_name
+        )));
+    };
+    let data_type: String = column.get(0);
+    if UNSAMPLED_TYPES.contains(&data_type.as_str()) {
+        return Err(ChatterError::InvalidToolCall(format!(
+            "The column `{}` is of type `{}`, which can't be sampled as text. Query it with SQL instead.",
+            column_name, data_type
+        )));
+    }
+
+    let values = format!(
+        r#"SELECT {column}::text AS "value", COUNT(*) AS "count" FROM {table} WHERE {column} IS NOT NULL GROUP BY 1"#,
+        column = quote_identifier(&column_name),
+        table = quote_identifier(&table_name),
+    );
+    // Only the start of long values is sent back
+    let shown = r#"CASE WHEN length("value") > $1 THEN left("value", $1) || '…' ELSE "value" END"#;
+
+    // The number of distinct values is counted in the same pass as the most frequent ones
+    let rows = pg
+        .query(
+            &format!(
+                r#"SELECT {}, "count", COUNT(*) OVER () FROM ({}) AS v ORDER BY "count" DESC, "value" LIMIT $2"#,
+                shown, values
+            ),
+            &[&MAX_VALUE_CHARS, &TOP_VALUES],
+        )
+        .await?;
+    let distinct: i64 = rows.first().map_or(0, |row| row.get(2));
+    let mut out = format!(
+        "Column `{}`.`{}` has {} distinct values.\n",
+        table_name, column_name, distinct
+    );
+    out.push_str("\nMost frequent values:\n");
+    for row in &rows {
+        let value: String = row.get(0);
+        let count: i64 = row.get(1);
+        out.push_str(&format!("- `{}` ({})\n", value, count));
+    }
+
+    let search = normalize_text(search.trim());
+    if !search.is_empty() {
+        let (from, to) = normalize_translation();
+        // Similarity of trigrams doesn't work well on short Japanese strings, so values that
+        // contain the search text are matches too, and come first.
+        let rows = pg
+            .query(
+                &format!(
+                    r#"
+                        WITH "n" AS (
+                            SELECT
+                                "value",
+                                "count",
+                                lower(normalize(translate("value", $2, $3), NFC)) AS "normalized"
+                            FROM ({}) AS v
+                        )
+                        SELECT
+                            {},
+                            "count",
+                            similarity("normalized", $4)::float8 AS "similarity"
+                        FROM "n"
+                        WHERE strpos("normalized", $4) > 0 OR similarity("normalized", $4) > $5::float8
+                        ORDER BY strpos("normalized", $4) > 0 DESC, 3 DESC, "count" DESC
+                        LIMIT $6
+                    "#,
+                    values, shown
+                ),
+                &[
+                    &MAX_VALUE_CHARS,
+                    &from,
+                    &to,
+                    &search,
+                    &MIN_SIMILARITY,
+                    &MAX_MATCHES,
+                ],
+            )
+            .await?;
+        if rows.is_empty() {
+            out.push_str(&format!(
+                "\nNo values are similar to `{}`. Check the spelling, or whether the data is in another column.\n",
+                search
+            ));
+        } else {
+            out.push_str(&format!("\nValues similar to `{}`:\n", search));
+            for row in &rows {
+                let value: String = row.get(0);
+                let count: i64 = row.get(1);
+                let similarity: f64 = row.get(2);
+                out.push_str(&format!(
+                    "- `{}` ({}, similarity {:.2})\n",
+                    value, count, similarity
+                ));
+            }
+        }
+    }
+
+    Ok(ToolOutput::new(out).with_sidecar(ChatterMessageSidecar::DatabaseLookup))
+}
@@ -0,0 +1,348 @@
+use crate::chatter_message::ChatterMessageSidecar;
+use crate::error::Result;
+use crate::functions::{SharedResources, ToolOutput, normalize_chars};
+use chatter_macros::llm_function;
+use km_to_sql::metadata::TableMetadata;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const MAX_RESULTS: usize = 10;
+/// Matching columns shown per table
+const MAX_COLUMNS: usize = 5;
+
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+// How much a term counts in each field. The human name says the most about what a table is.
+const NAME_WEIGHT: f64 = 3.0;
+const TABLE_NAME_WEIGHT: f64 = 2.0;
+const DESC_WEIGHT: f64 = 1.0;
+const COLUMN_WEIGHT: f64 = 0.5;
+
+/// How long an index is used before it is built again, so new datasets can be found.
+const INDEX_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The index, and when it was built. Building it reads the metadata of every table, so it
+/// is shared by all calls. Locked while the index is built, so it is only built once.
+static INDEX: Mutex<Option<(Instant, Arc<DatasetIndex>)>> = Mutex::const_new(None);
+
+/// Search for tables by keywords. Returns the best matching tables with their descriptions.
+/// Use this to find tables that are relevant to the request, then use `describe_tables` to get their columns.
+// Only reads from the database.
+#[llm_function(concurrency_safe, access = read)]
+async fn search_datasets(
+    resources: &SharedResources,
+    /// Keywords to search for, separated by spaces. Use Japanese keywords, because the metadata is in Japanese (for example, "学校 小学校" for elementary schools).
+    query: String,
+) -> Result<ToolOutput> {
+    let index = dataset_index(&resources.pg).await?;
+    let results = index.search(&query, MAX_RESULTS);
+
+    if results.is_empty() {
+        return Ok(ToolOutput::new(format!(
+            "No tables matched `{}`. Try other keywords. If the data is not available, request it with `request_unavailable_data`.",
+            query
+        ))
+        .with_sidecar(ChatterMessageSidecar::DatabaseLookup));
+    }
+
+    let terms: HashSet<String> = tokenize(&query).into_iter().collect();
+    let mut out = String::new();
+    for (table_name, metadata) in results {
+        out.push_str(&format!(
+            "- `{}` (for humans: {})",
+            table_name, metadata.name
+        ));
+        if let Some(desc) = &metadata.desc {
+            out.push_str(&format!(": {}", desc));
+        }
+        out.push('\n');
+        let columns: Vec<String> = metadata
+            .columns
+            .iter()
+            .filter(|column| {
+                column_text(column)
+                    .iter()
+                    .flat_map(|text| index_terms(text))
+                    .any(|token| terms.contains(&token))
+            })
+            .take(MAX_COLUMNS)
+            .map(|column| format!("`{}`", column.name))
+            .collect();
+        if !columns.is_empty() {
+            out.push_str(&format!("  - Matching columns: {}\n", columns.join(", ")));
+        }
+    }
+
+    Ok(ToolOutput::new(out).with_sidecar(ChatterMessageSidecar::DatabaseLookup))
+}
+
+/// The index of every table in `datasets`. Built when it is first needed, and again once it
+/// is older than `INDEX_TTL`.
+async fn dataset_index(client: &deadpool_postgres::Client) -> Result<Arc<DatasetIndex>> {
+    let mut cached = INDEX.lock().await;
+    if let Some((built, index)) = cached.as_ref()
+        && built.elapsed() < INDEX_TTL
+    {
+        return Ok(index.clone());
+    }
+    let index = Arc::new(DatasetIndex::new(load_datasets(client).await?));
+    *cached = Some((Instant::now(), index.clone()));
+    Ok(index)
+}
+
+/// Every table in `datasets`, with its metadata.
+pub async fn load_datasets(
+    client: &deadpool_postgres::Client,
+) -> Result<Vec<(String, TableMetadata)>> {
+    let rows = client
+        .query(
+            r#"
+                SELECT "table_name" FROM "datasets"
+                    ORDER BY "table_name" ASC
+            "#,
+            &[],
+        )
+        .await?;
+    let table_names: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    let table_name_refs: Vec<&str> = table_names.iter().map(|s| s.as_str()).collect();
+    Ok(km_to_sql::postgres::get(client, &table_name_refs).await?)
+}
+
+fn column_text(column: &km_to_sql::metadata::ColumnMetadata) -> Vec<&str> {
+    let mut text = vec![column.name.as_str()];
+    if let Some(desc) = &column.desc {
+        text.push(desc.as_str());
+    }
+    text
+}
+
+/// Split text into search terms. Japanese isn't separated by spaces, so runs of non-ASCII
+/// letters are split into overlapping pairs of characters (bigrams), which match words
+/// without a dictionary. ASCII is split into lowercase words. Characters are normalized with
+/// `normalize_chars` first.
+pub fn tokenize(text: &str) -> Vec<String> {
+    split_terms(text, false)
+}
+
+/// Like `tokenize`, but each non-ASCII character is also a term of its own, so that
+/// one-character queries (`駅`) match.
+fn index_terms(text: &str) -> Vec<String> {
+    split_terms(text, true)
+}
+
+fn split_terms(text: &str, unigrams: bool) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    let mut run: Vec<char> = vec![];
+
+    let flush_run = |run: &mut Vec<char>, tokens: &mut Vec<String>| {
+        if unigrams || run.len() == 1 {
+            tokens.extend(run.iter().map(|c| c.to_string()));
+        }
+        for pair in run.windows(2) {
+            tokens.push(pair.iter().collect());
+        }
+        run.clear();
+    };
+
+    for c in normalize_chars(text).chars() {
+        if c.is_ascii_alphanumeric() {
+            flush_run(&mut run, &mut tokens);
+            word.push(c.to_ascii_lowercase());
+        } else if c.is_alphanumeric() || c == 'ー' {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            run.push(c);
+        } else {
+            flush_run(&mut run, &mut tokens);
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+        }
+    }
+    flush_run(&mut run, &mut tokens);
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+struct Document {
+    table_name: String,
+    metadata: TableMetadata,
+    /// Weighted term frequencies
+    terms: HashMap<String, f64>,
+    length: f64,
+}
+
+/// A BM25 index over the metadata of the datasets.
+pub struct DatasetIndex {
+    documents: Vec<Document>,
+    /// The number of documents each term appears in
+    document_frequency: HashMap<String, usize>,
+    average_length: f64,
+}
+
+impl DatasetIndex {
+    pub fn new(tables: Vec<(String, TableMetadata)>) -> Self {
+        let mut documents = vec![];
+        let mut document_frequency: HashMap<String, usize> = HashMap::new();
+        for (table_name, metadata) in tables {
+            let mut fields: Vec<(&str, f64)> = vec![
+                (table_name.as_str(), TABLE_NAME_WEIGHT),
+                (metadata.name.as_str(), NAME_WEIGHT),
+            ];
+            if let Some(desc) = &metadata.desc {
+                fields.push((desc.as_str(), DESC_WEIGHT));
+            }
+            for column in &metadata.columns {
+                for text in column_text(column) {
+                    fields.push((text, COLUMN_WEIGHT));
+                }
+            }
+
+            let mut terms: HashMap<String, f64> = HashMap::new();
+            let mut length = 0.0;
+            for (text, weight) in fields {
+                for token in index_terms(text) {
+                    *terms.entry(token).or_default() += weight;
+                    length += weight;
+                }
+            }
+            for term in terms.keys() {
+                *document_frequency.entry(term.clone()).or_default() += 1;
+            }
+            documents.push(Document {
+                table_name,
+                metadata,
+                terms,
+                length,
+            });
+        }
+        let average_length = if documents.is_empty() {
+            0.0
+        } else {
+            documents.iter().map(|d| d.length).sum::<f64>() / documents.len() as f64
+        };
+        Self {
+            documents,
+            document_frequency,
+            average_length,
+        }
+    }
+
+    /// The tables that best match `query`, best first. Tables that match none of the terms
+    /// are left out.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<(&str, &TableMetadata)> {
+        let terms: HashSet<String> = tokenize(query).into_iter().collect();
+        let count = self.documents.len() as f64;
+
+        let mut scored: Vec<(f64, &Document)> = self
+            .documents
+            .iter()
+            .filter_map(|document| {
+                let mut score = 0.0;
+                for term in &terms {
+                    let Some(&tf) = document.terms.get(term) else {
+                        continue;
+                    };
+                    let df = self.document_frequency[term] as f64;
+                    let idf = (1.0 + (count - df + 0.5) / (df + 0.5)).ln();
+                    let norm = 1.0 - B + B * document.length / self.average_length;
+                    score += idf * tf * (K1 + 1.0) / (tf + K1 * norm);
+                }
+                (score > 0.0).then_some((score, document))
+            })
+            .collect();
+        scored.sort_by(|a, b| {
+            b.0.total_cmp(&a.0)
+                .then_with(|| a.1.table_name.cmp(&b.1.table_name))
+        });
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, document)| (document.table_name.as_str(), &document.metadata))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table(name: &str, desc: &str, columns: &[(&str, &str)]) -> TableMetadata {
+        let columns: Vec<_> = columns
+            .iter()
+            .map(|(name, desc)| json!({ "name": name, "desc": desc, "data_type": "varchar" }))
+            .collect();
+        serde_json::from_value(json!({ "name": name, "desc": desc, "columns": columns })).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("小学校"), vec!["小学", "学校"]);
+        assert_eq!(tokenize("駅 P29"), vec!["駅", "p29"]);
+        assert_eq!(tokenize("ＡＢＣ・鉄道駅"), vec!["abc", "鉄道", "道駅"]);
+        assert_eq!(tokenize("school_code"), vec!["school", "code"]);
+        assert_eq!(tokenize("バス停留所"), vec!["バス", "ス停", "停留", "留所"]);
+        assert_eq!(index_terms("駅名"), vec!["駅", "名", "駅名"]);
+    }
+
+    #[test]
+    fn test_search() {
+        let index = DatasetIndex::new(vec![
+            (
+                "p29".to_string(),
+                table(
+                    "学校",
+                    "小学校、中学校、高等学校などの位置",
+                    &[("p29_004", "学校分類")],
+                ),
+            ),
+            (
+                "n02".to_string(),
+                table("鉄道", "鉄道路線と駅", &[("n02_005", "駅名")]),
+            ),
+            (
+                "p04".to_string(),
+                table(
+                    "医療機関",
+                    "病院、診療所の位置",
+                    &[("p04_001", "医療機関分類")],
+                ),
+            ),
+        ]);
+
+        let results: Vec<&str> = index
+            .search("小学校", 10)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect();
+        assert_eq!(results, vec!["p29"]);
+
+        let results: Vec<&str> = index.search("駅", 10).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(results, vec!["n02"]);
+
+        let results: Vec<&str> = index
+            .search("N02", 10)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect();
+        assert_eq!(results, vec!["n02"]);
+
+        let results: Vec<&str> = index
+            .search("医療機関 学校", 10)
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect();
+        assert_eq!(results.len(), 2);
+
+        assert!(index.search("空港", 10).is_empty());
+    }
+}
@@ -1,41 +1,80 @@
 //! LLM functions that will be called by the LLM runtime.
 
 use crate::chatter_context::ChatterContext;
-use crate::chatter_message::ChatterMessage;
+use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
 use crate::data::dynamodb::Db;
-use crate::error::Result;
+use crate::error::{ChatterError, Result};
 use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+mod http;
 mod impls;
+mod profile;
 mod utils;
 
+pub use http::{HttpFunction, HttpToolConfig, HttpToolsConfig};
+pub use impls::aggregate_to_mesh::AggregateToMeshFunction;
+pub use impls::convert_mesh_code::ConvertMeshCodeFunction;
 pub use impls::describe_tables::DescribeTablesFunction;
+pub use impls::geocode_place::GeocodePlaceFunction;
 pub use impls::query_database::QueryDatabaseFunction;
 pub use impls::request_unavailable_data::RequestUnavailableDataFunction;
-pub use utils::format_column;
+pub use impls::resolve_municipality_code::ResolveMunicipalityCodeFunction;
+pub use impls::reverse_geocode::{
+    AdministrativeArea, ReverseGeocodeFunction, find_administrative_area, validate_coordinates,
+};
+pub use impls::sample_column_values::SampleColumnValuesFunction;
+pub use impls::search_datasets::SearchDatasetsFunction;
+pub use profile::{ToolAccess, ToolProfile};
+pub use utils::{
+    format_column, normalize_char, normalize_chars, normalize_text, normalize_translation,
+};
 
 /// Shared resources needed by functions
 #[derive(Clone)]
 pub struct SharedResources {
     pub chatter_context: Arc<Mutex<ChatterContext>>,
+    /// The connection of the call. Each call gets its own, so it can be cancelled alone.
     pub pg: Arc<deadpool_postgres::Client>,
+    /// For functions that run queries in parallel.
+    pub pg_pool: deadpool_postgres::Pool,
     pub ddb: Arc<Db>,
 }
 
+/// How long a function may run, unless it declares its own timeout.
+pub const DEFAULT_TOOL_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Trait defining the interface for LLM functions
 pub trait LlmFunction: Send + Sync {
     /// Get the function name
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
 
     /// Get the function description
-    fn description(&self) -> &'static str;
+    fn description(&self) -> &str;
 
     /// Get the function parameters schema
     fn parameters_schema(&self) -> serde_json::Value;
 
+    /// Whether this function may run at the same time as other calls in the same response.
+    /// Functions with side effects that depend on the order of calls should return `false`.
+    fn concurrency_safe(&self) -> bool {
+        false
+    }
+
+    /// How long a call may run before it is abandoned. Can be overridden per function with
+    /// `TurnLimits::tool_timeouts_secs`.
+    fn timeout(&self) -> Duration {
+        DEFAULT_TOOL_TIMEOUT
+    }
+
+    /// What the function can do. Only profiles that allow this access level can use it.
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+
     /// Get the tool definition
     fn tool(&self) -> ChatCompletionTool {
         ChatCompletionTool {
@@ -62,47 +101,236 @@ pub trait LlmFunctionExecutor: Send + Sync {
     ) -> Result<ChatterMessage>;
 }
 
+/// What a function defined with `#[llm_function]` returns: the message for the model, and
+/// the sidecar shown to the user.
+#[derive(Clone, Debug)]
+pub struct ToolOutput {
+    pub message: String,
+    pub sidecar: ChatterMessageSidecar,
+}
+
+impl ToolOutput {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    pub fn with_sidecar(mut self, sidecar: ChatterMessageSidecar) -> Self {
+        self.sidecar = sidecar;
+        self
+    }
+
+    /// The tool response message for the call `tool_call_id`.
+    pub fn into_message(self, tool_call_id: String) -> ChatterMessage {
+        ChatterMessage {
+            message: Some(self.message),
+            role: Role::Tool,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id),
+            sidecar: self.sidecar,
+        }
+    }
+}
+
+impl From<String> for ToolOutput {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
 /// Combined trait for LLM functions that can be used as a trait object
 pub trait LlmFunctionTrait: LlmFunction + LlmFunctionExecutor {}
 impl<T: LlmFunction + LlmFunctionExecutor> LlmFunctionTrait for T {}
 
+/// A registered function, with its compiled parameters schema.
+struct RegisteredFunction {
+    function: Arc<dyn LlmFunctionTrait>,
+    validator: jsonschema::Validator,
+}
+
 /// Registry for managing and dispatching LLM functions
 pub struct FunctionRegistry {
-    functions: HashMap<String, Arc<dyn LlmFunctionTrait>>,
+    /// By name, so the tools are always listed in the same order. The prompt cache and
+    /// replayed transcripts depend on it.
+    functions: BTreeMap<String, RegisteredFunction>,
 }
 
 impl FunctionRegistry {
     /// Create a new function registry
     pub fn new() -> Self {
         Self {
-            functions: HashMap::new(),
+            functions: BTreeMap::new(),
+        }
+    }
+
+    /// The built-in functions, and the HTTP tools declared in the file at
+    /// `CHATTER_HTTP_TOOLS`, if it is set.
+    pub fn from_env() -> Result<Self> {
+        let mut registry = Self::new();
+        registry.register(DescribeTablesFunction);
+        registry.register(QueryDatabaseFunction);
+        registry.register(RequestUnavailableDataFunction);
+        registry.register(SearchDatasetsFunction);
+        registry.register(SampleColumnValuesFunction);
+        registry.register(GeocodePlaceFunction);
+        registry.register(ReverseGeocodeFunction);
+        registry.register(AggregateToMeshFunction);
+        registry.register(ConvertMeshCodeFunction);
+        registry.register(ResolveMunicipalityCodeFunction);
+        // Tools served by other services, declared in a config file
+        if let Ok(path) = std::env::var("CHATTER_HTTP_TOOLS") {
+            registry.load_http_tools(&path)?;
         }
+        Ok(registry)
     }
 
     /// Register a function with the registry
+    ///
+    /// Panics if the function's parameters schema is not a valid JSON schema.
     pub fn register<F: LlmFunctionTrait + 'static>(&mut self, function: F) {
+        self.try_register(Arc::new(function))
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    /// Register a function, returning an error if its parameters schema is invalid or
+    /// another function with the same name has already been registered.
+    pub fn try_register(&mut self, function: Arc<dyn LlmFunctionTrait>) -> Result<()> {
+        let name = function.name().to_string();
+        if self.functions.contains_key(&name) {
+            return Err(ChatterError::ToolConfigError(format!(
+                "A function named {} is already registered",
+                name
+            )));
+        }
+        let validator = jsonschema::validator_for(&function.parameters_schema()).map_err(|e| {
+            ChatterError::ToolConfigError(format!("Invalid parameters schema for {}: {}", name, e))
+        })?;
+        self.functions.insert(
+            name,
+            RegisteredFunction {
+                function,
+                validator,
+            },
+        );
+        Ok(())
+    }
+
+    /// Register the HTTP tools declared in the config file at `path`.
+    pub fn load_http_tools(&mut self, path: &str) -> Result<()> {
+        let config = HttpToolsConfig::from_file(path)?;
+        for tool in config.tools {
+            self.try_register(Arc::new(HttpFunction::new(tool)))?;
+        }
+        Ok(())
+    }
+
+    /// Get the functions available in `profile` as tools, sorted by name
+    pub fn get_tools(&self, profile: ToolProfile) -> Vec<ChatCompletionTool> {
         self.functions
-            .insert(function.name().to_string(), Arc::new(function));
+            .values()
+            .filter(|f| profile.allows(f.function.access()))
+            .map(|f| f.function.tool())
+            .collect()
     }
 
-    /// Get all registered functions as tools
-    pub fn get_tools(&self) -> Vec<ChatCompletionTool> {
-        self.functions.values().map(|f| f.tool()).collect()
+    /// Whether the function can run concurrently with other tool calls.
+    /// Unknown functions are treated as unsafe.
+    pub fn is_concurrency_safe(&self, function_name: &str) -> bool {
+        self.functions
+            .get(function_name)
+            .is_some_and(|f| f.function.concurrency_safe())
+    }
+
+    /// Look up a function and check the arguments the model sent against its parameters
+    /// schema. The error describes exactly what is wrong, so the model can fix the call.
+    /// Functions that `profile` doesn't allow are refused.
+    fn validate(
+        &self,
+        profile: ToolProfile,
+        function_name: &str,
+        arguments: &str,
+    ) -> Result<(&dyn LlmFunctionTrait, serde_json::Value)> {
+        let registered = self
+            .functions
+            .get(function_name)
+            .filter(|f| profile.allows(f.function.access()));
+        let Some(registered) = registered else {
+            let mut names: Vec<&str> = self
+                .functions
+                .iter()
+                .filter(|(_, f)| profile.allows(f.function.access()))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            names.sort_unstable();
+            let reason = if self.functions.contains_key(function_name) {
+                "is not available in this thread"
+            } else {
+                "does not exist"
+            };
+            return Err(ChatterError::InvalidToolCall(format!(
+                "The function `{}` {}. Available functions: {}",
+                function_name,
+                reason,
+                names.join(", ")
+            )));
+        };
+
+        // Functions without parameters are sometimes called with no arguments at all
+        let arguments = if arguments.trim().is_empty() {
+            "{}"
+        } else {
+            arguments
+        };
+        let params: serde_json::Value = serde_json::from_str(arguments).map_err(|e| {
+            ChatterError::InvalidToolCall(format!(
+                "The arguments for `{}` are not valid JSON: {}",
+                function_name, e
+            ))
+        })?;
+
+        let errors: Vec<String> = registered
+            .validator
+            .iter_errors(&params)
+            .map(|error| {
+                let path = error.instance_path.to_string();
+                let path = if path.is_empty() { "/" } else { path.as_str() };
+                format!("- {}: {}", path, error)
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(ChatterError::InvalidToolCall(format!(
+                "The arguments for `{}` don't match its parameters schema:\n{}",
+                function_name,
+                errors.join("\n")
+            )));
+        }
+
+        Ok((registered.function.as_ref(), params))
     }
 
-    /// Execute a function by name
+    /// Execute a function by name. `arguments` is the JSON string sent by the model.
+    /// Unknown functions and invalid arguments are reported as `ChatterError::InvalidToolCall`.
+    /// The call is abandoned after `timeout`, or the function's own timeout if `None`.
+    /// Functions outside of `profile` are refused, even if the model calls them anyway.
     pub async fn execute(
         &self,
         resources: &SharedResources,
+        profile: ToolProfile,
         function_name: &str,
         tool_call_id: String,
-        params: serde_json::Value,
+        arguments: &str,
+        timeout: Option<Duration>,
     ) -> Result<ChatterMessage> {
-        let function = self.functions.get(function_name).ok_or_else(|| {
-            crate::error::ChatterError::FunctionNotFound(function_name.to_string())
-        })?;
-
-        function.execute(resources, tool_call_id, params).await
+        let (function, params) = self.validate(profile, function_name, arguments)?;
+        let timeout = timeout.unwrap_or_else(|| function.timeout());
+        with_timeout(
+            function_name,
+            timeout,
+            function.execute(resources, tool_call_id, params),
+        )
+        .await
     }
 }
 
@@ -111,3 +339,111 @@ impl Default for FunctionRegistry {
         Self::new()
     }
 }
+
+async fn with_timeout(
+    function_name: &str,
+    timeout: Duration,
+    future: impl Future<Output = Result<ChatterMessage>>,
+) -> Result<ChatterMessage> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(ChatterError::ToolTimeout {
+                tool: function_name.to_string(),
+                timeout,
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> FunctionRegistry {
+        let mut registry = FunctionRegistry::new();
+        registry.register(DescribeTablesFunction);
+        registry.register(QueryDatabaseFunction);
+        registry.register(RequestUnavailableDataFunction);
+        registry
+    }
+
+    fn validation_error(function_name: &str, arguments: &str) -> String {
+        match registry().validate(ToolProfile::Analysis, function_name, arguments) {
+            Err(ChatterError::InvalidToolCall(message)) => message,
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Expected {} to be invalid", arguments),
+        }
+    }
+
+    #[test]
+    fn test_validate_arguments() {
+        let registry = registry();
+        let (function, params) = registry
+            .validate(
+                ToolProfile::Analysis,
+                "describe_tables",
+                r#"{"table_names":["stations"]}"#,
+            )
+            .expect("Valid arguments were rejected");
+        assert_eq!(function.name(), "describe_tables");
+        assert_eq!(params["table_names"][0], "stations");
+
+        let message = validation_error("drop_tables", "{}");
+        assert!(message.contains("`drop_tables` does not exist"));
+        assert!(message.contains("describe_tables, query_database, request_unavailable_data"));
+
+        let message = validation_error("describe_tables", r#"{"table_names":"#);
+        assert!(message.contains("not valid JSON"));
+
+        let message = validation_error("describe_tables", r#"{"table_names":"stations"}"#);
+        assert!(message.contains("/table_names"));
+
+        let message = validation_error("query_database", r#"{"name":"Stations"}"#);
+        assert!(message.contains("query"));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(ChatterMessage::tool_error("call_1", "unreachable"))
+        };
+        let result = with_timeout("query_database", Duration::from_millis(10), slow).await;
+        let Err(ChatterError::ToolTimeout { tool, timeout }) = result else {
+            panic!("Expected a timeout");
+        };
+        assert_eq!(tool, "query_database");
+        assert_eq!(timeout, Duration::from_millis(10));
+
+        let fast = async { Ok(ChatterMessage::tool_error("call_1", "done")) };
+        let result = with_timeout("query_database", Duration::from_secs(1), fast).await;
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let registry = registry();
+        let names: Vec<String> = registry
+            .get_tools(ToolProfile::Explore)
+            .into_iter()
+            .map(|tool| tool.function.name)
+            .collect();
+        // Sorted by name
+        assert_eq!(names, vec!["describe_tables", "query_database"]);
+        assert_eq!(registry.get_tools(ToolProfile::Analysis).len(), 3);
+
+        let arguments = r#"{"name":"Bus stops","explanation":"To find nearby stops"}"#;
+        assert!(
+            registry
+                .validate(ToolProfile::Analysis, "request_unavailable_data", arguments)
+                .is_ok()
+        );
+        let Err(ChatterError::InvalidToolCall(message)) =
+            registry.validate(ToolProfile::Explore, "request_unavailable_data", arguments)
+        else {
+            panic!("Expected request_unavailable_data to be refused");
+        };
+        assert!(message.contains("not available in this thread"));
+        assert!(!message.contains("request_unavailable_data,"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Which tools a thread may use.
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::str::FromStr;
+
+/// What a tool can do. Ordered from least to most privileged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolAccess {
+    /// Only reads data.
+    Read,
+    /// Changes something outside of the conversation, such as filing a data request.
+    /// Tools are `Write` unless they declare otherwise, so a new tool doesn't show up in
+    /// read-only profiles by accident.
+    #[default]
+    Write,
+    /// For operators only.
+    Admin,
+}
+
+/// A named set of tools, stored on each thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolProfile {
+    /// Read-only exploration of the datasets.
+    Explore,
+    /// Every tool except administrative ones.
+    #[default]
+    Analysis,
+    /// Every tool.
+    Admin,
+}
+
+impl ToolProfile {
+    /// Whether tools with `access` are available in this profile.
+    pub fn allows(self, access: ToolAccess) -> bool {
+        let max = match self {
+            Self::Explore => ToolAccess::Read,
+            Self::Analysis => ToolAccess::Write,
+            Self::Admin => ToolAccess::Admin,
+        };
+        access <= max
+    }
+
+    /// The most permissive profile this deployment allows, from `TOOL_PROFILE`. Deployments
+    /// for external customers set it to `explore`. Defaults to `analysis`. An invalid value
+    /// falls back to `explore`, so a typo doesn't open up more tools.
+    pub fn max_from_env() -> Self {
+        match env::var("TOOL_PROFILE") {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                tracing::warn!("{}, falling back to explore", e);
+                Self::Explore
+            }),
+            Err(_) => Self::Analysis,
+        }
+    }
+
+    /// This profile, limited to what the deployment allows.
+    pub fn restrict_to_env(self) -> Self {
+        self.min(Self::max_from_env())
+    }
+}
+
+impl FromStr for ToolProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "explore" => Ok(Self::Explore),
+            "analysis" => Ok(Self::Analysis),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("Unknown tool profile: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        assert!(ToolProfile::Explore.allows(ToolAccess::Read));
+        assert!(!ToolProfile::Explore.allows(ToolAccess::Write));
+        assert!(ToolProfile::Analysis.allows(ToolAccess::Write));
+        assert!(!ToolProfile::Analysis.allows(ToolAccess::Admin));
+        assert!(ToolProfile::Admin.allows(ToolAccess::Admin));
+
+        assert_eq!(
+            ToolProfile::Admin.min(ToolProfile::Explore),
+            ToolProfile::Explore
+        );
+        assert_eq!("explore".parse(), Ok(ToolProfile::Explore));
+        assert!("everything".parse::<ToolProfile>().is_err());
+    }
+}
@@ -28,3 +28,98 @@ pub fn format_column(column: &ColumnMetadata) -> String {
     out.push('\n');
     out
 }
+
+/// Half-width katakana and punctuation (`U+FF61`..=`U+FF9F`), in order, as full-width. The
+/// half-width voiced marks become the combining ones, which `normalize_chars` joins with the
+/// kana before them.
+const FULL_WIDTH_KANA: &str = "。「」、・ヲァィゥェォャュョッーアイウエオカキクケコサシスセソタチツテトナニヌネノハヒフヘホマミムメモヤユヨラリルレロワン\u{3099}\u{309A}";
+
+/// Normalize a character for matching Japanese text: full-width ASCII (`Ａ１`) becomes ASCII,
+/// the ideographic space becomes a space, half-width katakana (`ｼ`) becomes full-width, and
+/// hiragana becomes katakana.
+pub fn normalize_char(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{FF61}'..='\u{FF9F}' => FULL_WIDTH_KANA
+            .chars()
+            .nth((c as u32 - 0xFF61) as usize)
+            .unwrap_or(c),
+        '\u{3000}' => ' ',
+        '\u{3041}'..='\u{3096}' => char::from_u32(c as u32 + 0x60).unwrap_or(c),
+        _ => c,
+    }
+}
+
+/// The katakana with a voiced (`\u{3099}`) or semi-voiced (`\u{309A}`) mark, as one
+/// character, as Unicode's NFC composes them.
+fn compose_voiced(kana: char, mark: char) -> Option<char> {
+    let offset = match mark {
+        '\u{3099}' if kana == 'ウ' => 'ヴ' as u32 - 'ウ' as u32,
+        '\u{3099}' if "カキクケコサシスセソタチツテトハヒフヘホヽ".contains(kana) => {
+            1
+        }
+        '\u{3099}' if "ワヰヱヲ".contains(kana) => 8,
+        '\u{309A}' if "ハヒフヘホ".contains(kana) => 2,
+        _ => return None,
+    };
+    char::from_u32(kana as u32 + offset)
+}
+
+/// `normalize_char` every character, and join voiced marks with the kana before them, so
+/// that half-width `ｼﾌﾞﾔ` becomes `シブヤ`.
+pub fn normalize_chars(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars().map(normalize_char) {
+        let composed = out
+            .chars()
+            .next_back()
+            .and_then(|kana| compose_voiced(kana, c));
+        match composed {
+            Some(composed) => {
+                out.pop();
+                out.push(composed);
+            }
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+/// `normalize_chars`, and lowercase.
+pub fn normalize_text(text: &str) -> String {
+    normalize_chars(text).to_lowercase()
+}
+
+/// The arguments to Postgres's `translate()` that do what `normalize_char` does, so values
+/// can be normalized in SQL: `lower(normalize(translate(value, $from, $to), NFC))`.
+/// `translate()` only replaces single characters, so `normalize(..., NFC)` joins the voiced
+/// marks, like `normalize_chars` does.
+pub fn normalize_translation() -> (String, String) {
+    ('\u{3000}'..='\u{3096}')
+        .chain('\u{FF01}'..='\u{FF9F}')
+        .filter(|&c| normalize_char(c) != c)
+        .map(|c| (c, normalize_char(c)))
+        .unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_text() {
+        assert_eq!(normalize_text("しぶや区"), "シブヤ区");
+        assert_eq!(normalize_text("ＡＢＣ　１２３"), "abc 123");
+        assert_eq!(normalize_text("渋谷区"), "渋谷区");
+        assert_eq!(normalize_text("ｼﾌﾞﾔ"), "シブヤ");
+        assert_eq!(normalize_text("ﾊﾟｰｸ ｳﾞｨﾗ"), "パーク ヴィラ");
+        assert_eq!(normalize_text("ﾎﾃﾙ｢ﾆｭｰｵｰﾀﾆ｣"), "ホテル「ニューオータニ」");
+        assert_eq!(normalize_text("ﾞｱ"), "\u{3099}ア");
+
+        let (from, to) = normalize_translation();
+        assert_eq!(from.chars().count(), to.chars().count());
+        assert!(from.contains('あ') && to.contains('ア'));
+        assert!(from.contains('ｼ') && to.contains('シ'));
+        assert_eq!(FULL_WIDTH_KANA.chars().count(), 0xFF9F - 0xFF61 + 1);
+    }
+}
@@ -41,8 +41,8 @@ impl GeometryWrapper {
     }
 }
 
-impl ToString for GeometryWrapper {
-    fn to_string(&self) -> String {
-        self.variant_name().to_string()
+impl std::fmt::Display for GeometryWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.variant_name())
     }
 }
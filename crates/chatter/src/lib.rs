@@ -1,9 +1,16 @@
 pub mod chatter;
 pub mod chatter_context;
 pub mod chatter_message;
+pub mod config;
+pub mod context_window;
 pub mod data;
 pub mod error;
-mod functions;
+pub mod functions;
 pub mod geom;
+pub mod llm;
+pub mod mesh;
+pub mod municipality_codes;
 mod pg_helpers;
+pub mod places;
 mod rows_to_tsv;
+pub mod turn_limits;
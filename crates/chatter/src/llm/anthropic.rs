@@ -0,0 +1,642 @@
+use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
+use crate::error::{ChatterError, Result};
+use crate::llm::{LlmEventStream, LlmProvider, LlmRequest, LlmResponse, LlmStreamEvent, LlmUsage};
+use async_openai::types::{ChatCompletionMessageToolCall, ChatCompletionToolType, FunctionCall};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Provider for Anthropic-style messages APIs.
+pub struct AnthropicProvider {
+    http: reqwest::Client,
+    api_key: String,
+    base_url: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: String, base_url: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_key,
+            base_url: base_url
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MessagesRequest {
+    model: String,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<serde_json::Value>,
+    /// Stream the response as server-sent events
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: &'static str,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize)]
+struct Tool {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    input_schema: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    /// Input tokens that were not read from or written to the cache
+    input_tokens: u32,
+    output_tokens: u32,
+    #[serde(default)]
+    cache_creation_input_tokens: Option<u32>,
+    #[serde(default)]
+    cache_read_input_tokens: Option<u32>,
+}
+
+impl From<Usage> for LlmUsage {
+    fn from(usage: Usage) -> Self {
+        let cache_read = usage.cache_read_input_tokens.unwrap_or(0);
+        let cache_write = usage.cache_creation_input_tokens.unwrap_or(0);
+        Self {
+            prompt_tokens: usage.input_tokens + cache_write + cache_read,
+            completion_tokens: usage.output_tokens,
+            cached_prompt_tokens: cache_read,
+            cache_write_prompt_tokens: cache_write,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetails {
+    message: String,
+}
+
+/// Convert the conversation into Anthropic's format. System messages are pulled out
+/// into the top-level `system` parameter, tool responses become `tool_result` blocks
+/// in a user message, and consecutive messages from the same role are merged.
+fn convert_messages(messages: Vec<ChatterMessage>) -> Result<(Option<String>, Vec<Message>)> {
+    let mut system: Vec<String> = vec![];
+    let mut out: Vec<Message> = vec![];
+
+    for message in messages {
+        let (role, blocks) = match message.role {
+            Role::System => {
+                if let Some(text) = message.message {
+                    system.push(text);
+                }
+                continue;
+            }
+            Role::User => (
+                "user",
+                message
+                    .message
+                    .map(|text| vec![ContentBlock::Text { text }])
+                    .unwrap_or_default(),
+            ),
+            Role::Assistant => {
+                let mut blocks = vec![];
+                if let Some(text) = message.message.filter(|t| !t.is_empty()) {
+                    blocks.push(ContentBlock::Text { text });
+                }
+                for tool_call in message.tool_calls.unwrap_or_default() {
+                    let input = if tool_call.function.arguments.is_empty() {
+                        json!({})
+                    } else {
+                        serde_json::from_str(&tool_call.function.arguments)?
+                    };
+                    blocks.push(ContentBlock::ToolUse {
+                        id: tool_call.id,
+                        name: tool_call.function.name,
+                        input,
+                    });
+                }
+                ("assistant", blocks)
+            }
+            Role::Tool => (
+                "user",
+                vec![ContentBlock::ToolResult {
+                    tool_use_id: message.tool_call_id.unwrap_or_default(),
+                    content: message.message.unwrap_or_default(),
+                }],
+            ),
+            role => Err(ChatterError::UnknownRole(role.to_string()))?,
+        };
+
+        match out.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => out.push(Message {
+                role,
+                content: blocks,
+            }),
+        }
+    }
+
+    let system = if system.is_empty() {
+        None
+    } else {
+        Some(system.join("\n\n"))
+    };
+    Ok((system, out))
+}
+
+impl AnthropicProvider {
+    fn build_body(request: LlmRequest, stream: bool) -> Result<MessagesRequest> {
+        let (system, messages) = convert_messages(request.messages)?;
+        let tools = request
+            .tools
+            .into_iter()
+            .map(|tool| Tool {
+                name: tool.function.name,
+                description: tool.function.description,
+                input_schema: tool.function.parameters.unwrap_or_else(|| json!({})),
+            })
+            .collect::<Vec<_>>();
+        let tool_choice = match request.parallel_tool_calls {
+            Some(false) if !tools.is_empty() => Some(json!({
+                "type": "auto",
+                "disable_parallel_tool_use": true,
+            })),
+            _ => None,
+        };
+        Ok(MessagesRequest {
+            model: request.model,
+            max_tokens: request.max_completion_tokens,
+            system,
+            messages,
+            tools,
+            // Anthropic's temperature range is 0 to 1
+            temperature: request.temperature.map(|t| t.min(1.0)),
+            tool_choice,
+            stream,
+        })
+    }
+
+    /// Send the request, and turn an error status into an `LlmApiError`.
+    async fn send(&self, body: &MessagesRequest) -> Result<reqwest::Response> {
+        let response = self
+            .http
+            .post(format!("{}/v1/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .map(Duration::from_secs_f64);
+            let text = response.text().await?;
+            let message = serde_json::from_str::<ErrorResponse>(&text)
+                .map(|e| e.error.message)
+                .unwrap_or(text);
+            return Err(ChatterError::LlmApiError {
+                status: status.as_u16(),
+                message,
+                retry_after,
+            });
+        }
+        Ok(response)
+    }
+}
+
+/// The assistant message made of the content blocks of a response.
+fn into_response(content: Vec<ContentBlock>, usage: Option<LlmUsage>) -> LlmResponse {
+    let mut text: Vec<String> = vec![];
+    let mut tool_calls: Vec<ChatCompletionMessageToolCall> = vec![];
+    for block in content {
+        match block {
+            ContentBlock::Text { text: t } => text.push(t),
+            ContentBlock::ToolUse { id, name, input } => {
+                tool_calls.push(ChatCompletionMessageToolCall {
+                    id,
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionCall {
+                        name,
+                        arguments: input.to_string(),
+                    },
+                })
+            }
+            _ => {}
+        }
+    }
+
+    LlmResponse {
+        message: ChatterMessage {
+            message: if text.is_empty() {
+                None
+            } else {
+                Some(text.join(""))
+            },
+            role: Role::Assistant,
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls)
+            },
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        },
+        usage,
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(&self, request: LlmRequest) -> Result<LlmResponse> {
+        let body = Self::build_body(request, false)?;
+        let response: MessagesResponse = self.send(&body).await?.json().await?;
+        Ok(into_response(
+            response.content,
+            response.usage.map(Into::into),
+        ))
+    }
+
+    async fn stream(&self, request: LlmRequest) -> Result<LlmEventStream> {
+        let body = Self::build_body(request, true)?;
+        let response = self.send(&body).await?;
+        let chunks = futures::stream::unfold(Some(response), |response| async move {
+            let mut response = response?;
+            match response.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), Some(response))),
+                Ok(None) => None,
+                // Stop reading after an error
+                Err(e) => Some((Err(e.into()), None)),
+            }
+        });
+        Ok(Box::pin(stream_events(chunks)))
+    }
+}
+
+/// A server-sent event of a streamed response.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: ContentDelta,
+    },
+    MessageDelta {
+        usage: Option<DeltaUsage>,
+    },
+    Error {
+        error: StreamError,
+    },
+    /// `content_block_stop`, `message_stop` and `ping`
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct DeltaUsage {
+    /// All output tokens so far
+    output_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct StreamError {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+/// A content block as it is being streamed. The input of a tool use arrives as fragments
+/// of JSON.
+enum StreamedBlock {
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        input: String,
+    },
+    Other,
+}
+
+/// Split server-sent events out of the streamed bytes, and return the `data` of each
+/// complete event. Events end with a blank line, and may be split across chunks anywhere,
+/// even inside a character.
+fn take_events(buffer: &mut Vec<u8>) -> Vec<String> {
+    let mut events = vec![];
+    while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+        let event: Vec<u8> = buffer.drain(..end + 2).collect();
+        let event = String::from_utf8_lossy(&event);
+        let data: Vec<&str> = event
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim_start)
+            .collect();
+        if !data.is_empty() {
+            events.push(data.join("\n"));
+        }
+    }
+    events
+}
+
+/// Assemble the streamed events into text deltas, started tool calls, and the final
+/// message.
+fn stream_events(
+    chunks: impl Stream<Item = Result<Vec<u8>>> + Send + 'static,
+) -> impl Stream<Item = Result<LlmStreamEvent>> + Send {
+    try_stream! {
+        let mut chunks = Box::pin(chunks);
+        let mut buffer: Vec<u8> = vec![];
+        let mut blocks: Vec<StreamedBlock> = vec![];
+        let mut usage: Option<LlmUsage> = None;
+
+        while let Some(chunk) = chunks.next().await {
+            // Anthropic separates lines with `\n`, but the SSE format allows `\r\n`
+            buffer.extend(chunk?.into_iter().filter(|&b| b != b'\r'));
+            for data in take_events(&mut buffer) {
+                match serde_json::from_str::<StreamEvent>(&data)? {
+                    StreamEvent::MessageStart { message } => {
+                        usage = message.usage.map(Into::into);
+                    }
+                    StreamEvent::ContentBlockStart { index, content_block } => {
+                        let block = match content_block {
+                            ContentBlock::Text { text } => StreamedBlock::Text(text),
+                            ContentBlock::ToolUse { id, name, .. } => StreamedBlock::ToolUse {
+                                id,
+                                name,
+                                input: String::new(),
+                            },
+                            _ => StreamedBlock::Other,
+                        };
+                        if let StreamedBlock::ToolUse { id, name, .. } = &block {
+                            yield LlmStreamEvent::ToolCallStarted {
+                                id: id.clone(),
+                                name: name.clone(),
+                            };
+                        }
+                        while blocks.len() <= index {
+                            blocks.push(StreamedBlock::Other);
+                        }
+                        blocks[index] = block;
+                    }
+                    StreamEvent::ContentBlockDelta { index, delta } => {
+                        match (blocks.get_mut(index), delta) {
+                            (Some(StreamedBlock::Text(text)), ContentDelta::TextDelta { text: delta }) => {
+                                text.push_str(&delta);
+                                yield LlmStreamEvent::TextDelta(delta);
+                            }
+                            (
+                                Some(StreamedBlock::ToolUse { input, .. }),
+                                ContentDelta::InputJsonDelta { partial_json },
+                            ) => input.push_str(&partial_json),
+                            _ => {}
+                        }
+                    }
+                    StreamEvent::MessageDelta { usage: Some(delta) } => {
+                        if let Some(usage) = &mut usage {
+                            usage.completion_tokens = delta.output_tokens;
+                        }
+                    }
+                    StreamEvent::Error { error } => {
+                        Err(ChatterError::LlmApiError {
+                            // The status the API uses for the same error outside a stream
+                            status: if error.kind == "overloaded_error" { 529 } else { 500 },
+                            message: error.message,
+                            retry_after: None,
+                        })?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut content = vec![];
+        for block in blocks {
+            content.push(match block {
+                StreamedBlock::Text(text) => ContentBlock::Text { text },
+                StreamedBlock::ToolUse { id, name, input } => ContentBlock::ToolUse {
+                    id,
+                    name,
+                    input: if input.is_empty() {
+                        json!({})
+                    } else {
+                        serde_json::from_str(&input)?
+                    },
+                },
+                StreamedBlock::Other => continue,
+            });
+        }
+        yield LlmStreamEvent::Done(into_response(content, usage));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, text: &str) -> ChatterMessage {
+        ChatterMessage {
+            message: Some(text.to_string()),
+            role,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+
+    #[test]
+    fn test_convert_messages() {
+        let mut assistant = message(Role::Assistant, "");
+        assistant.tool_calls = Some(vec![
+            ChatCompletionMessageToolCall {
+                id: "call_1".to_string(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: "describe_tables".to_string(),
+                    arguments: r#"{"table_names":["n03_union"]}"#.to_string(),
+                },
+            },
+            ChatCompletionMessageToolCall {
+                id: "call_2".to_string(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: "describe_tables".to_string(),
+                    arguments: r#"{"table_names":["p29"]}"#.to_string(),
+                },
+            },
+        ]);
+        let mut tool_1 = message(Role::Tool, "result 1");
+        tool_1.tool_call_id = Some("call_1".to_string());
+        let mut tool_2 = message(Role::Tool, "result 2");
+        tool_2.tool_call_id = Some("call_2".to_string());
+
+        let (system, messages) = convert_messages(vec![
+            message(Role::System, "You are a bot."),
+            message(Role::User, "Hello"),
+            assistant,
+            tool_1,
+            tool_2,
+        ])
+        .unwrap();
+
+        assert_eq!(system.as_deref(), Some("You are a bot."));
+        // user, assistant, user (both tool results merged)
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].role, "assistant");
+        // the empty assistant text is dropped, leaving only the tool uses
+        assert_eq!(messages[1].content.len(), 2);
+        assert_eq!(messages[2].role, "user");
+        assert_eq!(messages[2].content.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_events() {
+        let body = [
+            r#"{"type":"message_start","message":{"id":"msg_1","usage":{"input_tokens":10,"cache_read_input_tokens":90,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"渋谷区を"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"調べます。"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"geocode_place","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"place\": \"渋"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"谷区\"}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":25}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .iter()
+        .map(|data| {
+            let event = serde_json::from_str::<serde_json::Value>(data).unwrap()["type"].clone();
+            format!("event: {}\ndata: {}\n\n", event.as_str().unwrap(), data)
+        })
+        .collect::<String>();
+        // Split inside characters and events
+        let chunks: Vec<Result<Vec<u8>>> = body
+            .as_bytes()
+            .chunks(7)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect();
+
+        let events: Vec<LlmStreamEvent> = stream_events(futures::stream::iter(chunks))
+            .map(|event| event.unwrap())
+            .collect()
+            .await;
+        let deltas: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                LlmStreamEvent::TextDelta(delta) => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec!["渋谷区を", "調べます。"]);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            LlmStreamEvent::ToolCallStarted { id, name } if id == "toolu_1" && name == "geocode_place"
+        )));
+
+        let Some(LlmStreamEvent::Done(response)) = events.last() else {
+            panic!("Expected the last event to be Done");
+        };
+        assert_eq!(
+            response.message.message.as_deref(),
+            Some("渋谷区を調べます。")
+        );
+        let tool_calls = response.message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].function.name, "geocode_place");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&tool_calls[0].function.arguments).unwrap(),
+            json!({ "place": "渋谷区" })
+        );
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 100);
+        assert_eq!(usage.cached_prompt_tokens, 90);
+        assert_eq!(usage.completion_tokens, 25);
+    }
+
+    #[tokio::test]
+    async fn test_stream_error() {
+        let body = "event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n";
+        let mut events = Box::pin(stream_events(futures::stream::iter(vec![Ok(body
+            .as_bytes()
+            .to_vec())])));
+        let Some(Err(ChatterError::LlmApiError { status, .. })) = events.next().await else {
+            panic!("Expected an API error");
+        };
+        assert_eq!(status, 529);
+    }
+}
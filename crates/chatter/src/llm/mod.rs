@@ -0,0 +1,204 @@
+//! LLM providers. The rest of the chatter only talks to the `LlmProvider` trait,
+//! so the backing API can be switched by configuration.
+
+use crate::chatter_message::ChatterMessage;
+use crate::error::{ChatterError, Result};
+use async_openai::types::ChatCompletionTool;
+use async_trait::async_trait;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+mod anthropic;
+mod models;
+mod openai;
+mod pricing;
+mod replay;
+mod retry;
+
+pub use anthropic::AnthropicProvider;
+pub use models::{
+    DEFAULT_MAX_COMPLETION_TOKENS, GenerationSettings, ModelCapabilities, ResolvedSettings,
+};
+pub use openai::OpenAIProvider;
+pub use pricing::{ModelPricing, cost_micros};
+pub use replay::{RecordingProvider, ReplayProvider, Transcript};
+pub use retry::{RetryClass, RetryPolicy, classify};
+
+/// The model used when nothing else is configured.
+const DEFAULT_MODEL: &str = "gpt-4.1";
+
+/// Get the default model name. Can be overridden with the `LLM_MODEL` environment variable.
+pub fn default_model() -> String {
+    env::var("LLM_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string())
+}
+
+/// Build the provider configured in the environment. If `LLM_RECORD_TRANSCRIPT` is set,
+/// every response is also recorded to that file so it can be replayed later.
+pub fn provider_from_env() -> Result<Arc<dyn LlmProvider>> {
+    let provider = LlmProviderConfig::from_env()?.build()?;
+    match env::var("LLM_RECORD_TRANSCRIPT") {
+        Ok(path) => Ok(Arc::new(RecordingProvider::new(provider, path))),
+        Err(_) => Ok(provider),
+    }
+}
+
+/// A provider-agnostic chat completion request.
+#[derive(Clone, Debug)]
+pub struct LlmRequest {
+    pub model: String,
+    /// The full conversation, including the system message.
+    pub messages: Vec<ChatterMessage>,
+    pub tools: Vec<ChatCompletionTool>,
+    pub max_completion_tokens: u32,
+    /// Only set if the model accepts it.
+    pub temperature: Option<f32>,
+    /// Only set if the model accepts it.
+    pub parallel_tool_calls: Option<bool>,
+}
+
+/// The response to a `LlmRequest`.
+#[derive(Clone, Debug)]
+pub struct LlmResponse {
+    /// The assistant message. Tool calls are represented in the OpenAI format,
+    /// regardless of the provider.
+    pub message: ChatterMessage,
+    /// Token usage, if the provider reported it.
+    pub usage: Option<LlmUsage>,
+}
+
+/// The number of tokens used by a request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LlmUsage {
+    /// All input tokens, including cached ones.
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// The part of `prompt_tokens` that was read from the prompt cache.
+    #[serde(default)]
+    pub cached_prompt_tokens: u32,
+    /// The part of `prompt_tokens` that was written to the prompt cache. Only reported by
+    /// providers that charge extra for it.
+    #[serde(default)]
+    pub cache_write_prompt_tokens: u32,
+}
+
+impl LlmUsage {
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// An event emitted while a response is being streamed from the LLM.
+#[derive(Clone, Debug)]
+pub enum LlmStreamEvent {
+    /// A fragment of the assistant's reply text.
+    TextDelta(String),
+    /// The model started emitting a tool call.
+    ToolCallStarted { id: String, name: String },
+    /// The complete response. This is always the last event in the stream.
+    Done(LlmResponse),
+}
+
+pub type LlmEventStream = Pin<Box<dyn Stream<Item = Result<LlmStreamEvent>> + Send>>;
+
+/// Trait implemented by all LLM backends.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Send the request to the LLM and return the assistant's reply.
+    async fn complete(&self, request: LlmRequest) -> Result<LlmResponse>;
+
+    /// Send the request to the LLM and stream the reply as it is generated.
+    /// Providers that don't support streaming emit a single `Done` event.
+    async fn stream(&self, request: LlmRequest) -> Result<LlmEventStream> {
+        let response = self.complete(request).await?;
+        Ok(Box::pin(futures::stream::once(async move {
+            Ok(LlmStreamEvent::Done(response))
+        })))
+    }
+}
+
+/// Which provider to use, and how to reach it.
+#[derive(Clone, Debug)]
+pub enum LlmProviderConfig {
+    /// The OpenAI API. The API key is read from `OPENAI_API_KEY`.
+    OpenAI,
+    /// Any server that implements the OpenAI chat completions API (vLLM, Ollama, llama.cpp, etc.)
+    OpenAICompatible {
+        base_url: String,
+        api_key: Option<String>,
+    },
+    /// The Anthropic messages API.
+    Anthropic {
+        base_url: Option<String>,
+        api_key: String,
+    },
+    /// Play back a recorded transcript instead of calling an LLM. Used for testing.
+    Replay { path: PathBuf },
+}
+
+impl LlmProviderConfig {
+    /// Read the provider configuration from the environment.
+    ///
+    /// - `LLM_PROVIDER`: `openai` (default), `openai_compatible`, `anthropic`, or `replay`
+    /// - `LLM_BASE_URL`: base URL of the API. Required for `openai_compatible`.
+    /// - `LLM_REPLAY_FILE`: the transcript to play back. Required for `replay`.
+    /// - `LLM_API_KEY`: API key. `anthropic` falls back to `ANTHROPIC_API_KEY`.
+    pub fn from_env() -> Result<Self> {
+        let provider = env::var("LLM_PROVIDER").unwrap_or_else(|_| "openai".to_string());
+        let base_url = env::var("LLM_BASE_URL").ok();
+        let api_key = env::var("LLM_API_KEY").ok();
+
+        match provider.as_str() {
+            "openai" => Ok(Self::OpenAI),
+            "openai_compatible" => Ok(Self::OpenAICompatible {
+                base_url: base_url.ok_or_else(|| {
+                    ChatterError::LlmConfigError(
+                        "LLM_BASE_URL must be set for the openai_compatible provider".to_string(),
+                    )
+                })?,
+                api_key,
+            }),
+            "anthropic" => Ok(Self::Anthropic {
+                base_url,
+                api_key: api_key
+                    .or_else(|| env::var("ANTHROPIC_API_KEY").ok())
+                    .ok_or_else(|| {
+                        ChatterError::LlmConfigError(
+                            "LLM_API_KEY or ANTHROPIC_API_KEY must be set for the anthropic provider"
+                                .to_string(),
+                        )
+                    })?,
+            }),
+            "replay" => Ok(Self::Replay {
+                path: env::var("LLM_REPLAY_FILE")
+                    .map_err(|_| {
+                        ChatterError::LlmConfigError(
+                            "LLM_REPLAY_FILE must be set for the replay provider".to_string(),
+                        )
+                    })?
+                    .into(),
+            }),
+            other => Err(ChatterError::LlmConfigError(format!(
+                "Unknown LLM provider: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Instantiate the provider described by this configuration.
+    pub fn build(self) -> Result<Arc<dyn LlmProvider>> {
+        Ok(match self {
+            Self::OpenAI => Arc::new(OpenAIProvider::new()),
+            Self::OpenAICompatible { base_url, api_key } => {
+                Arc::new(OpenAIProvider::with_base_url(&base_url, api_key.as_deref()))
+            }
+            Self::Anthropic { base_url, api_key } => {
+                Arc::new(AnthropicProvider::new(api_key, base_url))
+            }
+            Self::Replay { path } => Arc::new(ReplayProvider::from_file(path)?),
+        })
+    }
+}
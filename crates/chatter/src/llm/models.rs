@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+/// The completion token limit used when a thread doesn't set one.
+pub const DEFAULT_MAX_COMPLETION_TOKENS: u32 = 2048;
+
+/// Generation parameters chosen for a thread. Anything left unset uses the model's default.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    /// Whether the model may request more than one tool call at a time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+}
+
+impl GenerationSettings {
+    /// Check that the settings are within the ranges accepted by the APIs.
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .temperature
+            .is_some_and(|temperature| !(0.0..=2.0).contains(&temperature))
+        {
+            return Err("temperature must be between 0 and 2".to_string());
+        }
+        if self.max_completion_tokens == Some(0) {
+            return Err("max_completion_tokens must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a model accepts. Parameters a model doesn't accept are left out of the request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    pub temperature: bool,
+    pub parallel_tool_calls: bool,
+    /// The total number of tokens (prompt and completion) the model accepts.
+    pub context_window: usize,
+    pub max_completion_tokens: u32,
+}
+
+impl ModelCapabilities {
+    /// Look up the capabilities of a model by name. Unknown models (for example, local
+    /// models behind an OpenAI-compatible server) get conservative defaults.
+    pub fn for_model(model: &str) -> Self {
+        let reasoning = ["o1", "o3", "o4", "gpt-5"]
+            .iter()
+            .any(|prefix| model.starts_with(prefix));
+        if reasoning {
+            Self {
+                temperature: false,
+                parallel_tool_calls: false,
+                context_window: 200_000,
+                max_completion_tokens: 100_000,
+            }
+        } else if model.starts_with("gpt-4.1") {
+            Self {
+                temperature: true,
+                parallel_tool_calls: true,
+                context_window: 1_047_576,
+                max_completion_tokens: 32_768,
+            }
+        } else if model.starts_with("gpt-4o") {
+            Self {
+                temperature: true,
+                parallel_tool_calls: true,
+                context_window: 128_000,
+                max_completion_tokens: 16_384,
+            }
+        } else if model.starts_with("claude") {
+            Self {
+                temperature: true,
+                parallel_tool_calls: true,
+                context_window: 200_000,
+                max_completion_tokens: 32_000,
+            }
+        } else {
+            Self {
+                temperature: true,
+                parallel_tool_calls: false,
+                context_window: 32_768,
+                max_completion_tokens: 4096,
+            }
+        }
+    }
+
+    /// Resolve the parameters to send for `settings`, dropping the ones this model doesn't accept.
+    pub fn apply(&self, settings: &GenerationSettings) -> ResolvedSettings {
+        ResolvedSettings {
+            temperature: settings.temperature.filter(|_| self.temperature),
+            max_completion_tokens: settings
+                .max_completion_tokens
+                .unwrap_or(DEFAULT_MAX_COMPLETION_TOKENS)
+                .min(self.max_completion_tokens),
+            parallel_tool_calls: settings
+                .parallel_tool_calls
+                .filter(|_| self.parallel_tool_calls),
+        }
+    }
+}
+
+/// Generation parameters after applying the model's capabilities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResolvedSettings {
+    pub temperature: Option<f32>,
+    pub max_completion_tokens: u32,
+    pub parallel_tool_calls: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_capabilities() {
+        let settings = GenerationSettings {
+            temperature: Some(0.2),
+            max_completion_tokens: None,
+            parallel_tool_calls: Some(false),
+        };
+
+        let resolved = ModelCapabilities::for_model("gpt-4.1").apply(&settings);
+        assert_eq!(resolved.temperature, Some(0.2));
+        assert_eq!(
+            resolved.max_completion_tokens,
+            DEFAULT_MAX_COMPLETION_TOKENS
+        );
+        assert_eq!(resolved.parallel_tool_calls, Some(false));
+
+        // Reasoning models reject temperature and parallel_tool_calls
+        let resolved = ModelCapabilities::for_model("o3-mini").apply(&settings);
+        assert_eq!(resolved.temperature, None);
+        assert_eq!(resolved.parallel_tool_calls, None);
+
+        // The completion limit is capped by the model
+        let resolved = ModelCapabilities::for_model("llama3").apply(&GenerationSettings {
+            max_completion_tokens: Some(100_000),
+            ..Default::default()
+        });
+        assert_eq!(resolved.max_completion_tokens, 4096);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(GenerationSettings::default().validate().is_ok());
+        assert!(
+            GenerationSettings {
+                temperature: Some(3.0),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+    }
+}
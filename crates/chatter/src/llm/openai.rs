@@ -0,0 +1,251 @@
+use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
+use crate::error::{ChatterError, Result};
+use crate::llm::{LlmEventStream, LlmProvider, LlmRequest, LlmResponse, LlmStreamEvent, LlmUsage};
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionResponseMessage, ChatCompletionResponseStream, ChatCompletionStreamOptions,
+    ChatCompletionToolType, CompletionUsage, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs, FunctionCall,
+};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+
+/// Provider for the OpenAI chat completions API, and servers compatible with it.
+pub struct OpenAIProvider {
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAIProvider {
+    /// Connect to the OpenAI API, using `OPENAI_API_KEY` from the environment.
+    pub fn new() -> Self {
+        Self {
+            client: Client::new(),
+        }
+    }
+
+    /// Connect to an OpenAI-compatible server at `base_url` (for example, `http://localhost:11434/v1`).
+    pub fn with_base_url(base_url: &str, api_key: Option<&str>) -> Self {
+        let mut config = OpenAIConfig::new().with_api_base(base_url);
+        if let Some(api_key) = api_key {
+            config = config.with_api_key(api_key);
+        }
+        Self {
+            client: Client::with_config(config),
+        }
+    }
+}
+
+impl Default for OpenAIProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn build_request(request: LlmRequest) -> Result<CreateChatCompletionRequest> {
+    let mut builder = CreateChatCompletionRequestArgs::default();
+    builder
+        .max_completion_tokens(request.max_completion_tokens)
+        .model(&request.model)
+        .messages(
+            request
+                .messages
+                .into_iter()
+                .map(|m| m.try_into())
+                .collect::<Result<Vec<ChatCompletionRequestMessage>>>()?,
+        )
+        .tools(request.tools);
+    if let Some(temperature) = request.temperature {
+        builder.temperature(temperature);
+    }
+    if let Some(parallel_tool_calls) = request.parallel_tool_calls {
+        builder.parallel_tool_calls(parallel_tool_calls);
+    }
+    builder.build().map_err(ChatterError::OpenAIError)
+}
+
+#[async_trait]
+impl LlmProvider for OpenAIProvider {
+    async fn complete(&self, request: LlmRequest) -> Result<LlmResponse> {
+        let request = build_request(request)?;
+        let response = self.client.chat().create(request).await?;
+        let choice = response.choices[0].clone();
+
+        Ok(LlmResponse {
+            message: choice.message.try_into()?,
+            usage: response.usage.map(Into::into),
+        })
+    }
+
+    async fn stream(&self, request: LlmRequest) -> Result<LlmEventStream> {
+        let mut request = build_request(request)?;
+        // Ask for the token usage to be sent in the last chunk
+        request.stream_options = Some(ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+        let chunks = self.client.chat().create_stream(request).await?;
+        Ok(Box::pin(stream_events(chunks)))
+    }
+}
+
+/// Assemble the streamed chunks into events, and the final message.
+fn stream_events(
+    mut chunks: ChatCompletionResponseStream,
+) -> impl Stream<Item = Result<LlmStreamEvent>> + Send {
+    try_stream! {
+        let mut content = String::new();
+        let mut tool_calls: Vec<ChatCompletionMessageToolCall> = vec![];
+        let mut usage = None;
+
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            if let Some(chunk_usage) = chunk.usage {
+                usage = Some(chunk_usage.into());
+            }
+            let Some(choice) = chunk.choices.into_iter().next() else {
+                continue;
+            };
+
+            if let Some(delta) = choice.delta.content.filter(|d| !d.is_empty()) {
+                content.push_str(&delta);
+                yield LlmStreamEvent::TextDelta(delta);
+            }
+
+            // Tool calls are streamed in fragments. The first fragment of each call
+            // has the ID and name, the following ones append to the arguments.
+            for fragment in choice.delta.tool_calls.unwrap_or_default() {
+                let index = fragment.index as usize;
+                while tool_calls.len() <= index {
+                    tool_calls.push(ChatCompletionMessageToolCall {
+                        id: String::new(),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: String::new(),
+                            arguments: String::new(),
+                        },
+                    });
+                }
+                let call = &mut tool_calls[index];
+                let started = fragment.id.is_some();
+                if let Some(id) = fragment.id {
+                    call.id = id;
+                }
+                if let Some(function) = fragment.function {
+                    if let Some(name) = function.name {
+                        call.function.name.push_str(&name);
+                    }
+                    if let Some(arguments) = function.arguments {
+                        call.function.arguments.push_str(&arguments);
+                    }
+                }
+                if started {
+                    yield LlmStreamEvent::ToolCallStarted {
+                        id: call.id.clone(),
+                        name: call.function.name.clone(),
+                    };
+                }
+            }
+        }
+
+        yield LlmStreamEvent::Done(LlmResponse {
+            message: ChatterMessage {
+                message: if content.is_empty() { None } else { Some(content) },
+                role: Role::Assistant,
+                tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
+                tool_call_id: None,
+                sidecar: ChatterMessageSidecar::None,
+            },
+            usage,
+        });
+    }
+}
+
+impl From<CompletionUsage> for LlmUsage {
+    fn from(usage: CompletionUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cached_prompt_tokens: usage
+                .prompt_tokens_details
+                .and_then(|details| details.cached_tokens)
+                .unwrap_or(0),
+            // Writing to the cache is free
+            cache_write_prompt_tokens: 0,
+        }
+    }
+}
+
+impl TryFrom<ChatCompletionResponseMessage> for ChatterMessage {
+    type Error = ChatterError;
+
+    fn try_from(message: ChatCompletionResponseMessage) -> Result<Self> {
+        Ok(Self {
+            message: message.content,
+            role: message.role,
+            tool_calls: message.tool_calls,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::None,
+        })
+    }
+}
+
+impl TryFrom<ChatterMessage> for ChatCompletionRequestMessage {
+    type Error = ChatterError;
+
+    fn try_from(message: ChatterMessage) -> Result<Self> {
+        let out: ChatCompletionRequestMessage = match message.role {
+            Role::User => {
+                let mut msg = ChatCompletionRequestUserMessageArgs::default();
+                let msg = if let Some(message) = message.message {
+                    msg.content(message)
+                } else {
+                    &mut msg
+                };
+                msg.build()?.into()
+            }
+            Role::System => {
+                let mut msg = ChatCompletionRequestSystemMessageArgs::default();
+                let msg = if let Some(message) = message.message {
+                    msg.content(message)
+                } else {
+                    &mut msg
+                };
+                msg.build()?.into()
+            }
+            Role::Assistant => {
+                let mut msg = ChatCompletionRequestAssistantMessageArgs::default();
+                let mut msg = if let Some(message) = message.message {
+                    msg.content(message)
+                } else {
+                    &mut msg
+                };
+                let msg = if let Some(tool_calls) = message.tool_calls {
+                    msg.tool_calls(tool_calls)
+                } else {
+                    &mut msg
+                };
+                msg.build()?.into()
+            }
+            Role::Tool => {
+                let mut msg = ChatCompletionRequestToolMessageArgs::default();
+                let mut msg = if let Some(message) = message.message {
+                    msg.content(message)
+                } else {
+                    &mut msg
+                };
+                let msg = if let Some(tool_call_id) = message.tool_call_id {
+                    msg.tool_call_id(tool_call_id)
+                } else {
+                    &mut msg
+                };
+                msg.build()?.into()
+            }
+            role => Err(ChatterError::UnknownRole(role.to_string()))?,
+        };
+        Ok(out)
+    }
+}
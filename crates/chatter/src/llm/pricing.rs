@@ -0,0 +1,119 @@
+use crate::llm::LlmUsage;
+
+/// Prices in USD per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub cached_input: f64,
+    /// Input written to the prompt cache. The same as `input` unless the provider charges
+    /// extra for it.
+    pub cache_write_input: f64,
+    pub output: f64,
+}
+
+/// Known model prices, by model name prefix. More specific prefixes come first.
+const PRICES: &[(&str, ModelPricing)] = &[
+    ("gpt-4.1-nano", price(0.10, 0.025, 0.40)),
+    ("gpt-4.1-mini", price(0.40, 0.10, 1.60)),
+    ("gpt-4.1", price(2.00, 0.50, 8.00)),
+    ("gpt-4o-mini", price(0.15, 0.075, 0.60)),
+    ("gpt-4o", price(2.50, 1.25, 10.00)),
+    ("o4-mini", price(1.10, 0.275, 4.40)),
+    ("o3-mini", price(1.10, 0.55, 4.40)),
+    ("o3", price(2.00, 0.50, 8.00)),
+    // Anthropic charges 1.25 times the input price for writing to the (5 minute) cache
+    (
+        "claude-opus-4",
+        price(15.00, 1.50, 75.00).with_cache_write(18.75),
+    ),
+    (
+        "claude-sonnet-4",
+        price(3.00, 0.30, 15.00).with_cache_write(3.75),
+    ),
+    (
+        "claude-3-7-sonnet",
+        price(3.00, 0.30, 15.00).with_cache_write(3.75),
+    ),
+    (
+        "claude-3-5-haiku",
+        price(0.80, 0.08, 4.00).with_cache_write(1.00),
+    ),
+];
+
+const fn price(input: f64, cached_input: f64, output: f64) -> ModelPricing {
+    ModelPricing {
+        input,
+        cached_input,
+        cache_write_input: input,
+        output,
+    }
+}
+
+impl ModelPricing {
+    /// Look up the price of a model. Returns `None` for models we don't have prices for
+    /// (for example, self-hosted models).
+    pub fn for_model(model: &str) -> Option<Self> {
+        PRICES
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix))
+            .map(|(_, pricing)| *pricing)
+    }
+
+    const fn with_cache_write(mut self, cache_write_input: f64) -> Self {
+        self.cache_write_input = cache_write_input;
+        self
+    }
+}
+
+/// The cost of a request in millionths of a US dollar, or `None` if the model's price is unknown.
+pub fn cost_micros(model: &str, usage: &LlmUsage) -> Option<u64> {
+    let pricing = ModelPricing::for_model(model)?;
+    let cached = usage.cached_prompt_tokens.min(usage.prompt_tokens);
+    let written = usage
+        .cache_write_prompt_tokens
+        .min(usage.prompt_tokens - cached);
+    let uncached = usage.prompt_tokens - cached - written;
+    // Prices are per million tokens, so the cost in micro-dollars is tokens * price.
+    let cost = uncached as f64 * pricing.input
+        + cached as f64 * pricing.cached_input
+        + written as f64 * pricing.cache_write_input
+        + usage.completion_tokens as f64 * pricing.output;
+    Some(cost.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost() {
+        let usage = LlmUsage {
+            prompt_tokens: 10_000,
+            completion_tokens: 1_000,
+            cached_prompt_tokens: 4_000,
+            cache_write_prompt_tokens: 0,
+        };
+        // 6000 * 2.00 + 4000 * 0.50 + 1000 * 8.00
+        assert_eq!(cost_micros("gpt-4.1-2025-04-14", &usage), Some(22_000));
+        // The mini model must not be priced as gpt-4.1
+        assert_eq!(cost_micros("gpt-4.1-mini", &usage), Some(4_400));
+        assert_eq!(cost_micros("llama3", &usage), None);
+    }
+
+    #[test]
+    fn test_cache_write_cost() {
+        let usage = LlmUsage {
+            prompt_tokens: 10_000,
+            completion_tokens: 1_000,
+            cached_prompt_tokens: 4_000,
+            cache_write_prompt_tokens: 5_000,
+        };
+        // 1000 * 3.00 + 4000 * 0.30 + 5000 * 3.75 + 1000 * 15.00
+        assert_eq!(
+            cost_micros("claude-sonnet-4-20250514", &usage),
+            Some(37_950)
+        );
+        // Providers that don't charge for cache writes price them as input
+        assert_eq!(cost_micros("gpt-4.1", &usage), Some(22_000));
+    }
+}
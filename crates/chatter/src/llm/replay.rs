@@ -0,0 +1,140 @@
+use crate::chatter_message::ChatterMessage;
+use crate::error::{ChatterError, Result};
+use crate::llm::{LlmEventStream, LlmProvider, LlmRequest, LlmResponse, LlmStreamEvent};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A recorded conversation with an LLM: the assistant responses, in the order they were returned.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Transcript {
+    pub responses: Vec<ChatterMessage>,
+}
+
+impl Transcript {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::read_to_string(path.as_ref()).map_err(|e| {
+            ChatterError::LlmConfigError(format!(
+                "Failed to read transcript {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+        Ok(serde_json::from_str(&file)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path.as_ref(), json).map_err(|e| {
+            ChatterError::LlmConfigError(format!(
+                "Failed to write transcript {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })
+    }
+}
+
+/// A provider that plays back scripted assistant responses instead of calling an LLM.
+/// Every request received is kept so tests can assert on what would have been sent.
+#[derive(Default)]
+pub struct ReplayProvider {
+    responses: Mutex<VecDeque<ChatterMessage>>,
+    requests: Mutex<Vec<LlmRequest>>,
+}
+
+impl ReplayProvider {
+    pub fn new(transcript: Transcript) -> Self {
+        Self {
+            responses: Mutex::new(transcript.responses.into()),
+            requests: Mutex::new(vec![]),
+        }
+    }
+
+    /// Load the scripted responses from a transcript file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(Transcript::load(path)?))
+    }
+
+    /// The requests this provider has received so far.
+    pub fn requests(&self) -> Vec<LlmRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The number of scripted responses that have not been played back yet.
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+}
+
+#[async_trait]
+impl LlmProvider for ReplayProvider {
+    async fn complete(&self, request: LlmRequest) -> Result<LlmResponse> {
+        self.requests.lock().unwrap().push(request);
+        let message = self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or(ChatterError::ReplayExhausted)?;
+        Ok(LlmResponse {
+            message,
+            usage: None,
+        })
+    }
+}
+
+/// Wraps another provider and records every response into a transcript file,
+/// in the format `ReplayProvider` reads. The file is rewritten after each response.
+pub struct RecordingProvider {
+    inner: Arc<dyn LlmProvider>,
+    path: PathBuf,
+    transcript: Arc<Mutex<Transcript>>,
+}
+
+impl RecordingProvider {
+    pub fn new(inner: Arc<dyn LlmProvider>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            path: path.into(),
+            transcript: Arc::new(Mutex::new(Transcript::default())),
+        }
+    }
+
+    pub fn transcript(&self) -> Transcript {
+        self.transcript.lock().unwrap().clone()
+    }
+}
+
+fn record(transcript: &Mutex<Transcript>, path: &Path, message: &ChatterMessage) -> Result<()> {
+    let transcript = {
+        let mut transcript = transcript.lock().unwrap();
+        transcript.responses.push(message.clone());
+        transcript.clone()
+    };
+    transcript.save(path)
+}
+
+#[async_trait]
+impl LlmProvider for RecordingProvider {
+    async fn complete(&self, request: LlmRequest) -> Result<LlmResponse> {
+        let response = self.inner.complete(request).await?;
+        record(&self.transcript, &self.path, &response.message)?;
+        Ok(response)
+    }
+
+    async fn stream(&self, request: LlmRequest) -> Result<LlmEventStream> {
+        let events = self.inner.stream(request).await?;
+        let transcript = self.transcript.clone();
+        let path = self.path.clone();
+        Ok(Box::pin(events.map(move |event| {
+            if let Ok(LlmStreamEvent::Done(response)) = &event {
+                record(&transcript, &path, &response.message)?;
+            }
+            event
+        })))
+    }
+}
@@ -0,0 +1,251 @@
+use crate::error::ChatterError;
+use async_openai::error::OpenAIError;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Duration;
+
+/// How failed LLM requests are retried within a turn.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// The number of retries per model, after the first attempt.
+    pub max_retries: u32,
+    /// The backoff before the first retry. Doubles with every retry.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// The model to switch to when the retries run out. Defaults to `LLM_FALLBACK_MODEL`.
+    pub fallback_model: Option<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            fallback_model: env::var("LLM_FALLBACK_MODEL")
+                .ok()
+                .filter(|m| !m.is_empty()),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 1). Uses "full jitter": a random
+    /// delay between zero and the exponential backoff. `jitter` is a random number in `0..1`.
+    /// If the server asked us to wait (`Retry-After`), we wait at least that long.
+    pub fn backoff(&self, retry: u32, retry_after: Option<Duration>, jitter: f64) -> Duration {
+        let exponential = self
+            .initial_backoff_ms
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_backoff_ms);
+        let delay = Duration::from_millis((exponential as f64 * jitter.clamp(0.0, 1.0)) as u64);
+        match retry_after {
+            Some(retry_after) => delay.max(retry_after),
+            None => delay,
+        }
+    }
+}
+
+/// Whether a failed request should be retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryClass {
+    /// A transient failure: rate limits, server errors, timeouts and dropped connections.
+    Retryable { retry_after: Option<Duration> },
+    /// Retrying won't help (invalid request, authentication, quota, etc.)
+    Fatal,
+}
+
+fn retryable_status(status: u16) -> bool {
+    matches!(status, 408 | 409 | 429) || (500..600).contains(&status)
+}
+
+fn classify_reqwest(err: &reqwest::Error) -> RetryClass {
+    let retryable = err.is_timeout()
+        || err.is_connect()
+        || err.is_request()
+        || err.status().is_some_and(|s| retryable_status(s.as_u16()));
+    if retryable {
+        RetryClass::Retryable { retry_after: None }
+    } else {
+        RetryClass::Fatal
+    }
+}
+
+/// Parse OpenAI's "Please try again in 1.2s" / "try again in 350ms" rate limit hint.
+fn parse_try_again_in(message: &str) -> Option<Duration> {
+    let rest = &message[message.find("try again in ")? + "try again in ".len()..];
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(rest.len());
+    let value: f64 = rest[..end].parse().ok()?;
+    let unit = &rest[end..];
+    if unit.starts_with("ms") {
+        Some(Duration::from_secs_f64(value / 1000.0))
+    } else if unit.starts_with('s') {
+        Some(Duration::from_secs_f64(value))
+    } else {
+        None
+    }
+}
+
+/// Decide whether an error from an LLM request should be retried.
+pub fn classify(err: &ChatterError) -> RetryClass {
+    match err {
+        ChatterError::LlmApiError {
+            status,
+            retry_after,
+            ..
+        } => {
+            if retryable_status(*status) {
+                RetryClass::Retryable {
+                    retry_after: *retry_after,
+                }
+            } else {
+                RetryClass::Fatal
+            }
+        }
+        ChatterError::HttpError(err) => classify_reqwest(err),
+        ChatterError::LlmStreamIncomplete => RetryClass::Retryable { retry_after: None },
+        ChatterError::OpenAIError(err) => match err {
+            OpenAIError::Reqwest(err) => classify_reqwest(err),
+            OpenAIError::ApiError(api) => {
+                let code = api.code.as_deref().unwrap_or_default();
+                let r#type = api.r#type.as_deref().unwrap_or_default();
+                if code == "insufficient_quota" {
+                    RetryClass::Fatal
+                } else if code == "rate_limit_exceeded"
+                    || r#type == "server_error"
+                    || r#type == "requests"
+                    || r#type == "tokens"
+                {
+                    RetryClass::Retryable {
+                        retry_after: parse_try_again_in(&api.message),
+                    }
+                } else {
+                    RetryClass::Fatal
+                }
+            }
+            // The body of a gateway error is usually an HTML page
+            OpenAIError::JSONDeserialize(_) => RetryClass::Retryable { retry_after: None },
+            // The event source reports failed requests as "Invalid status code: 503 Service Unavailable"
+            OpenAIError::StreamError(message) => {
+                let status = message
+                    .split_whitespace()
+                    .find_map(|word| word.parse::<u16>().ok());
+                match status {
+                    Some(status) if !retryable_status(status) => RetryClass::Fatal,
+                    _ => RetryClass::Retryable {
+                        retry_after: parse_try_again_in(message),
+                    },
+                }
+            }
+            _ => RetryClass::Fatal,
+        },
+        _ => RetryClass::Fatal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    fn api_error(code: Option<&str>, r#type: Option<&str>, message: &str) -> ChatterError {
+        ChatterError::OpenAIError(OpenAIError::ApiError(ApiError {
+            message: message.to_string(),
+            r#type: r#type.map(str::to_string),
+            param: None,
+            code: code.map(str::to_string),
+        }))
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify(&api_error(
+                Some("rate_limit_exceeded"),
+                Some("tokens"),
+                "Rate limit reached for gpt-4.1. Please try again in 1.5s. Visit ..."
+            )),
+            RetryClass::Retryable {
+                retry_after: Some(Duration::from_millis(1500))
+            }
+        );
+        assert_eq!(
+            classify(&api_error(
+                Some("insufficient_quota"),
+                Some("insufficient_quota"),
+                "You exceeded your current quota"
+            )),
+            RetryClass::Fatal
+        );
+        assert_eq!(
+            classify(&api_error(None, Some("invalid_request_error"), "Bad")),
+            RetryClass::Fatal
+        );
+        assert_eq!(
+            classify(&ChatterError::OpenAIError(OpenAIError::StreamError(
+                "Invalid status code: 503 Service Unavailable".to_string()
+            ))),
+            RetryClass::Retryable { retry_after: None }
+        );
+        assert_eq!(
+            classify(&ChatterError::OpenAIError(OpenAIError::StreamError(
+                "Invalid status code: 401 Unauthorized".to_string()
+            ))),
+            RetryClass::Fatal
+        );
+        assert_eq!(
+            classify(&ChatterError::LlmApiError {
+                status: 529,
+                message: "Overloaded".to_string(),
+                retry_after: Some(Duration::from_secs(2)),
+            }),
+            RetryClass::Retryable {
+                retry_after: Some(Duration::from_secs(2))
+            }
+        );
+        assert_eq!(
+            classify(&ChatterError::LlmApiError {
+                status: 400,
+                message: "Bad request".to_string(),
+                retry_after: None,
+            }),
+            RetryClass::Fatal
+        );
+    }
+
+    #[test]
+    fn test_parse_try_again_in() {
+        assert_eq!(
+            parse_try_again_in("Please try again in 350ms."),
+            Some(Duration::from_millis(350))
+        );
+        assert_eq!(
+            parse_try_again_in("Please try again in 20s."),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(parse_try_again_in("Please try again later."), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5000,
+            fallback_model: None,
+        };
+        assert_eq!(policy.backoff(1, None, 1.0), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(2, None, 1.0), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(3, None, 0.5), Duration::from_millis(2000));
+        // Capped at max_backoff_ms
+        assert_eq!(policy.backoff(10, None, 1.0), Duration::from_millis(5000));
+        // Retry-After is a lower bound
+        assert_eq!(
+            policy.backoff(1, Some(Duration::from_secs(10)), 0.0),
+            Duration::from_secs(10)
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! JIS X 0410 regional mesh codes (地域メッシュ). Converts between points and mesh codes in
+//! Rust, and generates the same conversions as SQL expressions for queries.
+//!
+//! A 1st order mesh is 40' of latitude by 1° of longitude, and is split into 8 × 8 2nd order
+//! meshes, each split into 10 × 10 3rd order meshes. Half and quarter meshes split a mesh into
+//! 2 × 2, numbered 1 (south west), 2 (south east), 3 (north west) and 4 (north east).
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MeshLevel {
+    /// 1次メッシュ, about 80 km
+    First,
+    /// 2次メッシュ, about 10 km
+    Second,
+    /// 3次メッシュ (基準地域メッシュ), about 1 km
+    Third,
+    /// 2分の1地域メッシュ, about 500 m
+    Half,
+    /// 4分の1地域メッシュ, about 250 m
+    Quarter,
+}
+
+/// Size of a mesh at each level, in seconds of latitude and of longitude
+const SIZES: [(f64, f64); 5] = [
+    (2400.0, 3600.0),
+    (300.0, 450.0),
+    (30.0, 45.0),
+    (15.0, 22.5),
+    (7.5, 11.25),
+];
+
+/// Longitude of the western edge of the meshes numbered 00
+const LON_ORIGIN: f64 = 100.0;
+
+/// The extent of the meshes, in seconds of latitude and of longitude from the origin. 1st
+/// order codes have two digits each for latitude and longitude, so meshes only cover
+/// latitudes 0° to 66°40' and longitudes 100° to 200°.
+const EXTENT: (f64, f64) = (SIZES[0].0 * 100.0, SIZES[0].1 * 100.0);
+
+impl MeshLevel {
+    pub const ALL: [MeshLevel; 5] = [
+        Self::First,
+        Self::Second,
+        Self::Third,
+        Self::Half,
+        Self::Quarter,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The name of the level, as parsed by `from_str`.
+    pub fn name(self) -> &'static str {
+        ["1", "2", "3", "half", "quarter"][self.index()]
+    }
+
+    /// The number of digits in a code at this level.
+    pub fn code_length(self) -> usize {
+        [4, 6, 8, 9, 10][self.index()]
+    }
+
+    /// The level of a code with `length` digits.
+    pub fn from_code_length(length: usize) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.code_length() == length)
+    }
+}
+
+impl FromStr for MeshLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" | "first" => Ok(Self::First),
+            "2" | "second" => Ok(Self::Second),
+            "3" | "third" => Ok(Self::Third),
+            "half" => Ok(Self::Half),
+            "quarter" => Ok(Self::Quarter),
+            _ => Err(format!(
+                "Unknown mesh level: {}. Use 1, 2, 3, half or quarter.",
+                s
+            )),
+        }
+    }
+}
+
+/// The code of the mesh at `level` that contains the point, or `None` if the point is
+/// outside the meshes.
+pub fn mesh_code(lon: f64, lat: f64, level: MeshLevel) -> Option<String> {
+    let lat_s = lat * 3600.0;
+    let lon_s = (lon - LON_ORIGIN) * 3600.0;
+    if !((0.0..EXTENT.0).contains(&lat_s) && (0.0..EXTENT.1).contains(&lon_s)) {
+        return None;
+    }
+    // The index of the cell at level `i` within its parent
+    let cell = |value: f64, i: usize, size: fn((f64, f64)) -> f64| {
+        let index = (value / size(SIZES[i])).floor();
+        if i == 0 {
+            index as i64
+        } else {
+            (index - (value / size(SIZES[i - 1])).floor() * (size(SIZES[i - 1]) / size(SIZES[i])))
+                as i64
+        }
+    };
+    let lat_cell = |i| cell(lat_s, i, |(lat, _)| lat);
+    let lon_cell = |i| cell(lon_s, i, |(_, lon)| lon);
+
+    let mut code = format!("{:02}{:02}", lat_cell(0), lon_cell(0));
+    for i in 1..=level.index() {
+        if i <= 2 {
+            code.push_str(&format!("{}{}", lat_cell(i), lon_cell(i)));
+        } else {
+            code.push_str(&(1 + lon_cell(i) + 2 * lat_cell(i)).to_string());
+        }
+    }
+    Some(code)
+}
+
+/// The bounds of a mesh, `[min lon, min lat, max lon, max lat]`, or `None` if the code isn't
+/// a valid mesh code.
+pub fn mesh_bounds(code: &str) -> Option<[f64; 4]> {
+    let level = MeshLevel::from_code_length(code.len())?;
+    let digits: Vec<u32> = code
+        .chars()
+        .map(|c| c.to_digit(10))
+        .collect::<Option<_>>()?;
+
+    let mut lat_s = f64::from(digits[0] * 10 + digits[1]) * SIZES[0].0;
+    let mut lon_s = f64::from(digits[2] * 10 + digits[3]) * SIZES[0].1;
+    let levels = SIZES.iter().enumerate().take(level.index() + 1).skip(1);
+    for (i, &(lat_size, lon_size)) in levels {
+        let (lat_cell, lon_cell) = if i <= 2 {
+            let (lat_cell, lon_cell) = (digits[2 + i * 2], digits[3 + i * 2]);
+            let max = if i == 1 { 8 } else { 10 };
+            if lat_cell >= max || lon_cell >= max {
+                return None;
+            }
+            (lat_cell, lon_cell)
+        } else {
+            let quadrant = digits[5 + i];
+            if !(1..=4).contains(&quadrant) {
+                return None;
+            }
+            ((quadrant - 1) / 2, (quadrant - 1) % 2)
+        };
+        lat_s += f64::from(lat_cell) * lat_size;
+        lon_s += f64::from(lon_cell) * lon_size;
+    }
+
+    let (lat_size, lon_size) = SIZES[level.index()];
+    Some([
+        LON_ORIGIN + lon_s / 3600.0,
+        lat_s / 3600.0,
+        LON_ORIGIN + (lon_s + lon_size) / 3600.0,
+        (lat_s + lat_size) / 3600.0,
+    ])
+}
+
+/// A SQL expression for the mesh code at `level` of a point, given as SQL expressions for its
+/// latitude and longitude in seconds, relative to the origin of the meshes (see
+/// `mesh_seconds_sql`). `NULL` if the point is outside the meshes, like `mesh_code`.
+pub fn mesh_code_sql(lat_s: &str, lon_s: &str, level: MeshLevel) -> String {
+    let cell = |value: &str, i: usize, size: fn((f64, f64)) -> f64| {
+        if i == 0 {
+            format!("floor({} / {})::int", value, size(SIZES[0]))
+        } else {
+            format!(
+                "(floor({value} / {size}) - floor({value} / {parent}) * {ratio})::int",
+                size = size(SIZES[i]),
+                parent = size(SIZES[i - 1]),
+                ratio = size(SIZES[i - 1]) / size(SIZES[i]),
+            )
+        }
+    };
+    let lat_cell = |i| cell(lat_s, i, |(lat, _)| lat);
+    let lon_cell = |i| cell(lon_s, i, |(_, lon)| lon);
+
+    let mut parts = vec![
+        format!("lpad({}::text, 2, '0')", lat_cell(0)),
+        format!("lpad({}::text, 2, '0')", lon_cell(0)),
+    ];
+    for i in 1..=level.index() {
+        if i <= 2 {
+            parts.push(format!("{}::text", lat_cell(i)));
+            parts.push(format!("{}::text", lon_cell(i)));
+        } else {
+            parts.push(format!("(1 + {} + 2 * {})::text", lon_cell(i), lat_cell(i)));
+        }
+    }
+    format!(
+        "(CASE WHEN {lat_s} >= 0 AND {lat_s} < {max_lat} AND {lon_s} >= 0 AND {lon_s} < {max_lon} THEN {code} END)",
+        max_lat = EXTENT.0,
+        max_lon = EXTENT.1,
+        code = parts.join(" || "),
+    )
+}
+
+/// SQL expressions for the latitude and longitude, in seconds relative to the origin of the
+/// meshes, of a point geometry given as a SQL expression. For `mesh_code_sql`.
+pub fn mesh_seconds_sql(point: &str) -> (String, String) {
+    (
+        format!("(ST_Y({}) * 3600)", point),
+        format!("((ST_X({}) - {}) * 3600)", point, LON_ORIGIN),
+    )
+}
+
+/// A SQL expression for the polygon (a MultiPolygon in EPSG 4326) of the mesh with the code
+/// given as a SQL expression. Codes of any level can be mixed.
+pub fn mesh_polygon_sql(code: &str) -> String {
+    let digit = |n: usize| format!("nullif(substr(\"c\", {}, 1), '')::int", n);
+    let size_case = |size: fn((f64, f64)) -> f64| {
+        let whens: Vec<String> = MeshLevel::ALL
+            .iter()
+            .map(|level| {
+                format!(
+                    "WHEN {} THEN {}",
+                    level.code_length(),
+                    size(SIZES[level.index()])
+                )
+            })
+            .collect();
+        format!("CASE length(\"c\") {} END", whens.join(" "))
+    };
+    let lat_s = format!(
+        "substr(\"c\", 1, 2)::int * {} + coalesce({} * {}, 0) + coalesce({} * {}, 0) + coalesce(({} - 1) / 2 * {}, 0) + coalesce(({} - 1) / 2 * {}, 0)",
+        SIZES[0].0,
+        digit(5),
+        SIZES[1].0,
+        digit(7),
+        SIZES[2].0,
+        digit(9),
+        SIZES[3].0,
+        digit(10),
+        SIZES[4].0,
+    );
+    let lon_s = format!(
+        "substr(\"c\", 3, 2)::int * {} + coalesce({} * {}, 0) + coalesce({} * {}, 0) + coalesce(({} - 1) % 2 * {}, 0) + coalesce(({} - 1) % 2 * {}, 0)",
+        SIZES[0].1,
+        digit(6),
+        SIZES[1].1,
+        digit(8),
+        SIZES[2].1,
+        digit(9),
+        SIZES[3].1,
+        digit(10),
+        SIZES[4].1,
+    );
+    format!(
+        r#"(SELECT ST_Multi(ST_MakeEnvelope(
+            {origin} + "lon_s" / 3600, "lat_s" / 3600,
+            {origin} + ("lon_s" + "lon_size") / 3600, ("lat_s" + "lat_size") / 3600,
+            4326
+        )) FROM (
+            SELECT
+                ({lat_s})::float8 AS "lat_s",
+                ({lon_s})::float8 AS "lon_s",
+                ({lat_size})::float8 AS "lat_size",
+                ({lon_size})::float8 AS "lon_size"
+            FROM (SELECT ({code})::text AS "c") AS "_mesh_code"
+        ) AS "_mesh")"#,
+        origin = LON_ORIGIN,
+        lat_size = size_case(|(lat, _)| lat),
+        lon_size = size_case(|(_, lon)| lon),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tokyo Station
+    const LON: f64 = 139.7671;
+    const LAT: f64 = 35.6812;
+    /// Points without mesh codes: west of 100°, south of the equator and north of 66°40'
+    const OUT_OF_RANGE: [(f64, f64); 4] =
+        [(0.0, 0.0), (-74.0, 40.7), (151.2, -33.9), (139.0, 70.0)];
+
+    #[test]
+    fn test_mesh_code() {
+        assert_eq!(
+            mesh_code(LON, LAT, MeshLevel::First).as_deref(),
+            Some("5339")
+        );
+        assert_eq!(
+            mesh_code(LON, LAT, MeshLevel::Second).as_deref(),
+            Some("533946")
+        );
+        assert_eq!(
+            mesh_code(LON, LAT, MeshLevel::Third).as_deref(),
+            Some("53394611")
+        );
+        assert_eq!(
+            mesh_code(LON, LAT, MeshLevel::Half).as_deref(),
+            Some("533946113")
+        );
+        assert_eq!(
+            mesh_code(LON, LAT, MeshLevel::Quarter).as_deref(),
+            Some("5339461132")
+        );
+    }
+
+    #[test]
+    fn test_mesh_code_out_of_range() {
+        for (lon, lat) in OUT_OF_RANGE {
+            assert_eq!(
+                mesh_code(lon, lat, MeshLevel::Third),
+                None,
+                "{} {}",
+                lon,
+                lat
+            );
+        }
+    }
+
+    #[test]
+    fn test_mesh_bounds() {
+        for level in MeshLevel::ALL {
+            let code = mesh_code(LON, LAT, level).unwrap();
+            let [min_lon, min_lat, max_lon, max_lat] = mesh_bounds(&code).unwrap();
+            assert!(min_lon <= LON && LON < max_lon, "{}", code);
+            assert!(min_lat <= LAT && LAT < max_lat, "{}", code);
+        }
+        let [min_lon, min_lat, max_lon, max_lat] = mesh_bounds("5339").unwrap();
+        assert_eq!([min_lon, max_lon], [139.0, 140.0]);
+        assert!((min_lat - 35.0 - 1.0 / 3.0).abs() < 1e-9);
+        assert!((max_lat - 36.0).abs() < 1e-9);
+
+        assert!(mesh_bounds("53394").is_none());
+        assert!(mesh_bounds("533986").is_none());
+        assert!(mesh_bounds("533946115").is_none());
+        assert!(mesh_bounds("5339461x").is_none());
+    }
+
+    #[test]
+    fn test_mesh_level() {
+        assert_eq!("half".parse(), Ok(MeshLevel::Half));
+        assert_eq!("3".parse(), Ok(MeshLevel::Third));
+        assert!("5".parse::<MeshLevel>().is_err());
+        assert_eq!(MeshLevel::from_code_length(8), Some(MeshLevel::Third));
+        for level in MeshLevel::ALL {
+            assert_eq!(level.name().parse(), Ok(level));
+        }
+    }
+
+    #[test]
+    fn test_mesh_code_sql() {
+        let (lat_s, lon_s) = mesh_seconds_sql("p");
+        let sql = mesh_code_sql(&lat_s, &lon_s, MeshLevel::First);
+        assert_eq!(
+            sql,
+            "(CASE WHEN (ST_Y(p) * 3600) >= 0 AND (ST_Y(p) * 3600) < 240000 AND ((ST_X(p) - 100) * 3600) >= 0 AND ((ST_X(p) - 100) * 3600) < 360000 THEN lpad(floor((ST_Y(p) * 3600) / 2400)::int::text, 2, '0') || lpad(floor(((ST_X(p) - 100) * 3600) / 3600)::int::text, 2, '0') END)"
+        );
+    }
+
+    /// The SQL expressions give the same codes and bounds as `mesh_code` and `mesh_bounds`.
+    /// Uses the database at `POSTGRES_CONN_STR_TEST`, which needs PostGIS.
+    #[tokio::test]
+    async fn test_mesh_sql_matches_rust() {
+        let connect_str = std::env::var("POSTGRES_CONN_STR_TEST").unwrap();
+        let (client, connection) = tokio_postgres::connect(&connect_str, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let (lat_s, lon_s) = mesh_seconds_sql("ST_SetSRID(ST_MakePoint($1, $2), 4326)");
+        for level in MeshLevel::ALL {
+            let sql = format!("SELECT {}", mesh_code_sql(&lat_s, &lon_s, level));
+            let code: String = client.query_one(&sql, &[&LON, &LAT]).await.unwrap().get(0);
+            assert_eq!(Some(code.clone()), mesh_code(LON, LAT, level));
+
+            let sql = format!(
+                r#"SELECT ST_XMin("g"), ST_YMin("g"), ST_XMax("g"), ST_YMax("g") FROM (SELECT {} AS "g") AS "m""#,
+                mesh_polygon_sql("$1")
+            );
+            let row = client.query_one(&sql, &[&code]).await.unwrap();
+            let expected = mesh_bounds(&code).unwrap();
+            for (i, expected) in expected.into_iter().enumerate() {
+                let bound: f64 = row.get(i);
+                assert!((bound - expected).abs() < 1e-9, "{} {}", code, i);
+            }
+        }
+
+        let sql = format!("SELECT {}", mesh_code_sql(&lat_s, &lon_s, MeshLevel::Third));
+        for (lon, lat) in OUT_OF_RANGE {
+            let code: Option<String> = client.query_one(&sql, &[&lon, &lat]).await.unwrap().get(0);
+            assert_eq!(code, None, "{} {}", lon, lat);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! 全国地方公共団体コード: check digits, and following codes through municipal mergers.
+//!
+//! Codes are 5 digits (2 for the prefecture, 3 for the municipality), optionally followed by a
+//! check digit. Datasets of different years use the codes of their year, so codes that were
+//! merged away must be mapped to the current ones before joining them. The changes are in the
+//! `municipality_code_changes` table (see `DB_SETUP.md`), and the codes that exist now in
+//! `admini_boundary_cd`.
+
+use crate::error::Result;
+use chrono::NaiveDate;
+use serde::Serialize;
+
+/// The check digit of a 5 digit code: the digits are weighted 6, 5, 4, 3 and 2, and the check
+/// digit is the last digit of 11 minus the remainder of the sum divided by 11.
+pub fn check_digit(code: &str) -> Option<u32> {
+    if code.len() != 5 {
+        return None;
+    }
+    let mut sum = 0;
+    for (c, weight) in code.chars().zip([6, 5, 4, 3, 2]) {
+        sum += c.to_digit(10)? * weight;
+    }
+    Some((11 - sum % 11) % 10)
+}
+
+/// Normalize a code to 5 digits. Accepts 6 digit codes with a valid check digit, and 4 digit
+/// codes that lost their leading zero by being stored as numbers (`1100` for 札幌市).
+pub fn normalize_code(code: &str) -> std::result::Result<String, String> {
+    let code = code.trim();
+    if !code.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("`{}` is not a municipality code.", code));
+    }
+    match code.len() {
+        4 => Ok(format!("0{}", code)),
+        5 => Ok(code.to_string()),
+        6 => {
+            let (code, digit) = code.split_at(5);
+            let expected = check_digit(code).unwrap();
+            if digit.parse() == Ok(expected) {
+                Ok(code.to_string())
+            } else {
+                Err(format!(
+                    "The check digit of `{}{}` is wrong. It should be {}.",
+                    code, digit, expected
+                ))
+            }
+        }
+        _ => Err(format!(
+            "`{}` is not a municipality code. Codes have 5 digits, or 6 with the check digit.",
+            code
+        )),
+    }
+}
+
+/// A 5 digit code with its check digit.
+pub fn with_check_digit(code: &str) -> Option<String> {
+    check_digit(code).map(|digit| format!("{}{}", code, digit))
+}
+
+/// A municipality that was merged into, or renamed to, another.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CodeChange {
+    pub old_code: String,
+    pub old_name: Option<String>,
+    pub new_code: String,
+    pub new_name: Option<String>,
+    pub effective_date: NaiveDate,
+    /// Such as 編入 or 新設合併
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CodeResolution {
+    pub code: String,
+    pub code_with_check_digit: String,
+    /// The codes that `code` became, as of the requested date. Just `code` if it hasn't
+    /// changed.
+    pub current_codes: Vec<String>,
+    /// The changes from `code` to `current_codes`, oldest first.
+    pub changes: Vec<CodeChange>,
+    /// The changes of the codes that became `code`, oldest first.
+    pub previous_changes: Vec<CodeChange>,
+}
+
+/// Follow `code` through the changes up to `as_of` (or up to now), and back to the codes it
+/// replaced. Returns `None` if the code has never existed: it has no changes, and isn't in
+/// `admini_boundary_cd`.
+pub async fn resolve_code(
+    client: &tokio_postgres::Client,
+    code: &str,
+    as_of: Option<NaiveDate>,
+) -> Result<Option<CodeResolution>> {
+    let as_of = as_of.map(|date| date.to_string());
+    // `UNION` instead of `UNION ALL`, so a code that comes back doesn't loop forever
+    let changes = query_changes(
+        client,
+        r#"
+            WITH RECURSIVE "chain" AS (
+                SELECT * FROM "municipality_code_changes"
+                    WHERE "old_code" = $1 AND ($2::text IS NULL OR "effective_date" <= $2::text::date)
+                UNION
+                SELECT "c".* FROM "municipality_code_changes" AS "c"
+                    JOIN "chain" ON "c"."old_code" = "chain"."new_code"
+                        AND "c"."effective_date" >= "chain"."effective_date"
+                    WHERE $2::text IS NULL OR "c"."effective_date" <= $2::text::date
+            )
+        "#,
+        code,
+        &as_of,
+    )
+    .await?;
+    let previous_changes = query_changes(
+        client,
+        r#"
+            WITH RECURSIVE "chain" AS (
+                SELECT * FROM "municipality_code_changes"
+                    WHERE "new_code" = $1 AND ($2::text IS NULL OR "effective_date" <= $2::text::date)
+                UNION
+                SELECT "c".* FROM "municipality_code_changes" AS "c"
+                    JOIN "chain" ON "c"."new_code" = "chain"."old_code"
+                        AND "c"."effective_date" <= "chain"."effective_date"
+            )
+        "#,
+        code,
+        &as_of,
+    )
+    .await?;
+
+    if changes.is_empty() && previous_changes.is_empty() && !code_exists(client, code).await? {
+        return Ok(None);
+    }
+
+    Ok(Some(CodeResolution {
+        code: code.to_string(),
+        code_with_check_digit: with_check_digit(code).unwrap_or_default(),
+        current_codes: current_codes(code, &changes),
+        changes,
+        previous_changes,
+    }))
+}
+
+/// Whether `code` is a prefecture (`13000`), or a municipality in `admini_boundary_cd`.
+async fn code_exists(client: &tokio_postgres::Client, code: &str) -> Result<bool> {
+    if is_prefecture_code(code) {
+        return Ok(true);
+    }
+    // The codes may have lost their leading zero by being loaded as numbers, or have their
+    // check digit
+    let row = client
+        .query_one(
+            r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM "admini_boundary_cd"
+                        WHERE left(lpad("行政区域コード"::text, 5, '0'), 5) = $1
+                            OR left(lpad("改正後のコード"::text, 5, '0'), 5) = $1
+                )
+            "#,
+            &[&code],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+fn is_prefecture_code(code: &str) -> bool {
+    code.strip_suffix("000")
+        .and_then(|prefecture| prefecture.parse::<u32>().ok())
+        .is_some_and(|prefecture| (1..=47).contains(&prefecture))
+}
+
+async fn query_changes(
+    client: &tokio_postgres::Client,
+    chain: &str,
+    code: &str,
+    as_of: &Option<String>,
+) -> Result<Vec<CodeChange>> {
+    let rows = client
+        .query(
+            &format!(
+                r#"
+                    {}
+                    SELECT DISTINCT
+                        "old_code"::text,
+                        "old_name",
+                        "new_code"::text,
+                        "new_name",
+                        "effective_date"::text,
+                        "reason"
+                    FROM "chain"
+                    ORDER BY 5, 1, 3
+                "#,
+                chain
+            ),
+            &[&code, as_of],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let effective_date: String = row.get(4);
+            Some(CodeChange {
+                old_code: row.get(0),
+                old_name: row.get(1),
+                new_code: row.get(2),
+                new_name: row.get(3),
+                effective_date: NaiveDate::parse_from_str(&effective_date, "%Y-%m-%d").ok()?,
+                reason: row.get(5),
+            })
+        })
+        .collect())
+}
+
+/// The codes that `code` ends up as: the codes in `changes` that didn't change again. A code
+/// that was renamed to itself (the name changed, the code didn't) is still current.
+fn current_codes(code: &str, changes: &[CodeChange]) -> Vec<String> {
+    let mut current: Vec<String> = changes
+        .iter()
+        .map(|change| change.new_code.clone())
+        .chain(std::iter::once(code.to_string()))
+        .filter(|code| {
+            !changes
+                .iter()
+                .any(|change| change.old_code == *code && change.new_code != *code)
+        })
+        .collect();
+    current.sort();
+    current.dedup();
+    current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(old_code: &str, new_code: &str, date: &str) -> CodeChange {
+        CodeChange {
+            old_code: old_code.to_string(),
+            old_name: None,
+            new_code: new_code.to_string(),
+            new_name: None,
+            effective_date: NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap(),
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn test_check_digit() {
+        // 東京都, 渋谷区, 札幌市
+        assert_eq!(with_check_digit("13000").as_deref(), Some("130001"));
+        assert_eq!(with_check_digit("13113").as_deref(), Some("131130"));
+        assert_eq!(with_check_digit("01100").as_deref(), Some("011002"));
+        assert_eq!(check_digit("1311"), None);
+        assert_eq!(check_digit("1311a"), None);
+    }
+
+    #[test]
+    fn test_normalize_code() {
+        assert_eq!(normalize_code("131130"), Ok("13113".to_string()));
+        assert_eq!(normalize_code("13113"), Ok("13113".to_string()));
+        assert_eq!(normalize_code("1100"), Ok("01100".to_string()));
+        assert!(normalize_code("131131").is_err());
+        assert!(normalize_code("13-113").is_err());
+        assert!(normalize_code("123").is_err());
+    }
+
+    #[test]
+    fn test_current_codes() {
+        assert_eq!(current_codes("13113", &[]), vec!["13113"]);
+        // Merged into a new city, which was later merged into another
+        let changes = vec![
+            change("08501", "08236", "2005-03-22"),
+            change("08236", "08230", "2006-02-20"),
+        ];
+        assert_eq!(current_codes("08501", &changes), vec!["08230"]);
+        // Renamed, keeping the code
+        let changes = vec![change("04101", "04101", "1989-04-01")];
+        assert_eq!(current_codes("04101", &changes), vec!["04101"]);
+    }
+
+    #[test]
+    fn test_is_prefecture_code() {
+        assert!(is_prefecture_code("13000"));
+        assert!(is_prefecture_code("01000"));
+        assert!(!is_prefecture_code("48000"));
+        assert!(!is_prefecture_code("13113"));
+    }
+
+    /// Resolves codes against the migration, loaded with the test changes. Uses the database
+    /// at `POSTGRES_CONN_STR_TEST`, in a schema of its own that is dropped afterwards.
+    #[tokio::test]
+    async fn test_resolve_code() {
+        use futures::SinkExt;
+
+        let connect_str = std::env::var("POSTGRES_CONN_STR_TEST").unwrap();
+        let (client, connection) = tokio_postgres::connect(&connect_str, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let schema = format!("test_{}", ulid::Ulid::new().to_string().to_lowercase());
+        client
+            .batch_execute(&format!(
+                r#"CREATE SCHEMA "{schema}"; SET search_path TO "{schema}";"#
+            ))
+            .await
+            .unwrap();
+        client
+            .batch_execute(include_str!("../migrations/municipality_code_changes.sql"))
+            .await
+            .unwrap();
+        let sink = client
+            .copy_in("COPY municipality_code_changes FROM STDIN WITH (FORMAT csv, HEADER)")
+            .await
+            .unwrap();
+        futures::pin_mut!(sink);
+        sink.send(&include_bytes!("../fixtures/municipality_code_changes.csv")[..])
+            .await
+            .unwrap();
+        sink.finish().await.unwrap();
+        client
+            .batch_execute(
+                r#"
+                    REFRESH MATERIALIZED VIEW municipality_code_current;
+                    CREATE TABLE admini_boundary_cd ("行政区域コード" text, "改正後のコード" text);
+                    INSERT INTO admini_boundary_cd VALUES ('90201', NULL), ('1100', NULL);
+                "#,
+            )
+            .await
+            .unwrap();
+
+        // Merged into a city that was merged into another, which was then renamed
+        let resolution = resolve_code(&client, "90302", None).await.unwrap().unwrap();
+        assert_eq!(resolution.current_codes, vec!["90201"]);
+        assert_eq!(resolution.changes.len(), 3);
+        let resolution = resolve_code(&client, "90302", NaiveDate::from_ymd_opt(2005, 12, 31))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolution.current_codes, vec!["90202"]);
+        let resolution = resolve_code(&client, "90201", None).await.unwrap().unwrap();
+        assert_eq!(resolution.current_codes, vec!["90201"]);
+        // 甲町 and 丁市 merged into it, 丙村 and 戊町 into 丁市 before that, and the rename
+        assert_eq!(resolution.previous_changes.len(), 5);
+
+        // Split
+        let resolution = resolve_code(&client, "90401", None).await.unwrap().unwrap();
+        assert_eq!(resolution.current_codes, vec!["90402", "90403"]);
+        let rows = client
+            .query(
+                "SELECT current_code, split FROM municipality_code_current WHERE code = '90401' ORDER BY 1",
+                &[],
+            )
+            .await
+            .unwrap();
+        let current: Vec<(String, bool)> =
+            rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        assert_eq!(
+            current,
+            vec![("90402".to_string(), true), ("90403".to_string(), true)]
+        );
+
+        // Codes that never changed exist if they are in `admini_boundary_cd`, or are prefectures
+        let resolution = resolve_code(&client, "01100", None).await.unwrap().unwrap();
+        assert_eq!(resolution.current_codes, vec!["01100"]);
+        assert!(
+            resolve_code(&client, "13000", None)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            resolve_code(&client, "99999", None)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        client
+            .batch_execute(&format!(r#"DROP SCHEMA "{schema}" CASCADE"#))
+            .await
+            .unwrap();
+    }
+}
@@ -13,6 +13,16 @@ pub async fn check_query(
     Ok(rows)
 }
 
+/// Quote an identifier (a table or column name) for use in SQL.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quote a string as a SQL literal.
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
 fn has_id_column(row: &Row) -> bool {
     row.columns().iter().any(|col| col.name() == "_id")
 }
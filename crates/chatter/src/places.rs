@@ -0,0 +1,330 @@
+//! Place names: parsing what the user typed into names that can be looked up in the
+//! administrative boundaries (`n03_union`).
+
+use crate::functions::normalize_char;
+
+/// Prefectures, romanized and in Japanese.
+const PREFECTURES: &[(&str, &str)] = &[
+    ("hokkaido", "北海道"),
+    ("aomori", "青森県"),
+    ("iwate", "岩手県"),
+    ("miyagi", "宮城県"),
+    ("akita", "秋田県"),
+    ("yamagata", "山形県"),
+    ("fukushima", "福島県"),
+    ("ibaraki", "茨城県"),
+    ("tochigi", "栃木県"),
+    ("gunma", "群馬県"),
+    ("saitama", "埼玉県"),
+    ("chiba", "千葉県"),
+    ("tokyo", "東京都"),
+    ("kanagawa", "神奈川県"),
+    ("niigata", "新潟県"),
+    ("toyama", "富山県"),
+    ("ishikawa", "石川県"),
+    ("fukui", "福井県"),
+    ("yamanashi", "山梨県"),
+    ("nagano", "長野県"),
+    ("gifu", "岐阜県"),
+    ("shizuoka", "静岡県"),
+    ("aichi", "愛知県"),
+    ("mie", "三重県"),
+    ("shiga", "滋賀県"),
+    ("kyoto", "京都府"),
+    ("osaka", "大阪府"),
+    ("hyogo", "兵庫県"),
+    ("nara", "奈良県"),
+    ("wakayama", "和歌山県"),
+    ("tottori", "鳥取県"),
+    ("shimane", "島根県"),
+    ("okayama", "岡山県"),
+    ("hiroshima", "広島県"),
+    ("yamaguchi", "山口県"),
+    ("tokushima", "徳島県"),
+    ("kagawa", "香川県"),
+    ("ehime", "愛媛県"),
+    ("kochi", "高知県"),
+    ("fukuoka", "福岡県"),
+    ("saga", "佐賀県"),
+    ("nagasaki", "長崎県"),
+    ("kumamoto", "熊本県"),
+    ("oita", "大分県"),
+    ("miyazaki", "宮崎県"),
+    ("kagoshima", "鹿児島県"),
+    ("okinawa", "沖縄県"),
+];
+
+/// Municipalities that can be looked up by their romanized names: the designated cities
+/// (政令指定都市) and the special wards of Tokyo. Other municipalities must be given in
+/// Japanese.
+const MUNICIPALITIES: &[(&str, &str)] = &[
+    ("sapporo", "札幌市"),
+    ("sendai", "仙台市"),
+    ("saitama", "さいたま市"),
+    ("chiba", "千葉市"),
+    ("kawasaki", "川崎市"),
+    ("yokohama", "横浜市"),
+    ("sagamihara", "相模原市"),
+    ("niigata", "新潟市"),
+    ("shizuoka", "静岡市"),
+    ("hamamatsu", "浜松市"),
+    ("nagoya", "名古屋市"),
+    ("kyoto", "京都市"),
+    ("osaka", "大阪市"),
+    ("sakai", "堺市"),
+    ("kobe", "神戸市"),
+    ("okayama", "岡山市"),
+    ("hiroshima", "広島市"),
+    ("kitakyushu", "北九州市"),
+    ("fukuoka", "福岡市"),
+    ("kumamoto", "熊本市"),
+    ("chiyoda", "千代田区"),
+    ("chuo", "中央区"),
+    ("minato", "港区"),
+    ("shinjuku", "新宿区"),
+    ("bunkyo", "文京区"),
+    ("taito", "台東区"),
+    ("sumida", "墨田区"),
+    ("koto", "江東区"),
+    ("shinagawa", "品川区"),
+    ("meguro", "目黒区"),
+    ("ota", "大田区"),
+    ("setagaya", "世田谷区"),
+    ("shibuya", "渋谷区"),
+    ("nakano", "中野区"),
+    ("suginami", "杉並区"),
+    ("toshima", "豊島区"),
+    ("kita", "北区"),
+    ("arakawa", "荒川区"),
+    ("itabashi", "板橋区"),
+    ("nerima", "練馬区"),
+    ("adachi", "足立区"),
+    ("katsushika", "葛飾区"),
+    ("edogawa", "江戸川区"),
+];
+
+/// Cities designated by ordinance (政令指定都市), which are divided into wards (区).
+const DESIGNATED_CITIES: &[&str] = &[
+    "札幌市",
+    "仙台市",
+    "さいたま市",
+    "千葉市",
+    "横浜市",
+    "川崎市",
+    "相模原市",
+    "新潟市",
+    "静岡市",
+    "浜松市",
+    "名古屋市",
+    "京都市",
+    "大阪市",
+    "堺市",
+    "神戸市",
+    "岡山市",
+    "広島市",
+    "北九州市",
+    "福岡市",
+    "熊本市",
+];
+
+/// Romanized words that only say what kind of place it is (`Shibuya-ku`, `Osaka City`).
+const ROMAJI_SUFFIXES: &[&str] = &[
+    "to",
+    "fu",
+    "ken",
+    "prefecture",
+    "shi",
+    "city",
+    "ku",
+    "ward",
+    "machi",
+    "cho",
+    "town",
+    "mura",
+    "son",
+    "village",
+    "gun",
+    "county",
+];
+
+/// A place name query, split into the place itself and the larger areas it is in.
+#[derive(Debug, Default, PartialEq)]
+pub struct PlaceQuery {
+    /// Names the place may have. Several names if a romanized name is ambiguous (`Kyoto` is
+    /// both 京都府 and 京都市).
+    pub names: Vec<String>,
+    /// Names of the larger areas the place must be in, such as the prefecture.
+    pub within: Vec<String>,
+    /// Whether the place was given in Japanese, so names that start with it may match too.
+    pub japanese: bool,
+}
+
+/// Parse a place name, such as `渋谷区`, `東京都渋谷区`, `横浜市中区` or `Shibuya-ku, Tokyo`.
+/// In Japanese, the place comes last; romanized, it comes first. Returns `None` if a
+/// romanized name isn't one we know.
+pub fn parse_place_query(query: &str) -> Option<PlaceQuery> {
+    let parts: Vec<String> = query
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '、' | '，' | '\u{3000}'))
+        .filter(|part| !part.is_empty())
+        .flat_map(split_prefecture)
+        .flat_map(|part| split_city(&part))
+        .collect();
+    let japanese = !parts.iter().any(|part| is_romanized(part));
+
+    let mut resolved = vec![];
+    for part in &parts {
+        if !japanese && ROMAJI_SUFFIXES.contains(&romaji_key(part).as_str()) {
+            continue;
+        }
+        resolved.push(resolve(part)?);
+    }
+    if resolved.is_empty() {
+        return None;
+    }
+    let names = if japanese {
+        resolved.pop()
+    } else {
+        Some(resolved.remove(0))
+    }?;
+    Some(PlaceQuery {
+        names,
+        within: resolved.into_iter().flatten().collect(),
+        japanese,
+    })
+}
+
+/// `東京都渋谷区` to `東京都` and `渋谷区`.
+fn split_prefecture(part: &str) -> Vec<String> {
+    for (_, prefecture) in PREFECTURES {
+        if let Some(rest) = part
+            .strip_prefix(*prefecture)
+            .filter(|rest| !rest.is_empty())
+        {
+            return vec![prefecture.to_string(), rest.to_string()];
+        }
+    }
+    vec![part.to_string()]
+}
+
+/// `横浜市中区` to `横浜市` and `中区`.
+fn split_city(part: &str) -> Vec<String> {
+    for city in DESIGNATED_CITIES {
+        if let Some(ward) = part
+            .strip_prefix(*city)
+            .filter(|ward| ward.ends_with('区') && ward.chars().count() > 1)
+        {
+            return vec![city.to_string(), ward.to_string()];
+        }
+    }
+    vec![part.to_string()]
+}
+
+fn is_romanized(part: &str) -> bool {
+    part.chars()
+        .map(normalize_char)
+        .flat_map(char::to_lowercase)
+        .any(|c| c.is_ascii_alphabetic() || "āīūēōâîûêô".contains(c))
+}
+
+fn resolve(part: &str) -> Option<Vec<String>> {
+    if !is_romanized(part) {
+        return Some(vec![part.to_string()]);
+    }
+    let key = romaji_key(part);
+    let names: Vec<String> = PREFECTURES
+        .iter()
+        .chain(MUNICIPALITIES)
+        .filter(|(romaji, _)| *romaji == key)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    (!names.is_empty()).then_some(names)
+}
+
+/// Lowercase, without long vowels, and without suffixes like `-ku`, so that `Tōkyō-to`,
+/// `Toukyou` and `tokyo` are the same.
+fn romaji_key(part: &str) -> String {
+    let lower: String = part
+        .chars()
+        .map(normalize_char)
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'ā' | 'â' => 'a',
+            'ī' | 'î' => 'i',
+            'ū' | 'û' => 'u',
+            'ē' | 'ê' => 'e',
+            'ō' | 'ô' => 'o',
+            c => c,
+        })
+        .collect();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let words = match words.split_last() {
+        Some((last, rest)) if !rest.is_empty() && ROMAJI_SUFFIXES.contains(last) => rest,
+        _ => &words[..],
+    };
+    words
+        .concat()
+        .replace("ou", "o")
+        .replace("oo", "o")
+        .replace("uu", "u")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(query: &str) -> (Vec<String>, Vec<String>) {
+        let query = parse_place_query(query).unwrap();
+        (query.names, query.within)
+    }
+
+    #[test]
+    fn test_romaji_key() {
+        assert_eq!(romaji_key("Tōkyō-to"), "tokyo");
+        assert_eq!(romaji_key("Toukyou"), "tokyo");
+        assert_eq!(romaji_key("Shibuya Ku"), "shibuya");
+        assert_eq!(romaji_key("Ōta"), "ota");
+        assert_eq!(romaji_key("Oita"), "oita");
+        assert_eq!(romaji_key("Ooita"), "oita");
+        assert_eq!(romaji_key("ku"), "ku");
+    }
+
+    #[test]
+    fn test_parse_place_query() {
+        assert_eq!(names("渋谷区"), (vec!["渋谷区".to_string()], vec![]));
+        assert_eq!(
+            names("東京都渋谷区"),
+            (vec!["渋谷区".to_string()], vec!["東京都".to_string()])
+        );
+        assert_eq!(
+            names("横浜市　中区"),
+            (vec!["中区".to_string()], vec!["横浜市".to_string()])
+        );
+        assert_eq!(
+            names("神奈川県横浜市中区"),
+            (
+                vec!["中区".to_string()],
+                vec!["神奈川県".to_string(), "横浜市".to_string()]
+            )
+        );
+        assert_eq!(
+            names("京都市中京区"),
+            (vec!["中京区".to_string()], vec!["京都市".to_string()])
+        );
+        assert_eq!(names("横浜市"), (vec!["横浜市".to_string()], vec![]));
+        assert_eq!(
+            names("Shibuya, Tokyo"),
+            (vec!["渋谷区".to_string()], vec!["東京都".to_string()])
+        );
+        assert_eq!(names("Shibuya ku"), (vec!["渋谷区".to_string()], vec![]));
+        assert_eq!(
+            names("Kyoto"),
+            (vec!["京都府".to_string(), "京都市".to_string()], vec![])
+        );
+        assert!(parse_place_query("Atlantis").is_none());
+        assert!(parse_place_query(" ").is_none());
+        assert!(parse_place_query("渋谷区").unwrap().japanese);
+    }
+}
@@ -0,0 +1,273 @@
+//! Limits on how much work a single turn (one user message) may do before it is stopped.
+
+use crate::chatter_message::{ChatterMessage, ChatterMessageSidecar, Role};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Configurable limits for a single turn.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TurnLimits {
+    /// The maximum number of requests sent to the model.
+    pub max_round_trips: u32,
+    /// The maximum number of tool calls executed.
+    pub max_tool_calls: u32,
+    /// The maximum number of tokens (prompt and completion, summed over all requests).
+    pub max_total_tokens: u64,
+    /// The maximum wall time of the turn, in seconds. Checked between model requests
+    /// and tool calls, and a model request that is still running at the deadline is
+    /// abandoned.
+    pub max_duration_secs: u64,
+    /// The maximum number of tool calls from one response that run at the same time.
+    pub max_concurrent_tool_calls: usize,
+    /// The number of invalid tool calls (unknown functions, or arguments that don't match
+    /// the schema) the model may make before the turn is stopped.
+    pub max_invalid_tool_calls: u32,
+    /// Timeouts for individual tools, in seconds, keyed by function name. Tools that aren't
+    /// listed use the timeout declared by the function.
+    pub tool_timeouts_secs: HashMap<String, u64>,
+}
+
+impl Default for TurnLimits {
+    fn default() -> Self {
+        Self {
+            max_round_trips: 10,
+            max_tool_calls: 20,
+            max_total_tokens: 500_000,
+            max_duration_secs: 300,
+            max_concurrent_tool_calls: 4,
+            max_invalid_tool_calls: 3,
+            tool_timeouts_secs: HashMap::new(),
+        }
+    }
+}
+
+/// The limit that ended a turn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TurnLimit {
+    RoundTrips,
+    ToolCalls,
+    TotalTokens,
+    Deadline,
+    InvalidToolCalls,
+}
+
+/// Attached to the assistant message that ends a turn early.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TurnLimitDetails {
+    pub limit: TurnLimit,
+    pub round_trips: u32,
+    pub tool_calls: u32,
+    pub total_tokens: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Tracks the work done in a turn against its `TurnLimits`.
+#[derive(Debug)]
+pub struct TurnBudget {
+    limits: TurnLimits,
+    started: Instant,
+    round_trips: u32,
+    tool_calls: u32,
+    invalid_tool_calls: u32,
+    total_tokens: u64,
+}
+
+impl TurnBudget {
+    pub fn new(limits: TurnLimits) -> Self {
+        Self {
+            limits,
+            started: Instant::now(),
+            round_trips: 0,
+            tool_calls: 0,
+            invalid_tool_calls: 0,
+            total_tokens: 0,
+        }
+    }
+
+    fn check_deadline(&self) -> Option<TurnLimit> {
+        (self.remaining().is_zero()).then_some(TurnLimit::Deadline)
+    }
+
+    /// The time left until the deadline.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_secs(self.limits.max_duration_secs).saturating_sub(self.started.elapsed())
+    }
+
+    /// Check whether another request may be sent to the model.
+    pub fn check_round_trip(&self) -> Option<TurnLimit> {
+        if self.round_trips >= self.limits.max_round_trips {
+            Some(TurnLimit::RoundTrips)
+        } else if self.total_tokens >= self.limits.max_total_tokens {
+            Some(TurnLimit::TotalTokens)
+        } else if self.invalid_tool_calls >= self.limits.max_invalid_tool_calls {
+            Some(TurnLimit::InvalidToolCalls)
+        } else {
+            self.check_deadline()
+        }
+    }
+
+    /// Record a response from the model, and the tokens it used.
+    pub fn record_round_trip(&mut self, tokens: u64) {
+        self.round_trips += 1;
+        self.total_tokens += tokens;
+    }
+
+    /// Check whether another tool call may be executed.
+    pub fn check_tool_call(&self) -> Option<TurnLimit> {
+        if self.tool_calls >= self.limits.max_tool_calls {
+            Some(TurnLimit::ToolCalls)
+        } else if self.invalid_tool_calls >= self.limits.max_invalid_tool_calls {
+            Some(TurnLimit::InvalidToolCalls)
+        } else {
+            self.check_deadline()
+        }
+    }
+
+    pub fn record_tool_call(&mut self) {
+        self.tool_calls += 1;
+    }
+
+    /// Record a tool call that was rejected before it was executed.
+    pub fn record_invalid_tool_call(&mut self) {
+        self.invalid_tool_calls += 1;
+    }
+
+    pub fn details(&self, limit: TurnLimit) -> TurnLimitDetails {
+        TurnLimitDetails {
+            limit,
+            round_trips: self.round_trips,
+            tool_calls: self.tool_calls,
+            total_tokens: self.total_tokens,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+
+    /// The assistant message that ends the turn when `limit` has been reached.
+    pub fn limit_message(&self, limit: TurnLimit) -> ChatterMessage {
+        let reason = match limit {
+            TurnLimit::RoundTrips => "AIへの問い合わせ回数",
+            TurnLimit::ToolCalls => "ツールの実行回数",
+            TurnLimit::TotalTokens => "トークン数",
+            TurnLimit::Deadline => "処理時間",
+            TurnLimit::InvalidToolCalls => "不正なツール呼び出しの回数",
+        };
+        ChatterMessage {
+            message: Some(format!(
+                "{}の上限に達したため、処理を中断しました。質問を絞り込むか、続きを指示してください。",
+                reason
+            )),
+            role: Role::Assistant,
+            tool_calls: None,
+            tool_call_id: None,
+            sidecar: ChatterMessageSidecar::TurnLimitReached(self.details(limit)),
+        }
+    }
+
+    /// How many tool calls may run at the same time. At least one.
+    pub fn max_concurrent_tool_calls(&self) -> usize {
+        self.limits.max_concurrent_tool_calls.max(1)
+    }
+
+    /// The configured timeout for a tool, if it overrides the function's own timeout.
+    pub fn tool_timeout(&self, function_name: &str) -> Option<Duration> {
+        self.limits
+            .tool_timeouts_secs
+            .get(function_name)
+            .map(|secs| Duration::from_secs(*secs))
+    }
+
+    /// The response to a tool call that was not executed because `limit` was reached.
+    /// Every tool call needs a response, or the conversation can't be sent to the model again.
+    pub fn skipped_tool_response(&self, tool_call_id: &str, limit: TurnLimit) -> ChatterMessage {
+        ChatterMessage {
+            message: Some(
+                json!({
+                    "error": "This tool call was not executed because the turn limit was reached.",
+                    "limit": limit,
+                })
+                .to_string(),
+            ),
+            role: Role::Tool,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id.to_string()),
+            sidecar: ChatterMessageSidecar::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let mut budget = TurnBudget::new(TurnLimits {
+            max_round_trips: 2,
+            max_tool_calls: 1,
+            max_total_tokens: 1000,
+            max_duration_secs: 60,
+            max_concurrent_tool_calls: 1,
+            max_invalid_tool_calls: 3,
+            tool_timeouts_secs: HashMap::from([("query_database".to_string(), 5)]),
+        });
+        assert_eq!(budget.check_round_trip(), None);
+        assert_eq!(budget.check_tool_call(), None);
+        assert_eq!(
+            budget.tool_timeout("query_database"),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(budget.tool_timeout("describe_tables"), None);
+        assert!(budget.remaining() > Duration::from_secs(59));
+
+        budget.record_round_trip(100);
+        budget.record_tool_call();
+        assert_eq!(budget.check_round_trip(), None);
+        assert_eq!(budget.check_tool_call(), Some(TurnLimit::ToolCalls));
+
+        budget.record_round_trip(100);
+        assert_eq!(budget.check_round_trip(), Some(TurnLimit::RoundTrips));
+
+        let message = budget.limit_message(TurnLimit::RoundTrips);
+        assert_eq!(message.role, Role::Assistant);
+        let ChatterMessageSidecar::TurnLimitReached(details) = message.sidecar else {
+            panic!("Expected a TurnLimitReached sidecar");
+        };
+        assert_eq!(details.round_trips, 2);
+        assert_eq!(details.tool_calls, 1);
+        assert_eq!(details.total_tokens, 200);
+    }
+
+    #[test]
+    fn test_token_and_deadline_limits() {
+        let mut budget = TurnBudget::new(TurnLimits {
+            max_total_tokens: 1000,
+            ..Default::default()
+        });
+        budget.record_round_trip(1500);
+        assert_eq!(budget.check_round_trip(), Some(TurnLimit::TotalTokens));
+
+        let budget = TurnBudget::new(TurnLimits {
+            max_duration_secs: 0,
+            ..Default::default()
+        });
+        assert_eq!(budget.check_round_trip(), Some(TurnLimit::Deadline));
+        assert_eq!(budget.check_tool_call(), Some(TurnLimit::Deadline));
+    }
+
+    #[test]
+    fn test_invalid_tool_calls() {
+        let mut budget = TurnBudget::new(TurnLimits {
+            max_invalid_tool_calls: 2,
+            ..Default::default()
+        });
+        budget.record_invalid_tool_call();
+        assert_eq!(budget.check_tool_call(), None);
+        budget.record_invalid_tool_call();
+        assert_eq!(budget.check_tool_call(), Some(TurnLimit::InvalidToolCalls));
+        assert_eq!(budget.check_round_trip(), Some(TurnLimit::InvalidToolCalls));
+    }
+}
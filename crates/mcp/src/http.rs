@@ -0,0 +1,241 @@
+//! The streamable HTTP transport. Every message is POSTed to `/mcp`, and the response is
+//! returned as JSON. We never send messages of our own, so there is no SSE stream.
+//!
+//! Requests from browsers are only accepted from local pages, or the origins listed in
+//! `MCP_ALLOWED_ORIGINS` (comma separated), so other sites can't reach a local server
+//! (DNS rebinding). Sessions that haven't been used for `SESSION_IDLE_TIMEOUT` are dropped.
+
+use crate::server::{McpServer, PARSE_ERROR, Session, error_response};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SESSION_HEADER: &str = "mcp-session-id";
+
+/// How long a session is kept without any messages. Clients that come back later get a
+/// 404, and start a new session.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Hosts that pages can be served from on this machine.
+const LOCAL_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+#[derive(Clone)]
+struct HttpState {
+    server: McpServer,
+    sessions: Arc<Mutex<Sessions>>,
+    /// Origins allowed besides local ones.
+    allowed_origins: Arc<Vec<String>>,
+}
+
+/// The open sessions, and when they were last used.
+struct Sessions {
+    sessions: HashMap<String, (Arc<Session>, Instant)>,
+    idle_timeout: Duration,
+}
+
+impl Sessions {
+    fn new(idle_timeout: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    /// Add a session. Sessions that have expired are removed at the same time.
+    fn insert(&mut self, session: Arc<Session>) {
+        let idle_timeout = self.idle_timeout;
+        self.sessions
+            .retain(|_, (_, used)| used.elapsed() < idle_timeout);
+        self.sessions
+            .insert(session.id(), (session, Instant::now()));
+    }
+
+    /// Get a session that hasn't expired, and mark it as used.
+    fn get(&mut self, id: &str) -> Option<Arc<Session>> {
+        let (session, used) = self.sessions.get_mut(id)?;
+        if used.elapsed() >= self.idle_timeout {
+            self.sessions.remove(id);
+            return None;
+        }
+        *used = Instant::now();
+        Some(session.clone())
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Arc<Session>> {
+        self.sessions.remove(id).map(|(session, _)| session)
+    }
+}
+
+fn router(server: McpServer, allowed_origins: Vec<String>) -> Router {
+    let state = HttpState {
+        server,
+        sessions: Arc::new(Mutex::new(Sessions::new(SESSION_IDLE_TIMEOUT))),
+        allowed_origins: Arc::new(allowed_origins),
+    };
+    Router::new()
+        .route(
+            "/mcp",
+            post(handle_post).get(handle_get).delete(handle_delete),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), check_origin))
+        .with_state(state)
+}
+
+/// Serve on `addr`. The server has no authentication, so only bind it to a public address
+/// behind something that has.
+pub async fn serve(server: McpServer, addr: &str) -> anyhow::Result<()> {
+    let allowed_origins = std::env::var("MCP_ALLOWED_ORIGINS")
+        .map(|origins| {
+            origins
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!(
+        "MCP server listening on http://{}/mcp",
+        listener.local_addr()?
+    );
+    axum::serve(listener, router(server, allowed_origins)).await?;
+    Ok(())
+}
+
+/// Refuse requests from pages on other sites. Clients that aren't browsers don't send an
+/// `Origin` header.
+async fn check_origin(State(state): State<HttpState>, request: Request, next: Next) -> Response {
+    if let Some(origin) = request.headers().get(header::ORIGIN) {
+        let allowed = origin
+            .to_str()
+            .is_ok_and(|origin| origin_allowed(origin, &state.allowed_origins));
+        if !allowed {
+            return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Whether `origin` is a local page, or one of `allowed_origins`.
+fn origin_allowed(origin: &str, allowed_origins: &[String]) -> bool {
+    if allowed_origins.iter().any(|allowed| allowed == origin) {
+        return true;
+    }
+    let Some(host) = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    else {
+        return false;
+    };
+    // Strip the port. IPv6 hosts are in brackets, so their colons aren't a port.
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    };
+    LOCAL_HOSTS.contains(&host)
+}
+
+async fn handle_post(State(state): State<HttpState>, headers: HeaderMap, body: String) -> Response {
+    let message: Value = match serde_json::from_str(&body) {
+        Ok(message) => message,
+        Err(e) => {
+            let error = error_response(Value::Null, PARSE_ERROR, &format!("Parse error: {}", e));
+            return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+        }
+    };
+
+    // `initialize` starts a session. Every other message must carry its ID.
+    let is_initialize = message.get("method").and_then(Value::as_str) == Some("initialize");
+    let session = if is_initialize {
+        let session = Arc::new(Session::new());
+        state.sessions.lock().unwrap().insert(session.clone());
+        session
+    } else {
+        let Some(session_id) = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok()) else {
+            return (StatusCode::BAD_REQUEST, "Missing Mcp-Session-Id header").into_response();
+        };
+        let session = state.sessions.lock().unwrap().get(session_id);
+        match session {
+            Some(session) => session,
+            None => return (StatusCode::NOT_FOUND, "Unknown session").into_response(),
+        }
+    };
+
+    let session_header = is_initialize
+        .then(|| HeaderValue::from_str(&session.id()).ok())
+        .flatten();
+    match state.server.handle_message(&session, message).await {
+        Some(response) => {
+            let mut response = Json(response).into_response();
+            if let Some(value) = session_header {
+                response.headers_mut().insert(SESSION_HEADER, value);
+            }
+            response
+        }
+        None => StatusCode::ACCEPTED.into_response(),
+    }
+}
+
+async fn handle_get() -> StatusCode {
+    StatusCode::METHOD_NOT_ALLOWED
+}
+
+/// The client ends its session.
+async fn handle_delete(State(state): State<HttpState>, headers: HeaderMap) -> StatusCode {
+    let session_id = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok());
+    let removed = session_id.and_then(|id| state.sessions.lock().unwrap().remove(id));
+    match removed {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = vec!["https://agent.example.com".to_string()];
+        assert!(origin_allowed("http://localhost:5173", &allowed));
+        assert!(origin_allowed("http://127.0.0.1", &allowed));
+        assert!(origin_allowed("http://[::1]:3000", &allowed));
+        assert!(origin_allowed("https://agent.example.com", &allowed));
+        assert!(!origin_allowed("https://evil.example.com", &allowed));
+        assert!(!origin_allowed(
+            "http://localhost.evil.example.com",
+            &allowed
+        ));
+        assert!(!origin_allowed("null", &allowed));
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let mut sessions = Sessions::new(Duration::from_millis(50));
+        let session = Arc::new(Session::new());
+        let id = session.id();
+        sessions.insert(session);
+        assert!(sessions.get(&id).is_some());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(sessions.get(&id).is_none());
+        // Expired sessions are removed, not only hidden
+        assert!(sessions.remove(&id).is_none());
+
+        let mut sessions = Sessions::new(Duration::from_millis(50));
+        let expired = Arc::new(Session::new());
+        let expired_id = expired.id();
+        sessions.insert(expired);
+        std::thread::sleep(Duration::from_millis(100));
+        sessions.insert(Arc::new(Session::new()));
+        assert!(!sessions.sessions.contains_key(&expired_id));
+        assert_eq!(sessions.sessions.len(), 1);
+    }
+}
@@ -0,0 +1,67 @@
+//! Serves the chatter tools (`describe_tables`, `query_database`, ...) over the Model
+//! Context Protocol, so other agents can use them.
+//!
+//! ```sh
+//! chatter-mcp                 # stdio
+//! chatter-mcp --http [ADDR]   # streamable HTTP at http://ADDR/mcp
+//! ```
+//!
+//! Uses the same environment as the API: `POSTGRES_CONN_STR`, `TABLE_NAME`, etc. The tools
+//! offered are those of the `TOOL_PROFILE` profile. Logs go to stderr, filtered by `RUST_LOG`
+//! (default `info`).
+
+use chatter::data::dynamodb::Db;
+use chatter::functions::{FunctionRegistry, ToolProfile};
+use deadpool_postgres::{Config, ManagerConfig, Pool, PoolConfig, RecyclingMethod, Runtime};
+use server::McpServer;
+use std::env;
+use std::sync::Arc;
+use tokio_postgres::NoTls;
+use tracing_subscriber::EnvFilter;
+
+mod http;
+mod server;
+mod stdio;
+
+const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:3001";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // stdout carries the protocol on stdio
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let registry = FunctionRegistry::from_env()?;
+    let ddb = Arc::new(Db::new().await);
+    let server = McpServer::new(registry, ToolProfile::max_from_env(), postgres_pool()?, ddb);
+
+    match args.first().map(String::as_str) {
+        None | Some("--stdio") => stdio::serve(server).await,
+        Some("--http") => {
+            let addr = args.get(1).map(String::as_str).unwrap_or(DEFAULT_HTTP_ADDR);
+            http::serve(server, addr).await
+        }
+        Some(other) => {
+            anyhow::bail!("Unknown argument: {other}\nUsage: chatter-mcp [--stdio | --http [ADDR]]")
+        }
+    }
+}
+
+fn postgres_pool() -> anyhow::Result<Pool> {
+    let mut cfg = Config::new();
+    cfg.url = Some(env::var("POSTGRES_CONN_STR")?);
+    cfg.pool = Some(PoolConfig {
+        max_size: 4,
+        ..Default::default()
+    });
+    cfg.manager = Some(ManagerConfig {
+        recycling_method: RecyclingMethod::Fast,
+    });
+    Ok(cfg.create_pool(Some(Runtime::Tokio1), NoTls)?)
+}
@@ -0,0 +1,309 @@
+//! The protocol itself: JSON-RPC messages in, JSON-RPC responses out. Shared by the stdio
+//! and HTTP transports.
+
+use chatter::chatter_context::ChatterContext;
+use chatter::chatter_message::ChatterMessageSidecar;
+use chatter::data::dynamodb::Db;
+use chatter::error::ChatterError;
+use chatter::functions::{FunctionRegistry, SharedResources, ToolProfile};
+use deadpool_postgres::Pool;
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+use tokio_postgres::NoTls;
+
+/// The protocol versions we support, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+pub const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+const INSTRUCTIONS: &str = "Query Japanese open datasets stored in PostGIS. \
+Use `search_datasets` to find tables by keyword, and `describe_tables` to see the columns of a table before writing SQL for `query_database`. \
+Queries are saved, and can be shown as map layers by their `query_id`.";
+
+pub fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+/// A client connection. Like a chat thread, it has a context, and the queries saved by
+/// `query_database` are stored under its ID.
+pub struct Session {
+    context: Arc<Mutex<ChatterContext>>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            context: Arc::new(Mutex::new(ChatterContext::new())),
+        }
+    }
+
+    pub fn id(&self) -> String {
+        self.context.lock().unwrap().id.clone()
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves the functions of a `FunctionRegistry` that `profile` allows as MCP tools.
+#[derive(Clone)]
+pub struct McpServer {
+    registry: Arc<FunctionRegistry>,
+    profile: ToolProfile,
+    postgres_pool: Pool,
+    ddb: Arc<Db>,
+}
+
+impl McpServer {
+    pub fn new(
+        registry: FunctionRegistry,
+        profile: ToolProfile,
+        postgres_pool: Pool,
+        ddb: Arc<Db>,
+    ) -> Self {
+        Self {
+            registry: Arc::new(registry),
+            profile,
+            postgres_pool,
+            ddb,
+        }
+    }
+
+    /// Handle a message that hasn't been parsed yet. Invalid JSON gets an error response.
+    pub async fn handle_text(&self, session: &Session, text: &str) -> Option<Value> {
+        match serde_json::from_str(text) {
+            Ok(message) => self.handle_message(session, message).await,
+            Err(e) => Some(error_response(
+                Value::Null,
+                PARSE_ERROR,
+                &format!("Parse error: {}", e),
+            )),
+        }
+    }
+
+    /// Handle a message. Returns the response, or `None` if the message was a notification
+    /// (or a response, which we don't expect because we never send requests).
+    pub async fn handle_message(&self, session: &Session, message: Value) -> Option<Value> {
+        if !message.is_object() {
+            return Some(error_response(
+                Value::Null,
+                INVALID_REQUEST,
+                "Expected a JSON-RPC message object",
+            ));
+        }
+        let id = message.get("id").cloned();
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            if message.get("result").is_some() || message.get("error").is_some() {
+                return None;
+            }
+            return Some(error_response(
+                id.unwrap_or(Value::Null),
+                INVALID_REQUEST,
+                "Missing method",
+            ));
+        };
+        // Notifications (`notifications/initialized`, `notifications/cancelled`, ...)
+        let id = id?;
+
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let result = match method {
+            "initialize" => Ok(self.initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(self.list_tools()),
+            "tools/call" => self.call_tool(session, &params).await,
+            _ => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_response(id, code, &message),
+        })
+    }
+
+    fn initialize(&self, params: &Value) -> Value {
+        let version = params
+            .get("protocolVersion")
+            .and_then(Value::as_str)
+            .filter(|version| PROTOCOL_VERSIONS.contains(version))
+            .unwrap_or(PROTOCOL_VERSIONS[0]);
+        json!({
+            "protocolVersion": version,
+            "capabilities": { "tools": { "listChanged": false } },
+            "serverInfo": { "name": "chatter", "version": env!("CARGO_PKG_VERSION") },
+            "instructions": INSTRUCTIONS,
+        })
+    }
+
+    fn list_tools(&self) -> Value {
+        let mut tools: Vec<Value> = self
+            .registry
+            .get_tools(self.profile)
+            .into_iter()
+            .map(|tool| {
+                json!({
+                    "name": tool.function.name,
+                    "description": tool.function.description,
+                    "inputSchema": tool.function.parameters.unwrap_or_else(|| json!({ "type": "object" })),
+                })
+            })
+            .collect();
+        tools.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        json!({ "tools": tools })
+    }
+
+    /// Run a tool. Failures of the tool itself (invalid arguments, SQL errors, timeouts) are
+    /// results with `isError`, so the calling model can see them and correct the call.
+    async fn call_tool(&self, session: &Session, params: &Value) -> Result<Value, (i64, String)> {
+        let Some(name) = params.get("name").and_then(Value::as_str) else {
+            return Err((INVALID_PARAMS, "Missing tool name".to_string()));
+        };
+        let arguments = params
+            .get("arguments")
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+
+        let pg = self
+            .postgres_pool
+            .get()
+            .await
+            .map_err(|e| (INTERNAL_ERROR, e.to_string()))?;
+        let resources = SharedResources {
+            chatter_context: session.context.clone(),
+            pg: Arc::new(pg),
+            pg_pool: self.postgres_pool.clone(),
+            ddb: self.ddb.clone(),
+        };
+        let tool_call_id = ulid::Ulid::new().to_string();
+        let result = self
+            .registry
+            .execute(
+                &resources,
+                self.profile,
+                name,
+                tool_call_id,
+                &arguments.to_string(),
+                None,
+            )
+            .await;
+
+        let (text, is_error) = match result {
+            Ok(message) => {
+                let is_error = matches!(message.sidecar, ChatterMessageSidecar::SQLExecutionError);
+                (message.message.unwrap_or_default(), is_error)
+            }
+            Err(error) => {
+                if let ChatterError::ToolTimeout { .. } = error {
+                    // Otherwise the query keeps running on the server
+                    if let Err(e) = resources.pg.cancel_token().cancel_query(NoTls).await {
+                        tracing::error!("Failed to cancel the query: {}", e);
+                    }
+                }
+                (error.to_string(), true)
+            }
+        };
+        Ok(json!({
+            "content": [{ "type": "text", "text": text }],
+            "isError": is_error,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::config::BehaviorVersion;
+    use deadpool_postgres::{Config, Runtime};
+
+    fn server() -> McpServer {
+        // The pool doesn't connect until a connection is needed
+        let mut cfg = Config::new();
+        cfg.url = Some("host=localhost dbname=bbh-test".to_string());
+        let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+        let mut registry = FunctionRegistry::new();
+        registry.register(chatter::functions::DescribeTablesFunction);
+        registry.register(chatter::functions::QueryDatabaseFunction);
+        registry.register(chatter::functions::RequestUnavailableDataFunction);
+        // Not used by these tests, so it doesn't need DynamoDB or `TABLE_NAME`
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .build();
+        let ddb = Db {
+            client: aws_sdk_dynamodb::Client::from_conf(config),
+            table_name: "test".to_string(),
+        };
+        McpServer::new(registry, ToolProfile::Explore, pool, Arc::new(ddb))
+    }
+
+    #[tokio::test]
+    async fn test_handle_message() {
+        let server = server();
+        let session = Session::new();
+
+        let response = server
+            .handle_message(
+                &session,
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "initialize",
+                    "params": {
+                        "protocolVersion": "2025-03-26",
+                        "capabilities": {},
+                        "clientInfo": { "name": "test", "version": "1" }
+                    }
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["protocolVersion"], "2025-03-26");
+        assert!(response["result"]["capabilities"]["tools"].is_object());
+
+        let response = server
+            .handle_message(
+                &session,
+                json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }),
+            )
+            .await;
+        assert!(response.is_none());
+
+        let response = server
+            .handle_message(
+                &session,
+                json!({ "jsonrpc": "2.0", "id": "list", "method": "tools/list" }),
+            )
+            .await
+            .unwrap();
+        let names: Vec<&str> = response["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["name"].as_str().unwrap())
+            .collect();
+        // `request_unavailable_data` isn't available in the explore profile
+        assert_eq!(names, vec!["describe_tables", "query_database"]);
+        assert!(response["result"]["tools"][0]["inputSchema"].is_object());
+
+        let response = server
+            .handle_message(
+                &session,
+                json!({ "jsonrpc": "2.0", "id": 2, "method": "resources/list" }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+
+        let response = server.handle_text(&session, "{not json").await.unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+    }
+}
@@ -0,0 +1,23 @@
+//! The stdio transport: one JSON-RPC message per line on stdin, responses on stdout.
+//! Anything else written to stdout would corrupt the stream, so logs go to stderr.
+
+use crate::server::{McpServer, Session};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+pub async fn serve(server: McpServer) -> anyhow::Result<()> {
+    let session = Session::new();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = server.handle_text(&session, &line).await {
+            let mut out = serde_json::to_string(&response)?;
+            out.push('\n');
+            stdout.write_all(out.as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+    Ok(())
+}
@@ -10,7 +10,7 @@ import { layersAtom, SQLLayer } from "./atoms";
 import { useSetAtom } from "jotai";
 import Header from "../../components/Header";
 import { fetcher, streamJsonLines } from "../../tools/api";
-import { archiveThread } from "../../tools/threads";
+import { archiveThread, forkThread } from "../../tools/threads";
 
 // Types for the API response data
 type Role = "user" | "assistant" | "system" | "tool";
@@ -21,17 +21,82 @@ type SQLExecutionDetails = {
   sql: string;
 };
 
+type TurnLimitDetails = {
+  limit:
+    | "round_trips"
+    | "tool_calls"
+    | "total_tokens"
+    | "deadline"
+    | "invalid_tool_calls";
+  round_trips: number;
+  tool_calls: number;
+  total_tokens: number;
+  elapsed_ms: number;
+};
+
+type LlmRetryDetails = {
+  model: string;
+  attempt: number;
+  max_retries: number;
+  delay_ms: number;
+  error: string;
+};
+
+type LlmFallbackDetails = {
+  from_model: string;
+  to_model: string;
+  error: string;
+};
+
+type ToolErrorDetails = {
+  tool: string;
+  kind: "timeout" | "invalid_call" | "failed";
+  detail: string;
+};
+
 type ChatterMessageSidecar =
   | "None"
   | "DatabaseLookup"
   | {
       SQLExecution: SQLExecutionDetails;
+    }
+  | {
+      ToolError: ToolErrorDetails;
+    }
+  | {
+      TurnLimitReached: TurnLimitDetails;
+    }
+  | {
+      TurnFailed: { error: string };
+    }
+  | "TurnCancelled"
+  | {
+      LlmRetry: LlmRetryDetails;
+    }
+  | {
+      LlmFallback: LlmFallbackDetails;
     };
 function isSidecarSQLExecution(
   sidecar?: ChatterMessageSidecar,
 ): sidecar is { SQLExecution: SQLExecutionDetails } {
   return typeof sidecar === "object" && sidecar && "SQLExecution" in sidecar;
 }
+function isSidecarToolError(
+  sidecar?: ChatterMessageSidecar,
+): sidecar is { ToolError: ToolErrorDetails } {
+  return typeof sidecar === "object" && sidecar && "ToolError" in sidecar;
+}
+
+function toolErrorText({ tool, kind }: ToolErrorDetails): string {
+  switch (kind) {
+    case "timeout":
+      return `${tool} がタイムアウトしました`;
+    case "invalid_call":
+      return `${tool} の呼び出しが不正でした`;
+    default:
+      return `${tool} の実行に失敗しました`;
+  }
+}
 
 type ChatterMessageView = {
   message?: string;
@@ -44,6 +109,27 @@ type Message = {
   content: ChatterMessageView;
 };
 
+// Lines in the NDJSON stream returned when sending a message
+type StreamEvent =
+  | ({ type: "message" } & Message)
+  | { type: "text_delta"; delta: string }
+  | { type: "tool_call_started"; tool_call_id: string; name: string }
+  | { type: "thread_title"; title: string }
+  | { type: "sidecar"; sidecar: ChatterMessageSidecar };
+
+// The status shown while a failed LLM request is retried
+function retryStatusText(sidecar: ChatterMessageSidecar): string | null {
+  if (typeof sidecar !== "object" || !sidecar) return null;
+  if ("LlmRetry" in sidecar) {
+    const { attempt, max_retries } = sidecar.LlmRetry;
+    return `再試行中… (${attempt}/${max_retries})`;
+  }
+  if ("LlmFallback" in sidecar) {
+    return `再試行中… (${sidecar.LlmFallback.to_model} に切り替えました)`;
+  }
+  return null;
+}
+
 type ThreadDetails = {
   id: string;
   title: string;
@@ -68,6 +154,10 @@ const useChatState = (threadId: string) => {
     OptimisticMessage[]
   >([]);
   const [isSending, setIsSending] = useState(false);
+  // The assistant reply that is currently being streamed, if any
+  const [streamingText, setStreamingText] = useState<string | null>(null);
+  // Shown instead of the streamed text while a failed request is retried
+  const [retryStatus, setRetryStatus] = useState<string | null>(null);
   const { mutate: globalMutate } = useSWRConfig();
 
   const {
@@ -87,6 +177,21 @@ const useChatState = (threadId: string) => {
     const bTime = "timestamp" in b ? b.timestamp : b.id;
     return aTime - bTime;
   });
+  if (streamingText) {
+    allMessages.push({
+      id: "streaming",
+      content: { message: streamingText, role: "assistant" },
+      isOptimistic: true,
+      timestamp: Date.now(),
+    });
+  } else if (retryStatus) {
+    allMessages.push({
+      id: "retrying",
+      content: { message: `_${retryStatus}_`, role: "assistant" },
+      isOptimistic: true,
+      timestamp: Date.now(),
+    });
+  }
 
   const addOptimisticMessage = useCallback((content: ChatterMessageView) => {
     const optimisticMessage: OptimisticMessage = {
@@ -103,41 +208,81 @@ const useChatState = (threadId: string) => {
     setOptimisticMessages((prev) => prev.filter((msg) => msg.id !== id));
   }, []);
 
-  const sendMessage = useCallback(
-    async (message: string) => {
+  // Run a turn and apply the streamed events. `message` is the user message sent
+  // with the request, if any.
+  const streamTurn = useCallback(
+    async (path: string, message?: string) => {
       setIsSending(true);
 
       // Add optimistic user message immediately
-      const optimisticId = addOptimisticMessage({
-        message,
-        role: "user",
-      });
+      const optimisticId =
+        message !== undefined
+          ? addOptimisticMessage({
+              message,
+              role: "user",
+            })
+          : undefined;
 
       try {
         const mutateKey = `/threads/${threadId}`;
 
         // Remove optimistic message once we start getting real messages
-        removeOptimisticMessage(optimisticId);
-
-        const messageStream = streamJsonLines<Message>(
-          `/threads/${threadId}/message`,
-          {
-            method: "POST",
-            headers: {
-              "Content-Type": "application/json",
-            },
-            body: JSON.stringify({
-              content: message,
-            }),
+        if (optimisticId) removeOptimisticMessage(optimisticId);
+
+        const eventStream = streamJsonLines<StreamEvent>(path, {
+          method: "POST",
+          headers: {
+            "Content-Type": "application/json",
           },
-        );
+          body:
+            message !== undefined
+              ? JSON.stringify({
+                  content: message,
+                })
+              : undefined,
+        });
 
-        for await (const message of messageStream) {
+        for await (const event of eventStream) {
+          if (event.type === "text_delta") {
+            setRetryStatus(null);
+            setStreamingText((prev) => (prev ?? "") + event.delta);
+            continue;
+          }
+          if (event.type === "sidecar") {
+            const status = retryStatusText(event.sidecar);
+            if (status) {
+              // The partial reply of the failed request is discarded
+              setStreamingText(null);
+              setRetryStatus(status);
+            }
+            continue;
+          }
+          if (event.type === "thread_title") {
+            globalMutate<ThreadDetails>(
+              mutateKey,
+              (prevData) =>
+                prevData ? { ...prevData, title: event.title } : prevData,
+              false,
+            );
+            globalMutate("/threads");
+            continue;
+          }
+          if (event.type !== "message") {
+            continue;
+          }
+          // The complete message replaces the streamed text
+          setStreamingText(null);
+          setRetryStatus(null);
+          const { type: _type, ...message } = event;
           globalMutate<ThreadDetails>(
             mutateKey,
             (prevData) => {
               if (!prevData) return prevData;
-              const newMessages = [...prevData.messages, message];
+              // Regenerating or editing replaces the messages from this one on
+              const newMessages = [
+                ...prevData.messages.filter((m) => m.id < message.id),
+                message,
+              ];
               return {
                 ...prevData,
                 messages: newMessages,
@@ -151,9 +296,11 @@ const useChatState = (threadId: string) => {
       } catch (error) {
         console.error("Error sending message:", error);
         // Remove optimistic message on error
-        removeOptimisticMessage(optimisticId);
+        if (optimisticId) removeOptimisticMessage(optimisticId);
         mutate();
       } finally {
+        setStreamingText(null);
+        setRetryStatus(null);
         setIsSending(false);
       }
     },
@@ -166,6 +313,24 @@ const useChatState = (threadId: string) => {
     ],
   );
 
+  const sendMessage = useCallback(
+    (message: string) => streamTurn(`/threads/${threadId}/message`, message),
+    [threadId, streamTurn],
+  );
+
+  // Answer the last user message again
+  const regenerate = useCallback(
+    () => streamTurn(`/threads/${threadId}/regenerate`),
+    [threadId, streamTurn],
+  );
+
+  // Replace a past user message, discarding everything after it
+  const editMessage = useCallback(
+    (messageId: number, message: string) =>
+      streamTurn(`/threads/${threadId}/messages/${messageId}/edit`, message),
+    [threadId, streamTurn],
+  );
+
   return {
     allMessages,
     isSending,
@@ -173,6 +338,8 @@ const useChatState = (threadId: string) => {
     error,
     threadDetails,
     sendMessage,
+    regenerate,
+    editMessage,
     mutate,
   };
 };
@@ -280,6 +447,15 @@ const MessageRenderer: React.FC<{ message: RenderMessage }> = ({ message }) => {
         </pre>
       </AssistantMessage>
     );
+  } else if (content.role === "tool" && isSidecarToolError(content.sidecar)) {
+    const details = content.sidecar.ToolError;
+    return (
+      <AssistantMessage key={message.id}>
+        <span className="text-warning" title={details.detail}>
+          {toolErrorText(details)}
+        </span>
+      </AssistantMessage>
+    );
   }
   return null;
 };
@@ -405,6 +581,8 @@ const ChatBox: React.FC<ChatBoxProps> = ({ threadId }) => {
     error,
     threadDetails,
     sendMessage,
+    regenerate,
+    editMessage,
     mutate,
   } = useChatState(threadId);
 
@@ -467,6 +645,24 @@ const ChatBox: React.FC<ChatBoxProps> = ({ threadId }) => {
     };
   }, [threadId, mutate, threadDetails?.archived]);
 
+  // Hidden console APIs to regenerate the last answer, edit a past message, and fork the thread
+  useEffect(() => {
+    // eslint-disable-next-line @typescript-eslint/no-explicit-any
+    const w = window as any;
+    w.__regenerate = regenerate;
+    w.__editMessage = editMessage;
+    w.__forkThread = async (messageId: number) => {
+      const newThreadId = await forkThread(threadId, messageId);
+      console.log("Forked thread:", newThreadId);
+      return newThreadId;
+    };
+    return () => {
+      w.__regenerate = undefined;
+      w.__editMessage = undefined;
+      w.__forkThread = undefined;
+    };
+  }, [threadId, regenerate, editMessage]);
+
   // Get initial message from navigation state
   const getInitialMessage = () => {
     const state = history.state as { initialMessage?: string } | null;
@@ -0,0 +1,70 @@
+///! Types and functions to look up administrative areas and municipality codes
+
+import { fetcher } from "./api";
+
+export type AdministrativeArea = {
+  prefecture: string;
+  subprefecture: string | null;
+  county: string | null;
+  municipality: string | null;
+  ward: string | null;
+  /** 全国地方公共団体コード */
+  code: string;
+};
+
+export type ReverseGeocodeResponse = {
+  /** `null` if the point isn't in any administrative area */
+  area: AdministrativeArea | null;
+};
+
+/**
+ * Finds the administrative area that contains a point
+ *
+ * @param lon - Longitude
+ * @param lat - Latitude
+ * @returns Promise<ReverseGeocodeResponse>
+ * @throws Error - If the API request fails
+ */
+export const reverseGeocode = (
+  lon: number,
+  lat: number,
+): Promise<ReverseGeocodeResponse> =>
+  fetcher<ReverseGeocodeResponse>(
+    `/places/reverse_geocode?lon=${lon}&lat=${lat}`,
+  );
+
+export type CodeChange = {
+  old_code: string;
+  old_name: string | null;
+  new_code: string;
+  new_name: string | null;
+  /** YYYY-MM-DD */
+  effective_date: string;
+  reason: string | null;
+};
+
+export type CodeResolution = {
+  code: string;
+  code_with_check_digit: string;
+  /** The codes `code` became. Just `code` if it hasn't changed. */
+  current_codes: string[];
+  changes: CodeChange[];
+  previous_changes: CodeChange[];
+};
+
+/**
+ * Follows a municipality code (全国地方公共団体コード) through municipal mergers
+ *
+ * @param code - 5 digits, or 6 with the check digit
+ * @param asOf - Follow the changes up to this date (YYYY-MM-DD). Defaults to now.
+ * @returns Promise<CodeResolution>
+ * @throws Error - If the API request fails, or the code is invalid
+ */
+export const resolveMunicipalityCode = (
+  code: string,
+  asOf?: string,
+): Promise<CodeResolution> =>
+  fetcher<CodeResolution>(
+    `/places/municipality_codes/${encodeURIComponent(code)}` +
+      (asOf ? `?as_of=${asOf}` : ""),
+  );
@@ -31,9 +31,22 @@ export type CreateThreadResponse = {
   thread_id: string;
 };
 
+export type GenerationSettings = {
+  temperature?: number;
+  max_completion_tokens?: number;
+  parallel_tool_calls?: boolean;
+};
+
+export type CreateThreadOptions = {
+  // The model to use. Defaults to the server's default model.
+  model?: string;
+  generation?: GenerationSettings;
+};
+
 /**
  * Creates a new thread and returns the thread ID
  *
+ * @param options - Optional model and generation settings for the thread
  * @returns Promise<string> - The ID of the newly created thread
  * @throws Error - If the API request fails or thread creation fails
  *
@@ -43,7 +56,9 @@ export type CreateThreadResponse = {
  * console.log('Created thread:', threadId);
  * ```
  */
-export const createThread = async (): Promise<string> => {
+export const createThread = async (
+  options: CreateThreadOptions = {},
+): Promise<string> => {
   const apiUrl = import.meta.env.VITE_API_URL;
   if (!apiUrl) {
     throw new Error("API URL is not defined");
@@ -54,7 +69,7 @@ export const createThread = async (): Promise<string> => {
     headers: {
       "Content-Type": "application/json",
     },
-    body: JSON.stringify({}),
+    body: JSON.stringify(options),
   });
 
   if (!response.ok) {
@@ -95,3 +110,66 @@ export const archiveThread = async (threadId: string): Promise<void> => {
     throw new Error("Failed to archive thread");
   }
 };
+
+/**
+ * Renames a thread
+ *
+ * @param threadId - The ID of the thread to rename
+ * @param title - The new title
+ * @returns Promise<void> - Resolves when the thread is successfully renamed
+ * @throws Error - If the API request fails or renaming fails
+ */
+export const renameThread = async (
+  threadId: string,
+  title: string,
+): Promise<void> => {
+  const apiUrl = import.meta.env.VITE_API_URL;
+  if (!apiUrl) {
+    throw new Error("API URL is not defined");
+  }
+
+  const response = await fetch(`${apiUrl}/threads/${threadId}`, {
+    method: "PATCH",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ title }),
+  });
+
+  if (!response.ok) {
+    throw new Error("Failed to rename thread");
+  }
+};
+
+/**
+ * Copies a thread, up to and including a message, into a new thread
+ *
+ * @param threadId - The ID of the thread to fork
+ * @param messageId - The ID of the last message to copy
+ * @returns Promise<string> - The ID of the new thread
+ * @throws Error - If the API request fails or forking fails
+ */
+export const forkThread = async (
+  threadId: string,
+  messageId: number,
+): Promise<string> => {
+  const apiUrl = import.meta.env.VITE_API_URL;
+  if (!apiUrl) {
+    throw new Error("API URL is not defined");
+  }
+
+  const response = await fetch(`${apiUrl}/threads/${threadId}/fork`, {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ message_id: messageId }),
+  });
+
+  if (!response.ok) {
+    throw new Error("Failed to fork thread");
+  }
+
+  const data: CreateThreadResponse = await response.json();
+  return data.thread_id;
+};